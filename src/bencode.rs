@@ -0,0 +1,663 @@
+//! Bencode without a fixed schema: owned and borrowed values, a pull
+//! decoder that hands out tokens and raw byte spans, and a canonical
+//! encoder.
+//!
+//! The decoder always rejects malformed input. In strict mode it also
+//! rejects valid but non-canonical encodings: unsorted or repeated dict
+//! keys, leading zeros and `-0`.
+
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
+use serde_bytes::{ByteBuf, Bytes};
+use std::collections::BTreeMap;
+use std::fmt;
+use thiserror::Error;
+
+/// Nesting allowed unless a decoder is given another limit.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{kind} at byte {offset}")]
+pub struct DecodeError {
+    /// Offset into the input of the byte the problem was found at.
+    pub offset: usize,
+    pub kind: ErrorKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum ErrorKind {
+    #[error("unexpected end of input")]
+    UnexpectedEof,
+    #[error("unexpected byte {0:#04x}")]
+    UnexpectedByte(u8),
+    #[error("malformed integer")]
+    InvalidInteger,
+    #[error("integer out of range")]
+    IntegerOverflow,
+    #[error("leading zero")]
+    LeadingZero,
+    #[error("negative zero")]
+    NegativeZero,
+    #[error("dict key is not a byte string")]
+    NonStringKey,
+    #[error("dict key without a value")]
+    MissingValue,
+    #[error("dict keys out of order")]
+    UnsortedKeys,
+    #[error("repeated dict key")]
+    DuplicateKey,
+    #[error("nested too deeply")]
+    TooDeep,
+    #[error("data after the value")]
+    TrailingData,
+}
+
+pub type Result<T> = std::result::Result<T, DecodeError>;
+
+/// An owned bencode value. Dict keys are kept sorted, so encoding one is
+/// always canonical.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+/// A value borrowing its byte strings from the input it was decoded from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValueRef<'a> {
+    Int(i64),
+    Bytes(&'a [u8]),
+    List(Vec<ValueRef<'a>>),
+    Dict(BTreeMap<&'a [u8], ValueRef<'a>>),
+}
+
+/// One step of a decode. Every `List` and `Dict` is closed by an `End`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Token<'a> {
+    Int(i64),
+    Bytes(&'a [u8]),
+    List,
+    Dict,
+    End,
+}
+
+// An open container, and for dicts, where in a key-value pair we are
+#[derive(Debug)]
+enum Frame<'a> {
+    List,
+    Dict {
+        last_key: Option<&'a [u8]>,
+        expecting_key: bool,
+    },
+}
+
+/// Pulls tokens, whole values or raw spans of values off a byte slice
+/// without copying it.
+#[derive(Debug)]
+pub struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    max_depth: usize,
+    strict: bool,
+    stack: Vec<Frame<'a>>,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            strict: false,
+            stack: Vec::new(),
+        }
+    }
+
+    /// Lists and dicts may nest `max_depth` deep; 0 allows neither.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Rejects anything that doesn't encode back to the same bytes.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    /// Offset of the next byte to be read.
+    pub fn offset(&self) -> usize {
+        self.pos
+    }
+
+    /// The next token, or `None` once the input is used up between
+    /// top-level values.
+    pub fn next_token(&mut self) -> Result<Option<Token<'a>>> {
+        let start = self.pos;
+        let Some(&byte) = self.data.get(start) else {
+            if self.stack.is_empty() {
+                return Ok(None);
+            }
+            return Err(self.error(start, ErrorKind::UnexpectedEof));
+        };
+
+        let expecting_key = matches!(
+            self.stack.last(),
+            Some(Frame::Dict {
+                expecting_key: true,
+                ..
+            })
+        );
+        if expecting_key && !matches!(byte, b'0'..=b'9' | b'e') {
+            return Err(self.error(start, ErrorKind::NonStringKey));
+        }
+
+        let token = match byte {
+            b'i' => {
+                let token = Token::Int(self.read_int()?);
+                self.value_done();
+                token
+            }
+            b'0'..=b'9' => {
+                let bytes = self.read_bytes()?;
+                if expecting_key {
+                    self.check_key(start, bytes)?;
+                }
+                self.value_done();
+                Token::Bytes(bytes)
+            }
+            b'l' | b'd' => {
+                if self.stack.len() >= self.max_depth {
+                    return Err(self.error(start, ErrorKind::TooDeep));
+                }
+                self.pos += 1;
+                if byte == b'l' {
+                    self.stack.push(Frame::List);
+                    Token::List
+                } else {
+                    self.stack.push(Frame::Dict {
+                        last_key: None,
+                        expecting_key: true,
+                    });
+                    Token::Dict
+                }
+            }
+            b'e' => {
+                match self.stack.pop() {
+                    None => return Err(self.error(start, ErrorKind::UnexpectedByte(byte))),
+                    Some(Frame::Dict {
+                        expecting_key: false,
+                        ..
+                    }) => return Err(self.error(start, ErrorKind::MissingValue)),
+                    Some(_) => {}
+                }
+                self.pos += 1;
+                self.value_done();
+                Token::End
+            }
+            _ => return Err(self.error(start, ErrorKind::UnexpectedByte(byte))),
+        };
+        Ok(Some(token))
+    }
+
+    /// Decodes the next whole value.
+    pub fn decode(&mut self) -> Result<ValueRef<'a>> {
+        let start = self.pos;
+        match self.next_token()? {
+            Some(token) => self.decode_from(token, start),
+            None => Err(self.error(start, ErrorKind::UnexpectedEof)),
+        }
+    }
+
+    fn decode_from(&mut self, token: Token<'a>, start: usize) -> Result<ValueRef<'a>> {
+        match token {
+            Token::Int(n) => Ok(ValueRef::Int(n)),
+            Token::Bytes(bytes) => Ok(ValueRef::Bytes(bytes)),
+            Token::List => {
+                let mut list = Vec::new();
+                loop {
+                    let start = self.pos;
+                    match self.next_token()? {
+                        Some(Token::End) => return Ok(ValueRef::List(list)),
+                        Some(token) => list.push(self.decode_from(token, start)?),
+                        None => return Err(self.error(start, ErrorKind::UnexpectedEof)),
+                    }
+                }
+            }
+            Token::Dict => {
+                let mut dict = BTreeMap::new();
+                loop {
+                    // Keys were checked by `next_token`
+                    let key = match self.next_token()? {
+                        Some(Token::End) => return Ok(ValueRef::Dict(dict)),
+                        Some(Token::Bytes(key)) => key,
+                        _ => unreachable!("dict keys are byte strings"),
+                    };
+                    let value = self.decode()?;
+                    dict.insert(key, value);
+                }
+            }
+            Token::End => Err(self.error(start, ErrorKind::UnexpectedByte(b'e'))),
+        }
+    }
+
+    /// Skips the next whole value, returning its encoded bytes.
+    pub fn skip(&mut self) -> Result<&'a [u8]> {
+        let start = self.pos;
+        let depth = self.stack.len();
+        loop {
+            match self.next_token()? {
+                None => return Err(self.error(start, ErrorKind::UnexpectedEof)),
+                Some(Token::End) if self.stack.len() < depth => {
+                    return Err(self.error(start, ErrorKind::UnexpectedByte(b'e')))
+                }
+                Some(_) if self.stack.len() == depth => return Ok(&self.data[start..self.pos]),
+                Some(_) => {}
+            }
+        }
+    }
+
+    /// Checks that nothing follows the values read so far.
+    pub fn finish(&self) -> Result<()> {
+        if self.pos < self.data.len() {
+            return Err(self.error(self.pos, ErrorKind::TrailingData));
+        }
+        Ok(())
+    }
+
+    fn error(&self, offset: usize, kind: ErrorKind) -> DecodeError {
+        DecodeError { offset, kind }
+    }
+
+    // A value just ended; in a dict, that flips between key and value
+    fn value_done(&mut self) {
+        if let Some(Frame::Dict { expecting_key, .. }) = self.stack.last_mut() {
+            *expecting_key = !*expecting_key;
+        }
+    }
+
+    fn check_key(&mut self, start: usize, key: &'a [u8]) -> Result<()> {
+        let strict = self.strict;
+        let Some(Frame::Dict { last_key, .. }) = self.stack.last_mut() else {
+            return Ok(());
+        };
+        match *last_key {
+            Some(last) if strict && key == last => {
+                return Err(DecodeError {
+                    offset: start,
+                    kind: ErrorKind::DuplicateKey,
+                })
+            }
+            Some(last) if strict && key < last => {
+                return Err(DecodeError {
+                    offset: start,
+                    kind: ErrorKind::UnsortedKeys,
+                })
+            }
+            _ => {}
+        }
+        *last_key = Some(key);
+        Ok(())
+    }
+
+    // The digits of an integer or length ending at `terminator`, which is
+    // consumed
+    fn read_digits(&mut self, terminator: u8) -> Result<&'a [u8]> {
+        let start = self.pos;
+        let length = self.data[start..]
+            .iter()
+            .position(|&b| b == terminator)
+            .ok_or_else(|| self.error(self.data.len(), ErrorKind::UnexpectedEof))?;
+        self.pos = start + length + 1;
+        Ok(&self.data[start..start + length])
+    }
+
+    fn read_int(&mut self) -> Result<i64> {
+        let start = self.pos;
+        self.pos += 1;
+        let text = self.read_digits(b'e')?;
+        let digits = text.strip_prefix(b"-").unwrap_or(text);
+        if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+            return Err(self.error(start, ErrorKind::InvalidInteger));
+        }
+        if self.strict {
+            if digits.len() > 1 && digits[0] == b'0' {
+                return Err(self.error(start, ErrorKind::LeadingZero));
+            }
+            if text.len() > digits.len() && digits == b"0" {
+                return Err(self.error(start, ErrorKind::NegativeZero));
+            }
+        }
+        std::str::from_utf8(text)
+            .ok()
+            .and_then(|text| text.parse().ok())
+            .ok_or_else(|| self.error(start, ErrorKind::IntegerOverflow))
+    }
+
+    fn read_bytes(&mut self) -> Result<&'a [u8]> {
+        let start = self.pos;
+        let digits = self.read_digits(b':')?;
+        if !digits.iter().all(u8::is_ascii_digit) {
+            return Err(self.error(start, ErrorKind::InvalidInteger));
+        }
+        if self.strict && digits.len() > 1 && digits[0] == b'0' {
+            return Err(self.error(start, ErrorKind::LeadingZero));
+        }
+        let length: usize = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| digits.parse().ok())
+            .ok_or_else(|| self.error(start, ErrorKind::IntegerOverflow))?;
+        let end = self
+            .pos
+            .checked_add(length)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| self.error(self.data.len(), ErrorKind::UnexpectedEof))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+}
+
+/// Decodes `data`, which must hold exactly one value.
+pub fn decode(data: &[u8]) -> Result<ValueRef<'_>> {
+    let mut decoder = Decoder::new(data);
+    let value = decoder.decode()?;
+    decoder.finish()?;
+    Ok(value)
+}
+
+/// Like `decode`, but only accepts the canonical encoding of the value.
+pub fn decode_strict(data: &[u8]) -> Result<ValueRef<'_>> {
+    let mut decoder = Decoder::new(data).strict();
+    let value = decoder.decode()?;
+    decoder.finish()?;
+    Ok(value)
+}
+
+/// The encoded bytes of the value stored under `key` in the dict that
+/// `data` starts with, or `None` if the dict has no such key.
+pub fn dict_value<'a>(data: &'a [u8], key: &[u8], max_depth: usize) -> Result<Option<&'a [u8]>> {
+    let mut decoder = Decoder::new(data).with_max_depth(max_depth);
+    if decoder.next_token()? != Some(Token::Dict) {
+        let kind = match data.first() {
+            Some(&byte) => ErrorKind::UnexpectedByte(byte),
+            None => ErrorKind::UnexpectedEof,
+        };
+        return Err(decoder.error(0, kind));
+    }
+    loop {
+        match decoder.next_token()? {
+            Some(Token::Bytes(k)) if k == key => return decoder.skip().map(Some),
+            Some(Token::Bytes(_)) => {
+                decoder.skip()?;
+            }
+            _ => return Ok(None),
+        }
+    }
+}
+
+impl Value {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes()
+            .and_then(|bytes| std::str::from_utf8(bytes).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Value>> {
+        match self {
+            Value::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    /// The value under `key`, if this is a dict that has one.
+    pub fn get(&self, key: &[u8]) -> Option<&Value> {
+        self.as_dict()?.get(key)
+    }
+
+    /// The canonical encoding.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Int(n) => out.extend_from_slice(format!("i{}e", n).as_bytes()),
+            Value::Bytes(bytes) => encode_bytes(bytes, out),
+            Value::List(list) => {
+                out.push(b'l');
+                for value in list {
+                    value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+            Value::Dict(dict) => {
+                out.push(b'd');
+                for (key, value) in dict {
+                    encode_bytes(key, out);
+                    value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+    out.extend_from_slice(bytes);
+}
+
+impl ValueRef<'_> {
+    /// The canonical encoding.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        Value::from(self.clone()).to_bytes()
+    }
+}
+
+impl From<ValueRef<'_>> for Value {
+    fn from(value: ValueRef<'_>) -> Self {
+        match value {
+            ValueRef::Int(n) => Value::Int(n),
+            ValueRef::Bytes(bytes) => Value::Bytes(bytes.to_vec()),
+            ValueRef::List(list) => Value::List(list.into_iter().map(Value::from).collect()),
+            ValueRef::Dict(dict) => Value::Dict(
+                dict.into_iter()
+                    .map(|(key, value)| (key.to_vec(), Value::from(value)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+// So `Value` can stand in for any part of a serde-modelled struct
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            Value::Int(n) => serializer.serialize_i64(*n),
+            Value::Bytes(bytes) => serializer.serialize_bytes(bytes),
+            Value::List(list) => {
+                let mut seq = serializer.serialize_seq(Some(list.len()))?;
+                for value in list {
+                    seq.serialize_element(value)?;
+                }
+                seq.end()
+            }
+            Value::Dict(dict) => {
+                let mut map = serializer.serialize_map(Some(dict.len()))?;
+                for (key, value) in dict {
+                    map.serialize_entry(Bytes::new(key), value)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a bencode value")
+    }
+
+    fn visit_i64<E>(self, n: i64) -> std::result::Result<Value, E> {
+        Ok(Value::Int(n))
+    }
+
+    fn visit_u64<E: de::Error>(self, n: u64) -> std::result::Result<Value, E> {
+        i64::try_from(n)
+            .map(Value::Int)
+            .map_err(|_| E::custom("integer out of range"))
+    }
+
+    fn visit_str<E>(self, s: &str) -> std::result::Result<Value, E> {
+        Ok(Value::Bytes(s.as_bytes().to_vec()))
+    }
+
+    fn visit_bytes<E>(self, bytes: &[u8]) -> std::result::Result<Value, E> {
+        Ok(Value::Bytes(bytes.to_vec()))
+    }
+
+    fn visit_byte_buf<E>(self, bytes: Vec<u8>) -> std::result::Result<Value, E> {
+        Ok(Value::Bytes(bytes))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Value, A::Error> {
+        let mut list = Vec::new();
+        while let Some(value) = seq.next_element()? {
+            list.push(value);
+        }
+        Ok(Value::List(list))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<Value, A::Error> {
+        let mut dict = BTreeMap::new();
+        while let Some((key, value)) = map.next_entry::<ByteBuf, Value>()? {
+            dict.insert(key.into_vec(), value);
+        }
+        Ok(Value::Dict(dict))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_and_encode_round_trip() {
+        let data = b"d4:infod6:lengthi-5e4:pathl1:a2:bcee4:spam0:e";
+        let value = decode_strict(data).unwrap();
+        let ValueRef::Dict(dict) = &value else {
+            panic!("expected a dict");
+        };
+        assert_eq!(dict[&b"spam"[..]], ValueRef::Bytes(b""));
+
+        let owned = Value::from(value.clone());
+        assert_eq!(
+            owned.get(b"info").unwrap().get(b"length"),
+            Some(&Value::Int(-5))
+        );
+        assert_eq!(owned.to_bytes(), data);
+        assert_eq!(value.to_bytes(), data);
+
+        // Works as a serde field, through serde_bencode
+        let via_serde: Value = serde_bencode::from_bytes(data).unwrap();
+        assert_eq!(via_serde, owned);
+        assert_eq!(serde_bencode::to_bytes(&owned).unwrap(), data);
+    }
+
+    #[test]
+    fn test_strict_rejects_non_canonical_input() {
+        let kind = |data: &[u8]| decode_strict(data).unwrap_err().kind;
+        assert_eq!(kind(b"d1:bi1e1:ai2ee"), ErrorKind::UnsortedKeys);
+        assert_eq!(kind(b"d1:ai1e1:ai2ee"), ErrorKind::DuplicateKey);
+        assert_eq!(kind(b"i03e"), ErrorKind::LeadingZero);
+        assert_eq!(kind(b"02:ab"), ErrorKind::LeadingZero);
+        assert_eq!(kind(b"i-0e"), ErrorKind::NegativeZero);
+
+        // Lenient decoding takes them; the last of a repeated key wins
+        assert_eq!(decode(b"i03e").unwrap(), ValueRef::Int(3));
+        let value = Value::from(decode(b"d1:bi1e1:ai2e1:ai3ee").unwrap());
+        assert_eq!(value.to_bytes(), b"d1:ai3e1:bi1ee");
+    }
+
+    #[test]
+    fn test_errors_report_offsets() {
+        let error = |data: &[u8]| decode(data).unwrap_err();
+        assert_eq!(
+            error(b"l4:spami1x2ee"),
+            DecodeError {
+                offset: 7,
+                kind: ErrorKind::InvalidInteger
+            }
+        );
+        assert_eq!(error(b"d3:key").offset, 6);
+        assert_eq!(error(b"di1ei2ee").kind, ErrorKind::NonStringKey);
+        assert_eq!(error(b"d1:ae").kind, ErrorKind::MissingValue);
+        assert_eq!(error(b"5:abc").kind, ErrorKind::UnexpectedEof);
+        assert_eq!(error(b"i1ei2e").kind, ErrorKind::TrailingData);
+        assert_eq!(
+            error(b"i99999999999999999999e").kind,
+            ErrorKind::IntegerOverflow
+        );
+        assert_eq!(error(b"x").kind, ErrorKind::UnexpectedByte(b'x'));
+
+        let deep = [vec![b'l'; 100], vec![b'e'; 100]].concat();
+        assert_eq!(error(&deep).offset, DEFAULT_MAX_DEPTH);
+        assert_eq!(error(&deep).kind, ErrorKind::TooDeep);
+        let mut decoder = Decoder::new(b"llee").with_max_depth(1);
+        assert_eq!(decoder.decode().unwrap_err().offset, 1);
+    }
+
+    #[test]
+    fn test_tokens_and_raw_spans() {
+        let data = b"d1:ai1e4:infod1:xli1ei2eee1:z0:e";
+        assert_eq!(
+            dict_value(data, b"info", 8).unwrap(),
+            Some(&b"d1:xli1ei2eee"[..])
+        );
+        assert_eq!(dict_value(data, b"none", 8).unwrap(), None);
+        assert!(dict_value(data, b"info", 1).is_err());
+
+        let mut decoder = Decoder::new(b"li7e3:abce");
+        assert_eq!(decoder.next_token().unwrap(), Some(Token::List));
+        assert_eq!(decoder.next_token().unwrap(), Some(Token::Int(7)));
+        assert_eq!(decoder.offset(), 4);
+        assert_eq!(decoder.skip().unwrap(), b"3:abc");
+        assert_eq!(decoder.next_token().unwrap(), Some(Token::End));
+        assert_eq!(decoder.next_token().unwrap(), None);
+    }
+}
@@ -0,0 +1,286 @@
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use tokio::sync::watch;
+use tokio::time::{Duration, Instant};
+
+/// How often the regular unchoke slots are reassigned.
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+/// How often the optimistic unchoke moves to another peer.
+pub const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+/// Unchoked peers at once, the optimistic one included.
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
+// Peers connected for less than this are more likely to get the optimistic
+// slot, so they get a first piece to trade with
+const NEW_PEER_AGE: Duration = Duration::from_secs(60);
+const NEW_PEER_WEIGHT: u32 = 3;
+
+#[derive(Debug)]
+struct ChokerPeer {
+    addr: SocketAddrV4,
+    interested: bool,
+    choked: bool,
+    // Bytes sent to it since the last round
+    uploaded: u64,
+    connected_at: Instant,
+    choke_tx: watch::Sender<bool>,
+}
+
+/// Tit-for-tat choker for the connections we upload on. Every
+/// `RECHOKE_INTERVAL` the regular slots go to the interested peers that
+/// sent us the most in the last round, or, while seeding, the ones we sent
+/// the most to. One more slot rotates between the rest every
+/// `OPTIMISTIC_INTERVAL`.
+#[derive(Debug)]
+pub struct Choker {
+    upload_slots: usize,
+    peers: HashMap<usize, ChokerPeer>,
+    next_id: usize,
+    optimistic: Option<usize>,
+    optimistic_since: Option<Instant>,
+    // Bytes received from each IP since the last round, over any connection
+    downloaded: HashMap<Ipv4Addr, u64>,
+}
+
+impl Default for Choker {
+    fn default() -> Self {
+        Self::new(DEFAULT_UPLOAD_SLOTS)
+    }
+}
+
+impl Choker {
+    pub fn new(upload_slots: usize) -> Self {
+        Self {
+            upload_slots: upload_slots.max(1),
+            peers: HashMap::new(),
+            next_id: 0,
+            optimistic: None,
+            optimistic_since: None,
+            downloaded: HashMap::new(),
+        }
+    }
+
+    pub fn upload_slots(&self) -> usize {
+        self.upload_slots
+    }
+
+    /// Changes the number of unchoked peers from the next round on. At
+    /// least one slot is always kept, for the optimistic unchoke.
+    pub fn set_upload_slots(&mut self, slots: usize) {
+        self.upload_slots = slots.max(1);
+    }
+
+    /// Adds a connection, initially choked. The receiver sees every
+    /// decision for it: `true` to choke, `false` to unchoke.
+    pub fn register(&mut self, addr: SocketAddrV4) -> (usize, watch::Receiver<bool>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (choke_tx, choke_rx) = watch::channel(true);
+        self.peers.insert(
+            id,
+            ChokerPeer {
+                addr,
+                interested: false,
+                choked: true,
+                uploaded: 0,
+                connected_at: Instant::now(),
+                choke_tx,
+            },
+        );
+        (id, choke_rx)
+    }
+
+    pub fn unregister(&mut self, id: usize) {
+        self.peers.remove(&id);
+        if self.optimistic == Some(id) {
+            self.optimistic = None;
+        }
+    }
+
+    pub fn set_interested(&mut self, id: usize, interested: bool) {
+        let Some(peer) = self.peers.get_mut(&id) else {
+            return;
+        };
+        peer.interested = interested;
+
+        // A free slot is handed out right away rather than at the next round
+        if interested && peer.choked && self.unchoked_count() < self.upload_slots {
+            self.set_choked(id, false);
+        }
+    }
+
+    pub fn record_upload(&mut self, id: usize, bytes: u64) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.uploaded += bytes;
+        }
+    }
+
+    /// Counts data received from `ip`, on whichever connection it came.
+    pub fn record_download(&mut self, ip: Ipv4Addr, bytes: u64) {
+        *self.downloaded.entry(ip).or_default() += bytes;
+    }
+
+    pub fn is_choked(&self, id: usize) -> bool {
+        self.peers.get(&id).is_none_or(|peer| peer.choked)
+    }
+
+    /// Reassigns the unchoke slots and starts a new round.
+    pub fn rechoke(&mut self, seeding: bool) {
+        let now = Instant::now();
+
+        let mut ranked: Vec<(u64, usize)> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.interested)
+            .map(|(&id, peer)| {
+                let rate = if seeding {
+                    peer.uploaded
+                } else {
+                    self.downloaded.get(peer.addr.ip()).copied().unwrap_or(0)
+                };
+                (rate, id)
+            })
+            .collect();
+        ranked.sort_unstable_by(|a, b| b.cmp(a));
+        let regular: HashSet<usize> = ranked
+            .iter()
+            .take(self.upload_slots - 1)
+            .map(|&(_, id)| id)
+            .collect();
+
+        let optimistic_valid = self.optimistic.is_some_and(|id| {
+            !regular.contains(&id) && self.peers.get(&id).is_some_and(|peer| peer.interested)
+        });
+        let rotate = self
+            .optimistic_since
+            .is_none_or(|since| now.duration_since(since) >= OPTIMISTIC_INTERVAL);
+        if !optimistic_valid || rotate {
+            self.optimistic = self.pick_optimistic(&regular, now);
+            self.optimistic_since = Some(now);
+        }
+
+        let ids: Vec<usize> = self.peers.keys().copied().collect();
+        for id in ids {
+            let unchoked = regular.contains(&id) || self.optimistic == Some(id);
+            self.set_choked(id, !unchoked);
+        }
+
+        for peer in self.peers.values_mut() {
+            peer.uploaded = 0;
+        }
+        self.downloaded.clear();
+    }
+
+    // Random interested peer outside the regular slots, weighted towards
+    // new connections
+    fn pick_optimistic(&self, regular: &HashSet<usize>, now: Instant) -> Option<usize> {
+        let candidates: Vec<(usize, u32)> = self
+            .peers
+            .iter()
+            .filter(|(id, peer)| peer.interested && !regular.contains(id))
+            .map(|(&id, peer)| {
+                let weight = if now.duration_since(peer.connected_at) < NEW_PEER_AGE {
+                    NEW_PEER_WEIGHT
+                } else {
+                    1
+                };
+                (id, weight)
+            })
+            .collect();
+
+        let total: u32 = candidates.iter().map(|&(_, weight)| weight).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut pick = rand::thread_rng().gen_range(0..total);
+        for (id, weight) in candidates {
+            if pick < weight {
+                return Some(id);
+            }
+            pick -= weight;
+        }
+        None
+    }
+
+    fn unchoked_count(&self) -> usize {
+        self.peers.values().filter(|peer| !peer.choked).count()
+    }
+
+    fn set_choked(&mut self, id: usize, choked: bool) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            if peer.choked != choked {
+                peer.choked = choked;
+                peer.choke_tx.send_replace(choked);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(last_octet: u8) -> SocketAddrV4 {
+        SocketAddrV4::new([10, 0, 0, last_octet].into(), 6881)
+    }
+
+    #[test]
+    fn test_unchokes_best_peers() {
+        let mut choker = Choker::new(3);
+        let ids: Vec<usize> = (1..=4).map(|i| choker.register(addr(i)).0).collect();
+
+        // Free slots are given out as soon as peers become interested
+        for &id in &ids {
+            choker.set_interested(id, true);
+        }
+        assert_eq!(ids.iter().filter(|&&id| !choker.is_choked(id)).count(), 3);
+
+        // Leeching: the two peers we got the most from, plus an optimistic
+        for (i, bytes) in [(1, 10), (2, 30), (3, 20), (4, 0)] {
+            choker.record_download(*addr(i).ip(), bytes);
+        }
+        choker.rechoke(false);
+        assert!(!choker.is_choked(ids[1]) && !choker.is_choked(ids[2]));
+        assert_eq!(ids.iter().filter(|&&id| !choker.is_choked(id)).count(), 3);
+
+        // Seeding: the two peers we sent the most to
+        choker.record_upload(ids[0], 500);
+        choker.record_upload(ids[3], 400);
+        choker.rechoke(true);
+        assert!(!choker.is_choked(ids[0]) && !choker.is_choked(ids[3]));
+
+        // Uninterested peers don't keep a slot
+        choker.set_interested(ids[0], false);
+        choker.rechoke(true);
+        assert!(choker.is_choked(ids[0]));
+    }
+
+    #[test]
+    fn test_optimistic_unchoke_rotates_and_favors_new_peers() {
+        let mut choker = Choker::new(1);
+        let (old, old_rx) = choker.register(addr(1));
+        let (new, _) = choker.register(addr(2));
+        choker.peers.get_mut(&old).unwrap().connected_at -= NEW_PEER_AGE * 2;
+        choker.set_interested(old, true);
+        choker.set_interested(new, true);
+        assert!(!*old_rx.borrow());
+
+        // The optimistic slot sticks until its interval is up
+        choker.rechoke(false);
+        let current = choker.optimistic.unwrap();
+        choker.rechoke(false);
+        assert_eq!(choker.optimistic, Some(current));
+
+        let mut new_picks = 0;
+        for _ in 0..200 {
+            choker.optimistic_since = None;
+            choker.rechoke(false);
+            if choker.optimistic == Some(new) {
+                new_picks += 1;
+            }
+        }
+        // Expected 150 of 200 with three times the weight
+        assert!(new_picks > 110, "new peer picked {} times", new_picks);
+    }
+}
@@ -1,30 +1,125 @@
-// src/client.rs
 use crate::{
+    dht::{resolve_bootstrap_nodes, Dht, DEFAULT_BOOTSTRAP_NODES},
     download::Download,
     error::{BitTorrentError, Result},
+    magnet::MagnetLink,
+    metadata,
+    rate_limit::RateLimits,
+    seed::PeerListener,
     torrent::Torrent,
+    DEFAULT_PORT_RANGE,
 };
-use std::path::Path;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+#[derive(Default)]
 pub struct Client {
     download: Option<Download>,
+    listener: Option<PeerListener>,
+    dht: Option<Arc<Dht>>,
+    // Where the DHT routing table is saved between runs
+    dht_node_cache: Option<PathBuf>,
+    // Shared by every torrent's connections
+    rate_limits: RateLimits,
 }
 
 impl Client {
     pub fn new() -> Self {
-        Self { download: None }
+        Self {
+            download: None,
+            listener: None,
+            dht: None,
+            dht_node_cache: None,
+            rate_limits: RateLimits::new(),
+        }
     }
 
-    pub async fn add_torrent(&mut self, path: impl AsRef<Path>) -> Result<()> {
+    /// Joins the DHT, bootstrapping from the nodes saved in `node_cache`
+    /// by a previous run and the well-known routers. Torrents added
+    /// afterwards also look for peers there.
+    pub async fn start_dht(&mut self, node_cache: impl AsRef<Path>) -> Result<()> {
+        // Use the same port number as for TCP when it is free, like most clients
+        let port = self.listener().await?.port();
+        let dht = match Dht::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)).await {
+            Ok(dht) => dht,
+            Err(_) => Dht::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await?,
+        };
+
+        let node_cache = node_cache.as_ref().to_path_buf();
+        let mut nodes: Vec<SocketAddrV4> = Dht::load_nodes(&node_cache)
+            .await
+            .into_iter()
+            .map(|node| node.addr)
+            .collect();
+        nodes.extend(resolve_bootstrap_nodes(DEFAULT_BOOTSTRAP_NODES).await);
+
+        let count = dht.bootstrap(&nodes).await;
+        println!(
+            "DHT listening on port {} with {} nodes",
+            dht.local_addr()?.port(),
+            count
+        );
+        if let Err(e) = dht.save_nodes(&node_cache).await {
+            println!("Failed to save DHT nodes: {}", e);
+        }
+
+        self.dht = Some(Arc::new(dht));
+        self.dht_node_cache = Some(node_cache);
+        Ok(())
+    }
+
+    pub async fn add_torrent(
+        &mut self,
+        path: impl AsRef<Path>,
+        download_dir: impl AsRef<Path>,
+    ) -> Result<()> {
         println!("Loading torrent file: {:?}", path.as_ref());
         let torrent = Torrent::from_file(path).await?;
-        println!("Torrent loaded successfully. Info: {}", torrent.info);
-        let download = Download::new(torrent).await?;
+        println!("Torrent loaded successfully. Info: {}", torrent);
+
+        self.add(torrent, download_dir).await
+    }
+
+    /// Adds a torrent from a `magnet:` URI, fetching its metadata from
+    /// peers first.
+    pub async fn add_magnet(&mut self, uri: &str, download_dir: impl AsRef<Path>) -> Result<()> {
+        let magnet = MagnetLink::parse(uri)?;
+        println!(
+            "Fetching metadata for {}",
+            magnet
+                .display_name
+                .clone()
+                .unwrap_or_else(|| hex::encode(magnet.info_hash))
+        );
+
+        let port = self.listener().await?.port();
+        let torrent = metadata::fetch_torrent(&magnet, port, self.dht.as_deref()).await?;
+        println!("Metadata received. Info: {}", torrent);
+
+        self.add(torrent, download_dir).await
+    }
+
+    async fn add(&mut self, torrent: Torrent, download_dir: impl AsRef<Path>) -> Result<()> {
+        let dht = self.dht.clone();
+        let rate_limits = self.rate_limits.clone();
+        let listener = self.listener().await?;
+        let mut download = Download::new(torrent, download_dir, listener.port(), dht).await?;
+        download.set_global_rate_limits(rate_limits);
+        listener.register(download.seed_context()).await;
         self.download = Some(download);
         Ok(())
     }
 
-    pub async fn start_download(&mut self) -> Result<Vec<u8>> {
+    // Listen before announcing so the tracker gets the real port
+    async fn listener(&mut self) -> Result<&PeerListener> {
+        if self.listener.is_none() {
+            self.listener = Some(PeerListener::bind(DEFAULT_PORT_RANGE).await?);
+        }
+        Ok(self.listener.as_ref().expect("listener was just bound"))
+    }
+
+    pub async fn start_download(&mut self) -> Result<()> {
         if let Some(download) = &mut self.download {
             download.download_all().await
         } else {
@@ -32,15 +127,60 @@ impl Client {
         }
     }
 
-    pub fn progress(&self) -> Option<f64> {
-        self.download.as_ref().map(|d| d.progress())
+    /// Keeps serving loaded torrents to other peers until Ctrl+C, then
+    /// tells the trackers we have stopped.
+    pub async fn seed(&mut self) -> Result<()> {
+        let Some(download) = &mut self.download else {
+            return Err(BitTorrentError::Client("No torrent loaded".into()));
+        };
+        tokio::signal::ctrl_c().await?;
+        download.shutdown().await;
+
+        if let (Some(dht), Some(path)) = (&self.dht, &self.dht_node_cache) {
+            dht.save_nodes(path).await?;
+        }
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::path::PathBuf;
-    use std::sync::atomic::{AtomicUsize, Ordering};
-    use std::sync::Arc;
+    /// Handle on the limits shared by all torrents. Clones stay valid, so
+    /// the limits can be changed while a download runs.
+    pub fn rate_limits(&self) -> RateLimits {
+        self.rate_limits.clone()
+    }
+
+    /// Handle on the loaded torrent's own limits.
+    pub fn torrent_rate_limits(&self) -> Option<RateLimits> {
+        self.download
+            .as_ref()
+            .map(|download| download.rate_limits())
+    }
+
+    /// Handle on the limits each of the loaded torrent's connections gets.
+    pub fn peer_rate_limits(&self) -> Option<RateLimits> {
+        self.download
+            .as_ref()
+            .map(|download| download.peer_rate_limits())
+    }
+
+    /// Sets how many peers the loaded torrent uploads to at once.
+    pub async fn set_upload_slots(&self, slots: usize) -> Result<()> {
+        match &self.download {
+            Some(download) => {
+                download.set_upload_slots(slots).await;
+                Ok(())
+            }
+            None => Err(BitTorrentError::Client("No torrent loaded".into())),
+        }
+    }
+
+    pub async fn progress(&self) -> Option<f64> {
+        match &self.download {
+            Some(download) => Some(download.progress().await),
+            None => None,
+        }
+    }
+
+    pub fn output_path(&self) -> Option<&Path> {
+        self.download.as_ref().map(|d| d.output_path())
+    }
 }
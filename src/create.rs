@@ -0,0 +1,421 @@
+//! Making `.torrent` files from files on disk.
+
+use crate::{
+    error::{BitTorrentError, Result},
+    torrent::{FileInfo, FileMode, Info, PieceHashes, Torrent, UrlList},
+    utils::calculate_piece_hash,
+    BLOCK_SIZE,
+};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MIN_PIECE_LENGTH: usize = BLOCK_SIZE;
+pub const MAX_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+// Enough pieces for fine-grained sharing without bloating the torrent with
+// hashes
+const TARGET_PIECES: usize = 1500;
+
+/// A power of two piece length giving about 1500 pieces for
+/// `total_length` bytes, between 16 KiB and 16 MiB.
+pub fn auto_piece_length(total_length: usize) -> usize {
+    (total_length / TARGET_PIECES)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+/// Builds a v1 torrent from a file, or from every file under a directory.
+#[derive(Debug, Clone)]
+pub struct TorrentBuilder {
+    path: PathBuf,
+    piece_length: Option<usize>,
+    trackers: Vec<Vec<String>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<i64>,
+    private: bool,
+    web_seeds: Vec<String>,
+}
+
+impl TorrentBuilder {
+    /// By default the piece length is picked from the total size, the
+    /// creation date is now, and `created by` names this client.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        let creation_date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|elapsed| elapsed.as_secs() as i64);
+        Self {
+            path: path.as_ref().to_path_buf(),
+            piece_length: None,
+            trackers: Vec::new(),
+            comment: None,
+            created_by: Some(format!("bittorrent {}", env!("CARGO_PKG_VERSION"))),
+            creation_date,
+            private: false,
+            web_seeds: Vec::new(),
+        }
+    }
+
+    /// A power of two of at least 16 KiB.
+    pub fn with_piece_length(mut self, piece_length: usize) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    /// Adds a tier of trackers (BEP 12). The first tracker of the first
+    /// tier also goes in `announce`.
+    pub fn with_tracker_tier(mut self, tier: Vec<String>) -> Self {
+        if !tier.is_empty() {
+            self.trackers.push(tier);
+        }
+        self
+    }
+
+    pub fn with_comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    pub fn with_created_by(mut self, created_by: Option<String>) -> Self {
+        self.created_by = created_by;
+        self
+    }
+
+    /// Seconds since the Unix epoch, or `None` to leave the date out.
+    pub fn with_creation_date(mut self, creation_date: Option<i64>) -> Self {
+        self.creation_date = creation_date;
+        self
+    }
+
+    pub fn with_private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Adds a web seed URL (BEP 19).
+    pub fn with_web_seed(mut self, url: impl Into<String>) -> Self {
+        self.web_seeds.push(url.into());
+        self
+    }
+
+    /// Walks the files and hashes their pieces, on as many threads as
+    /// there are cores.
+    pub async fn build(self) -> Result<Torrent> {
+        let root = self.path.clone();
+        let source = tokio::task::spawn_blocking(move || Source::scan(&root)).await??;
+
+        let total_length = source.total_length();
+        if total_length == 0 {
+            return Err(BitTorrentError::InvalidData(format!(
+                "Nothing to share in {}",
+                self.path.display()
+            )));
+        }
+        let piece_length = self
+            .piece_length
+            .unwrap_or_else(|| auto_piece_length(total_length));
+        if !piece_length.is_power_of_two() || piece_length < MIN_PIECE_LENGTH {
+            return Err(BitTorrentError::InvalidData(format!(
+                "Piece length must be a power of two of at least {} bytes",
+                MIN_PIECE_LENGTH
+            )));
+        }
+
+        let name = file_name(&self.path)?;
+        let files = if source.single_file {
+            FileMode::SingleFile {
+                length: total_length,
+            }
+        } else {
+            FileMode::MultiFile {
+                files: source
+                    .files
+                    .iter()
+                    .map(|file| FileInfo {
+                        length: file.length,
+                        path: file.path.clone(),
+                        attr: None,
+                        md5sum: None,
+                    })
+                    .collect(),
+            }
+        };
+        let pieces = source.hash_pieces(piece_length).await?;
+        let info = Info {
+            name,
+            piece_length,
+            pieces: PieceHashes(pieces),
+            files,
+            meta_version: None,
+            file_tree: None,
+            private: self.private.then_some(1),
+            md5sum: None,
+            extra: BTreeMap::new(),
+        };
+
+        let mut torrent = Torrent::new(info, self.trackers);
+        torrent.comment = self.comment;
+        torrent.created_by = self.created_by;
+        torrent.creation_date = self.creation_date;
+        torrent.url_list = match self.web_seeds.len() {
+            0 => None,
+            1 => self.web_seeds.into_iter().next().map(UrlList::Single),
+            _ => Some(UrlList::Multiple(self.web_seeds)),
+        };
+        Ok(torrent)
+    }
+}
+
+fn file_name(path: &Path) -> Result<String> {
+    // `.` and `..` have no name of their own
+    let path = fs::canonicalize(path)?;
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_string)
+        .ok_or_else(|| {
+            BitTorrentError::InvalidData(format!("{} has no UTF-8 name", path.display()))
+        })
+}
+
+#[derive(Debug)]
+struct SourceFile {
+    full_path: PathBuf,
+    // Components relative to the torrent's root directory
+    path: Vec<String>,
+    length: usize,
+}
+
+// The files of a torrent, whose data runs on from one to the next as
+// pieces see it
+#[derive(Debug)]
+struct Source {
+    files: Vec<SourceFile>,
+    single_file: bool,
+}
+
+impl Source {
+    fn scan(root: &Path) -> Result<Self> {
+        let metadata = fs::metadata(root)?;
+        if metadata.is_file() {
+            return Ok(Self {
+                files: vec![SourceFile {
+                    full_path: root.to_path_buf(),
+                    path: Vec::new(),
+                    length: metadata.len() as usize,
+                }],
+                single_file: true,
+            });
+        }
+
+        let mut files = Vec::new();
+        Self::walk(root, &mut Vec::new(), &mut files)?;
+        Ok(Self {
+            files,
+            single_file: false,
+        })
+    }
+
+    // Sorted by name so the same directory always gives the same torrent
+    fn walk(dir: &Path, path: &mut Vec<String>, files: &mut Vec<SourceFile>) -> Result<()> {
+        let mut entries = fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let name = entry.file_name().into_string().map_err(|name| {
+                BitTorrentError::InvalidData(format!("{:?} is not valid UTF-8", name))
+            })?;
+            // Follows symlinks, like the file's data will be read
+            let metadata = fs::metadata(entry.path())?;
+            path.push(name);
+            if metadata.is_dir() {
+                Self::walk(&entry.path(), path, files)?;
+            } else if metadata.is_file() {
+                files.push(SourceFile {
+                    full_path: entry.path(),
+                    path: path.clone(),
+                    length: metadata.len() as usize,
+                });
+            }
+            path.pop();
+        }
+        Ok(())
+    }
+
+    fn total_length(&self) -> usize {
+        self.files.iter().map(|file| file.length).sum()
+    }
+
+    // Splits the pieces into one contiguous run per worker, so each reads
+    // its part of the data sequentially
+    async fn hash_pieces(self, piece_length: usize) -> Result<Vec<[u8; 20]>> {
+        let num_pieces = self.total_length().div_ceil(piece_length);
+        let workers = thread::available_parallelism()
+            .map_or(1, NonZeroUsize::get)
+            .min(num_pieces);
+        let per_worker = num_pieces.div_ceil(workers);
+
+        let source = Arc::new(self);
+        let tasks: Vec<_> = (0..num_pieces)
+            .step_by(per_worker)
+            .map(|start| {
+                let source = Arc::clone(&source);
+                let pieces = start..(start + per_worker).min(num_pieces);
+                tokio::task::spawn_blocking(move || source.hash_range(pieces, piece_length))
+            })
+            .collect();
+
+        let mut hashes = Vec::with_capacity(num_pieces);
+        for task in tasks {
+            hashes.extend(task.await??);
+        }
+        Ok(hashes)
+    }
+
+    fn hash_range(&self, pieces: Range<usize>, piece_length: usize) -> Result<Vec<[u8; 20]>> {
+        let total_length = self.total_length();
+        let mut reader = SourceReader::new(self, pieces.start * piece_length);
+        let mut buffer = vec![0; piece_length];
+        pieces
+            .map(|index| {
+                let start = index * piece_length;
+                let length = piece_length.min(total_length - start);
+                reader.read_exact(&mut buffer[..length])?;
+                Ok(calculate_piece_hash(&buffer[..length]))
+            })
+            .collect()
+    }
+}
+
+// Reads the files' data in order from an offset, opening each file in turn
+struct SourceReader<'a> {
+    files: &'a [SourceFile],
+    // Index of the file the next read starts in, and the offset in it
+    file_index: usize,
+    file_offset: usize,
+    file: Option<File>,
+}
+
+impl<'a> SourceReader<'a> {
+    fn new(source: &'a Source, mut offset: usize) -> Self {
+        let mut file_index = 0;
+        while file_index < source.files.len() && offset >= source.files[file_index].length {
+            offset -= source.files[file_index].length;
+            file_index += 1;
+        }
+        Self {
+            files: &source.files,
+            file_index,
+            file_offset: offset,
+            file: None,
+        }
+    }
+
+    fn read_exact(&mut self, mut buffer: &mut [u8]) -> Result<()> {
+        while !buffer.is_empty() {
+            let entry = self
+                .files
+                .get(self.file_index)
+                .ok_or_else(|| BitTorrentError::InvalidData("Files shrank while hashing".into()))?;
+            let length = buffer.len().min(entry.length - self.file_offset);
+            if length > 0 {
+                let file = match &mut self.file {
+                    Some(file) => file,
+                    None => {
+                        let mut file = File::open(&entry.full_path)?;
+                        file.seek(SeekFrom::Start(self.file_offset as u64))?;
+                        self.file.insert(file)
+                    }
+                };
+                file.read_exact(&mut buffer[..length])?;
+                self.file_offset += length;
+                buffer = &mut buffer[length..];
+            }
+            if self.file_offset == entry.length {
+                self.file_index += 1;
+                self.file_offset = 0;
+                self.file = None;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auto_piece_length() {
+        assert_eq!(auto_piece_length(1), MIN_PIECE_LENGTH);
+        assert_eq!(auto_piece_length(700 * 1024 * 1024), 512 * 1024);
+        assert_eq!(auto_piece_length(1 << 50), MAX_PIECE_LENGTH);
+    }
+
+    #[tokio::test]
+    async fn test_builds_torrent_from_directory() {
+        let root = std::env::temp_dir().join(format!("bt-create-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let dir = root.join("share");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i / 7) as u8).collect();
+        fs::write(dir.join("b.bin"), &data[..30_000]).unwrap();
+        fs::write(dir.join("a.bin"), &data[30_000..31_000]).unwrap();
+        fs::write(dir.join("sub").join("c.bin"), &data[31_000..]).unwrap();
+
+        let torrent = TorrentBuilder::new(&dir)
+            .with_piece_length(16384)
+            .with_tracker_tier(vec!["http://t/announce".into()])
+            .with_tracker_tier(vec!["udp://u:1337".into()])
+            .with_comment("test")
+            .with_creation_date(Some(1_700_000_000))
+            .with_private(true)
+            .with_web_seed("http://w/")
+            .build()
+            .await
+            .unwrap();
+
+        // Round trips through its bencoding
+        let bytes = torrent.to_bytes().unwrap();
+        let parsed: Torrent = serde_bencode::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.info_hash(), torrent.info_hash());
+        assert_eq!(parsed.info.name, "share");
+        assert_eq!(parsed.info.private, Some(1));
+        assert_eq!(parsed.announce, "http://t/announce");
+        assert_eq!(parsed.trackers().len(), 2);
+        assert_eq!(parsed.comment.as_deref(), Some("test"));
+        assert_eq!(parsed.creation_date, Some(1_700_000_000));
+        assert_eq!(parsed.web_seeds(), vec!["http://w/".to_string()]);
+
+        // Files in name order, pieces running across them
+        let FileMode::MultiFile { files } = &parsed.info.files else {
+            panic!("expected a multi-file torrent");
+        };
+        let paths: Vec<String> = files.iter().map(|file| file.path.join("/")).collect();
+        assert_eq!(paths, vec!["a.bin", "b.bin", "sub/c.bin"]);
+        let mut joined = data[30_000..31_000].to_vec();
+        joined.extend_from_slice(&data[..30_000]);
+        joined.extend_from_slice(&data[31_000..]);
+        let expected: Vec<[u8; 20]> = joined.chunks(16384).map(calculate_piece_hash).collect();
+        assert_eq!(parsed.info.pieces.0, expected);
+
+        // A lone file makes a single-file torrent
+        let single = TorrentBuilder::new(dir.join("b.bin"))
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(single.info.name, "b.bin");
+        assert!(matches!(
+            single.info.files,
+            FileMode::SingleFile { length: 30_000 }
+        ));
+        assert_eq!(single.info.pieces.0.len(), 2);
+
+        let _ = fs::remove_dir_all(&root);
+    }
+}
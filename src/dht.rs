@@ -0,0 +1,885 @@
+use crate::{
+    error::{BitTorrentError, Result},
+    krpc::{
+        self, KrpcMessage, NodeInfo, QueryArgs, ResponseValues, ERROR_GENERIC,
+        ERROR_METHOD_UNKNOWN, ERROR_PROTOCOL,
+    },
+    resume::temp_path,
+};
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::Path;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration, Instant};
+
+/// Well-known routers used to join the network when the node cache is empty.
+pub const DEFAULT_BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+// Bucket size, and the number of closest nodes a lookup converges on
+const K: usize = 8;
+// Queries in flight at once during a lookup
+const ALPHA: usize = 3;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+// A node that failed this many queries in a row can be replaced
+const MAX_NODE_FAILURES: u32 = 2;
+
+// Tokens are tied to the querier's IP and a secret rotated this often;
+// tokens made with the previous secret are still accepted
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+const TOKEN_LENGTH: usize = 8;
+
+// Announced peers are forgotten after this
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+const MAX_PEERS_PER_HASH: usize = 200;
+// Peers returned in one get_peers response, so it fits in a datagram
+const MAX_VALUES: usize = 50;
+const RECV_BUFFER_SIZE: usize = 4096;
+
+/// XOR distance between two ids, comparable as big-endian integers.
+pub fn distance(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    let mut out = [0u8; 20];
+    for i in 0..20 {
+        out[i] = a[i] ^ b[i];
+    }
+    out
+}
+
+#[derive(Debug, Clone)]
+struct RoutingEntry {
+    node: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+/// Kademlia routing table: one bucket of up to `K` nodes per length of the
+/// prefix shared with our own id.
+#[derive(Debug)]
+pub struct RoutingTable {
+    own_id: [u8; 20],
+    buckets: Vec<Vec<RoutingEntry>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: [u8; 20]) -> Self {
+        Self {
+            own_id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    fn bucket_index(&self, id: &[u8; 20]) -> Option<usize> {
+        let distance = distance(&self.own_id, id);
+        let zeros: usize = distance
+            .iter()
+            .position(|&byte| byte != 0)
+            .map(|i| i * 8 + distance[i].leading_zeros() as usize)?;
+        Some(zeros)
+    }
+
+    /// Records that `node` responded or contacted us. Returns whether it is
+    /// now in the table; a full bucket only makes room by evicting a node
+    /// that has stopped responding.
+    pub fn insert(&mut self, node: NodeInfo) -> bool {
+        let Some(index) = self.bucket_index(&node.id) else {
+            return false;
+        };
+        // A node that changed its id is a different node now
+        for bucket in &mut self.buckets {
+            bucket.retain(|entry| entry.node.addr != node.addr || entry.node.id == node.id);
+        }
+
+        let bucket = &mut self.buckets[index];
+        let entry = RoutingEntry {
+            node,
+            last_seen: Instant::now(),
+            failures: 0,
+        };
+
+        if let Some(position) = bucket.iter().position(|e| e.node.id == node.id) {
+            // Most recently seen nodes live at the back
+            bucket.remove(position);
+            bucket.push(entry);
+            return true;
+        }
+        if bucket.len() < K {
+            bucket.push(entry);
+            return true;
+        }
+        if let Some(position) = bucket.iter().position(|e| e.failures >= MAX_NODE_FAILURES) {
+            bucket.remove(position);
+            bucket.push(entry);
+            return true;
+        }
+        false
+    }
+
+    /// Counts a query to `addr` that went unanswered.
+    pub fn mark_failed(&mut self, addr: SocketAddrV4) {
+        for entry in self.buckets.iter_mut().flatten() {
+            if entry.node.addr == addr {
+                entry.failures += 1;
+            }
+        }
+    }
+
+    /// Up to `count` known nodes closest to `target`, nearest first.
+    pub fn closest(&self, target: &[u8; 20], count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self.nodes();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// Nodes that answered their last query, oldest first per bucket.
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.buckets
+            .iter()
+            .flatten()
+            .filter(|entry| entry.failures < MAX_NODE_FAILURES)
+            .map(|entry| entry.node)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// When each node was last heard from.
+    pub fn last_seen(&self, id: &[u8; 20]) -> Option<Instant> {
+        let index = self.bucket_index(id)?;
+        self.buckets[index]
+            .iter()
+            .find(|entry| entry.node.id == *id)
+            .map(|entry| entry.last_seen)
+    }
+}
+
+// Secrets for the write tokens handed out in get_peers responses
+struct TokenSecrets {
+    current: [u8; 16],
+    previous: [u8; 16],
+    rotated: Instant,
+}
+
+impl TokenSecrets {
+    fn new() -> Self {
+        let secret = rand::random();
+        Self {
+            current: secret,
+            previous: secret,
+            rotated: Instant::now(),
+        }
+    }
+
+    fn rotate_if_due(&mut self) {
+        if self.rotated.elapsed() >= TOKEN_ROTATION {
+            self.previous = self.current;
+            self.current = rand::random();
+            self.rotated = Instant::now();
+        }
+    }
+
+    fn token(&mut self, ip: Ipv4Addr) -> Vec<u8> {
+        self.rotate_if_due();
+        make_token(&self.current, ip)
+    }
+
+    fn validate(&mut self, ip: Ipv4Addr, token: &[u8]) -> bool {
+        self.rotate_if_due();
+        token == make_token(&self.current, ip) || token == make_token(&self.previous, ip)
+    }
+}
+
+fn make_token(secret: &[u8; 16], ip: Ipv4Addr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(secret);
+    hasher.update(ip.octets());
+    hasher.finalize()[..TOKEN_LENGTH].to_vec()
+}
+
+// Peers announced to us, per info-hash
+#[derive(Default)]
+struct PeerStore {
+    peers: HashMap<[u8; 20], HashMap<SocketAddrV4, Instant>>,
+}
+
+impl PeerStore {
+    fn add(&mut self, info_hash: [u8; 20], peer: SocketAddrV4) {
+        let peers = self.peers.entry(info_hash).or_default();
+        if peers.len() < MAX_PEERS_PER_HASH || peers.contains_key(&peer) {
+            peers.insert(peer, Instant::now());
+        }
+    }
+
+    fn get(&mut self, info_hash: &[u8; 20]) -> Vec<SocketAddrV4> {
+        let Some(peers) = self.peers.get_mut(info_hash) else {
+            return Vec::new();
+        };
+        peers.retain(|_, announced| announced.elapsed() < PEER_TTL);
+        peers.keys().take(MAX_VALUES).copied().collect()
+    }
+}
+
+struct State {
+    table: RoutingTable,
+    tokens: TokenSecrets,
+    peers: PeerStore,
+    // Outstanding queries by transaction id, with the address queried
+    pending: HashMap<[u8; 2], (SocketAddrV4, oneshot::Sender<KrpcMessage>)>,
+}
+
+struct Shared {
+    socket: UdpSocket,
+    id: [u8; 20],
+    next_transaction: AtomicU16,
+    state: Mutex<State>,
+}
+
+// What an iterative lookup found
+struct Lookup {
+    peers: Vec<SocketAddrV4>,
+    // Nodes that answered, nearest first, with the token they gave us
+    closest: Vec<(NodeInfo, Option<ByteBuf>)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedNode {
+    id: String,
+    addr: SocketAddrV4,
+}
+
+/// A mainline DHT node (BEP 5). Answers queries from other nodes in the
+/// background and runs lookups to find peers for an info-hash.
+pub struct Dht {
+    shared: Arc<Shared>,
+    task: JoinHandle<()>,
+}
+
+impl Dht {
+    /// Binds a node with a random id to `addr`.
+    pub async fn bind(addr: SocketAddrV4) -> Result<Self> {
+        Self::bind_with_id(addr, rand::random()).await
+    }
+
+    pub async fn bind_with_id(addr: SocketAddrV4, id: [u8; 20]) -> Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+        let shared = Arc::new(Shared {
+            socket,
+            id,
+            next_transaction: AtomicU16::new(rand::random()),
+            state: Mutex::new(State {
+                table: RoutingTable::new(id),
+                tokens: TokenSecrets::new(),
+                peers: PeerStore::default(),
+                pending: HashMap::new(),
+            }),
+        });
+        let task = tokio::spawn(recv_loop(Arc::clone(&shared)));
+        Ok(Self { shared, task })
+    }
+
+    pub fn id(&self) -> [u8; 20] {
+        self.shared.id
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddrV4> {
+        match self.shared.socket.local_addr()? {
+            SocketAddr::V4(addr) => Ok(addr),
+            SocketAddr::V6(_) => Err(BitTorrentError::Dht("DHT bound to IPv6".into())),
+        }
+    }
+
+    pub async fn node_count(&self) -> usize {
+        self.shared.state.lock().await.table.len()
+    }
+
+    /// Returns the id of the node at `addr`.
+    pub async fn ping(&self, addr: SocketAddrV4) -> Result<[u8; 20]> {
+        let response = self
+            .shared
+            .query(addr, "ping", QueryArgs::default())
+            .await?;
+        krpc::parse_id(&response.id)
+            .ok_or_else(|| BitTorrentError::Dht("Invalid node id in ping response".into()))
+    }
+
+    /// Asks the node at `addr` for the nodes it knows closest to `target`.
+    pub async fn find_node(&self, addr: SocketAddrV4, target: [u8; 20]) -> Result<Vec<NodeInfo>> {
+        let args = QueryArgs {
+            target: Some(ByteBuf::from(target.to_vec())),
+            ..Default::default()
+        };
+        let response = self.shared.query(addr, "find_node", args).await?;
+        Ok(response
+            .nodes
+            .map(|nodes| krpc::decode_nodes(&nodes))
+            .unwrap_or_default())
+    }
+
+    /// Joins the network through `nodes` (cached nodes or routers) and
+    /// fills the routing table with a lookup of our own id. Returns the
+    /// number of nodes in the table afterwards.
+    pub async fn bootstrap(&self, nodes: &[SocketAddrV4]) -> usize {
+        let own_id = self.id();
+        let mut queries: FuturesUnordered<_> = nodes
+            .iter()
+            .map(|&addr| self.find_node(addr, own_id))
+            .collect();
+
+        let mut found = Vec::new();
+        while let Some(result) = queries.next().await {
+            if let Ok(nodes) = result {
+                found.extend(nodes);
+            }
+        }
+
+        self.lookup(own_id, false, found).await;
+        self.node_count().await
+    }
+
+    /// Finds peers for `info_hash` without announcing ourselves.
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> Result<Vec<SocketAddrV4>> {
+        self.ensure_bootstrapped().await?;
+        Ok(self.lookup(info_hash, true, Vec::new()).await.peers)
+    }
+
+    /// Finds peers for `info_hash` and announces that we accept
+    /// connections on `port` to the closest nodes.
+    pub async fn announce(&self, info_hash: [u8; 20], port: u16) -> Result<Vec<SocketAddrV4>> {
+        self.ensure_bootstrapped().await?;
+        let lookup = self.lookup(info_hash, true, Vec::new()).await;
+
+        let mut announces: FuturesUnordered<_> = lookup
+            .closest
+            .into_iter()
+            .filter_map(|(node, token)| Some((node, token?)))
+            .take(K)
+            .map(|(node, token)| {
+                let args = QueryArgs {
+                    info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+                    port: Some(port),
+                    token: Some(token),
+                    implied_port: Some(0),
+                    ..Default::default()
+                };
+                self.shared.query(node.addr, "announce_peer", args)
+            })
+            .collect();
+
+        let mut stored = 0;
+        while let Some(result) = announces.next().await {
+            if result.is_ok() {
+                stored += 1;
+            }
+        }
+        println!("DHT: announced to {} nodes", stored);
+
+        Ok(lookup.peers)
+    }
+
+    /// Writes the good nodes in the routing table to `path`.
+    pub async fn save_nodes(&self, path: impl AsRef<Path>) -> Result<()> {
+        let nodes: Vec<CachedNode> = self
+            .shared
+            .state
+            .lock()
+            .await
+            .table
+            .nodes()
+            .into_iter()
+            .map(|node| CachedNode {
+                id: hex::encode(node.id),
+                addr: node.addr,
+            })
+            .collect();
+        let data =
+            serde_json::to_vec(&nodes).map_err(|e| BitTorrentError::InvalidData(e.to_string()))?;
+
+        let path = path.as_ref();
+        let tmp = temp_path(path);
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+
+    /// Loads nodes saved by `save_nodes`. A missing or broken cache just
+    /// means bootstrapping from the routers.
+    pub async fn load_nodes(path: impl AsRef<Path>) -> Vec<NodeInfo> {
+        let Ok(data) = tokio::fs::read(path).await else {
+            return Vec::new();
+        };
+        let nodes: Vec<CachedNode> = serde_json::from_slice(&data).unwrap_or_default();
+        nodes
+            .into_iter()
+            .filter_map(|node| {
+                Some(NodeInfo {
+                    id: hex::decode(node.id).ok()?.try_into().ok()?,
+                    addr: node.addr,
+                })
+            })
+            .collect()
+    }
+
+    async fn ensure_bootstrapped(&self) -> Result<()> {
+        if self.node_count().await == 0 {
+            return Err(BitTorrentError::Dht("Routing table is empty".into()));
+        }
+        Ok(())
+    }
+
+    // Iterative Kademlia lookup: repeatedly queries the closest nodes not
+    // yet asked, `ALPHA` at a time, until the `K` closest known nodes have
+    // all answered or failed.
+    async fn lookup(&self, target: [u8; 20], get_peers: bool, seeds: Vec<NodeInfo>) -> Lookup {
+        let own_id = self.id();
+        let mut candidates: BTreeMap<[u8; 20], NodeInfo> = BTreeMap::new();
+        let known = self.shared.state.lock().await.table.closest(&target, K);
+        for node in known.into_iter().chain(seeds) {
+            if node.id != own_id {
+                candidates.insert(distance(&node.id, &target), node);
+            }
+        }
+
+        let mut queried = HashSet::new();
+        let mut closest = BTreeMap::new();
+        let mut peers = Vec::new();
+        let mut in_flight = FuturesUnordered::new();
+
+        loop {
+            while in_flight.len() < ALPHA {
+                let next = candidates
+                    .values()
+                    .take(K)
+                    .find(|node| !queried.contains(&node.addr))
+                    .copied();
+                let Some(node) = next else {
+                    break;
+                };
+                queried.insert(node.addr);
+                let shared = &self.shared;
+                in_flight.push(async move {
+                    let args = if get_peers {
+                        QueryArgs {
+                            info_hash: Some(ByteBuf::from(target.to_vec())),
+                            ..Default::default()
+                        }
+                    } else {
+                        QueryArgs {
+                            target: Some(ByteBuf::from(target.to_vec())),
+                            ..Default::default()
+                        }
+                    };
+                    let method = if get_peers { "get_peers" } else { "find_node" };
+                    (node, shared.query(node.addr, method, args).await)
+                });
+            }
+
+            let Some((node, result)) = in_flight.next().await else {
+                break;
+            };
+            let response = match result {
+                Ok(response) => response,
+                Err(_) => {
+                    candidates.remove(&distance(&node.id, &target));
+                    continue;
+                }
+            };
+
+            for value in response.values.iter().flatten() {
+                if let Some(peer) = krpc::decode_peer(value) {
+                    if !peers.contains(&peer) {
+                        peers.push(peer);
+                    }
+                }
+            }
+            for found in response
+                .nodes
+                .as_ref()
+                .map(|nodes| krpc::decode_nodes(nodes))
+                .unwrap_or_default()
+            {
+                if found.id != own_id && !queried.contains(&found.addr) {
+                    candidates.insert(distance(&found.id, &target), found);
+                }
+            }
+            // The id it reports is the one the token and distance are for
+            if let Some(id) = krpc::parse_id(&response.id) {
+                let node = NodeInfo {
+                    id,
+                    addr: node.addr,
+                };
+                closest.insert(distance(&id, &target), (node, response.token));
+            }
+        }
+
+        Lookup {
+            peers,
+            closest: closest.into_values().collect(),
+        }
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Shared {
+    async fn send(&self, message: &KrpcMessage, addr: SocketAddrV4) -> Result<()> {
+        self.socket.send_to(&message.encode()?, addr).await?;
+        Ok(())
+    }
+
+    async fn query(
+        &self,
+        addr: SocketAddrV4,
+        method: &str,
+        mut args: QueryArgs,
+    ) -> Result<ResponseValues> {
+        args.id = ByteBuf::from(self.id.to_vec());
+        let transaction = self
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes();
+        let (tx, rx) = oneshot::channel();
+        self.state
+            .lock()
+            .await
+            .pending
+            .insert(transaction, (addr, tx));
+
+        let sent = self
+            .send(&KrpcMessage::query(transaction, method, args), addr)
+            .await;
+        let result = match sent {
+            Ok(()) => timeout(QUERY_TIMEOUT, rx).await,
+            Err(e) => {
+                self.state.lock().await.pending.remove(&transaction);
+                return Err(e);
+            }
+        };
+
+        let mut state = self.state.lock().await;
+        state.pending.remove(&transaction);
+        let message = match result {
+            Ok(Ok(message)) => message,
+            Ok(Err(_)) => return Err(BitTorrentError::Dht("Query abandoned".into())),
+            Err(elapsed) => {
+                state.table.mark_failed(addr);
+                return Err(elapsed.into());
+            }
+        };
+        drop(state);
+
+        if let Some((code, reason)) = message.e {
+            return Err(BitTorrentError::Dht(format!(
+                "{} returned error {}: {}",
+                addr, code, reason
+            )));
+        }
+        message
+            .r
+            .ok_or_else(|| BitTorrentError::Dht(format!("{} sent an empty response", addr)))
+    }
+
+    async fn handle_reply(&self, message: KrpcMessage, from: SocketAddrV4) {
+        let Ok(transaction) = <[u8; 2]>::try_from(message.t.as_ref()) else {
+            return;
+        };
+        let mut state = self.state.lock().await;
+        // Only the node we asked may answer
+        match state.pending.get(&transaction) {
+            Some((addr, _)) if *addr == from => {}
+            _ => return,
+        }
+        let Some((_, tx)) = state.pending.remove(&transaction) else {
+            return;
+        };
+
+        if let Some(id) = message.r.as_ref().and_then(|r| krpc::parse_id(&r.id)) {
+            state.table.insert(NodeInfo { id, addr: from });
+        }
+        let _ = tx.send(message);
+    }
+
+    async fn handle_query(&self, message: KrpcMessage, from: SocketAddrV4) -> KrpcMessage {
+        let transaction = message.t;
+        let (Some(method), Some(args)) = (message.q, message.a) else {
+            return KrpcMessage::error(transaction, ERROR_PROTOCOL, "Missing query");
+        };
+        let Some(sender_id) = krpc::parse_id(&args.id) else {
+            return KrpcMessage::error(transaction, ERROR_PROTOCOL, "Invalid id");
+        };
+
+        let mut state = self.state.lock().await;
+        state.table.insert(NodeInfo {
+            id: sender_id,
+            addr: from,
+        });
+
+        let mut values = ResponseValues {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Default::default()
+        };
+        match method.as_str() {
+            "ping" => {}
+            "find_node" => {
+                let Some(target) = args.target.as_ref().and_then(|bytes| krpc::parse_id(bytes))
+                else {
+                    return KrpcMessage::error(transaction, ERROR_PROTOCOL, "Invalid target");
+                };
+                let nodes = state.table.closest(&target, K);
+                values.nodes = Some(ByteBuf::from(krpc::encode_nodes(&nodes)));
+            }
+            "get_peers" => {
+                let Some(info_hash) = args
+                    .info_hash
+                    .as_ref()
+                    .and_then(|bytes| krpc::parse_id(bytes))
+                else {
+                    return KrpcMessage::error(transaction, ERROR_PROTOCOL, "Invalid info_hash");
+                };
+                let peers = state.peers.get(&info_hash);
+                if !peers.is_empty() {
+                    values.values = Some(
+                        peers
+                            .into_iter()
+                            .map(|peer| ByteBuf::from(krpc::encode_peer(peer).to_vec()))
+                            .collect(),
+                    );
+                }
+                let nodes = state.table.closest(&info_hash, K);
+                values.nodes = Some(ByteBuf::from(krpc::encode_nodes(&nodes)));
+                values.token = Some(ByteBuf::from(state.tokens.token(*from.ip())));
+            }
+            "announce_peer" => {
+                let Some(info_hash) = args
+                    .info_hash
+                    .as_ref()
+                    .and_then(|bytes| krpc::parse_id(bytes))
+                else {
+                    return KrpcMessage::error(transaction, ERROR_PROTOCOL, "Invalid info_hash");
+                };
+                let token = args
+                    .token
+                    .as_ref()
+                    .map(|token| token.as_slice())
+                    .unwrap_or_default();
+                if !state.tokens.validate(*from.ip(), token) {
+                    return KrpcMessage::error(transaction, ERROR_PROTOCOL, "Bad token");
+                }
+                let port = match (args.implied_port, args.port) {
+                    (Some(1), _) => from.port(),
+                    (_, Some(port)) => port,
+                    _ => return KrpcMessage::error(transaction, ERROR_PROTOCOL, "Missing port"),
+                };
+                state
+                    .peers
+                    .add(info_hash, SocketAddrV4::new(*from.ip(), port));
+            }
+            _ => return KrpcMessage::error(transaction, ERROR_METHOD_UNKNOWN, "Method Unknown"),
+        }
+
+        KrpcMessage::response(transaction, values)
+    }
+}
+
+async fn recv_loop(shared: Arc<Shared>) {
+    let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+    loop {
+        let (len, from) = match shared.socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        let SocketAddr::V4(from) = from else {
+            continue;
+        };
+        let Ok(message) = KrpcMessage::decode(&buf[..len]) else {
+            // Can't answer without a transaction id
+            continue;
+        };
+
+        match message.y.as_str() {
+            "q" => {
+                let reply = shared.handle_query(message, from).await;
+                let _ = shared.send(&reply, from).await;
+            }
+            "r" | "e" => shared.handle_reply(message, from).await,
+            _ => {
+                let reply = KrpcMessage::error(message.t, ERROR_GENERIC, "Unknown message type");
+                let _ = shared.send(&reply, from).await;
+            }
+        }
+    }
+}
+
+/// Resolves `host:port` bootstrap entries, keeping IPv4 addresses.
+pub async fn resolve_bootstrap_nodes(hosts: &[&str]) -> Vec<SocketAddrV4> {
+    let mut nodes = Vec::new();
+    for host in hosts {
+        if let Ok(addrs) = tokio::net::lookup_host(host).await {
+            nodes.extend(addrs.filter_map(|addr| match addr {
+                SocketAddr::V4(addr) => Some(addr),
+                SocketAddr::V6(_) => None,
+            }));
+        }
+    }
+    nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id_byte: u8, port: u16) -> NodeInfo {
+        let mut id = [0u8; 20];
+        id[0] = id_byte;
+        NodeInfo {
+            id,
+            addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, port),
+        }
+    }
+
+    async fn localhost_node() -> Dht {
+        Dht::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_routing_table_buckets_and_closest() {
+        let mut table = RoutingTable::new([0; 20]);
+        // Ids 0x80..0x88 share no prefix with ours, so all land in bucket 0
+        for i in 0..K as u8 {
+            assert!(table.insert(node(0x80 + i, 1000 + i as u16)));
+        }
+        assert!(!table.insert(node(0x90, 2000)));
+
+        // An unresponsive node makes room
+        table.mark_failed(node(0x80, 1000).addr);
+        table.mark_failed(node(0x80, 1000).addr);
+        assert!(table.insert(node(0x90, 2000)));
+        assert_eq!(table.len(), K);
+
+        assert!(table.insert(node(0x01, 3000)));
+        let mut target = [0u8; 20];
+        target[0] = 0x03;
+        let closest: Vec<u8> = table
+            .closest(&target, 3)
+            .iter()
+            .map(|node| node.id[0])
+            .collect();
+        assert_eq!(closest, vec![0x01, 0x83, 0x82]);
+    }
+
+    #[tokio::test]
+    async fn test_swarm_announce_and_get_peers() {
+        let mut swarm = Vec::new();
+        for _ in 0..8 {
+            swarm.push(localhost_node().await);
+        }
+        let entry = swarm[0].local_addr().unwrap();
+        // A second round lets early nodes learn about later ones
+        for _ in 0..2 {
+            for dht in &swarm[1..] {
+                dht.bootstrap(&[entry]).await;
+            }
+        }
+        assert!(swarm
+            .iter()
+            .all(|dht| !dht.shared.state.try_lock().unwrap().table.is_empty()));
+
+        let info_hash = [0x42; 20];
+        swarm[3].announce(info_hash, 7000).await.unwrap();
+
+        let peers = swarm[6].get_peers(info_hash).await.unwrap();
+        assert_eq!(peers, vec!["127.0.0.1:7000".parse().unwrap()]);
+
+        let seen = swarm[0]
+            .shared
+            .state
+            .lock()
+            .await
+            .table
+            .last_seen(&swarm[6].id());
+        assert!(seen.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_announce_requires_valid_token() {
+        let server = localhost_node().await;
+        let client = localhost_node().await;
+        let server_addr = server.local_addr().unwrap();
+        assert_eq!(client.ping(server_addr).await.unwrap(), server.id());
+
+        let announce = |token: Vec<u8>| QueryArgs {
+            info_hash: Some(ByteBuf::from(vec![1; 20])),
+            port: Some(6881),
+            token: Some(ByteBuf::from(token)),
+            ..Default::default()
+        };
+        let result = client
+            .shared
+            .query(server_addr, "announce_peer", announce(b"bogus".to_vec()))
+            .await;
+        assert!(matches!(result, Err(BitTorrentError::Dht(message)) if message.contains("203")));
+
+        let args = QueryArgs {
+            info_hash: Some(ByteBuf::from(vec![1; 20])),
+            ..Default::default()
+        };
+        let response = client
+            .shared
+            .query(server_addr, "get_peers", args)
+            .await
+            .unwrap();
+        let token = response.token.unwrap().into_vec();
+        client
+            .shared
+            .query(server_addr, "announce_peer", announce(token))
+            .await
+            .unwrap();
+        assert_eq!(
+            server.shared.state.lock().await.peers.get(&[1; 20]),
+            vec!["127.0.0.1:6881".parse().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_node_cache_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dht_nodes.json");
+
+        let a = localhost_node().await;
+        let b = localhost_node().await;
+        b.ping(a.local_addr().unwrap()).await.unwrap();
+        b.save_nodes(&path).await.unwrap();
+
+        let loaded = Dht::load_nodes(&path).await;
+        assert_eq!(
+            loaded,
+            vec![NodeInfo {
+                id: a.id(),
+                addr: a.local_addr().unwrap()
+            }]
+        );
+        assert!(Dht::load_nodes(dir.path().join("missing")).await.is_empty());
+    }
+}
@@ -1,22 +1,69 @@
 use crate::{
+    choker::{Choker, RECHOKE_INTERVAL},
+    dht::Dht,
     error::{BitTorrentError, Result},
-    peer::Peer,
-    piece::PieceManager,
-    torrent::Torrent,
-    tracker::Tracker,
-    utils::{bit_set, generate_peer_id},
+    message::{BlockRequest, HashRequest},
+    peer::{
+        BlockResponse, ConnectOptions, ExtensionHandler, ExtensionRegistry, HashResponse, Peer,
+    },
+    pex::{PexMessage, PexPeer, PexState, FLAG_REACHABLE, FLAG_SEED, UT_PEX},
+    piece::{PieceInfo, PieceManager},
+    rate_limit::{RateLimits, TorrentLimits},
+    resume::ResumeState,
+    seed::{self, SeedContext},
+    stats::TransferStats,
+    storage::Storage,
+    swarm::{PeerPool, PeerSource},
+    torrent::{MetaVersion, Torrent},
+    tracker::{Tracker, TrackerHandle},
+    utils::generate_peer_id,
     MAX_PEERS,
 };
 use futures_util::{stream::FuturesUnordered, StreamExt};
-use std::{collections::HashSet, sync::Arc};
-use tokio::sync::{mpsc, Mutex};
-use tokio::time::{timeout, Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    net::SocketAddrV4,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::sync::{broadcast, mpsc, watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, timeout, Duration, Instant};
+
+// Persist fast-resume state after this many newly completed pieces
+const RESUME_SAVE_INTERVAL: usize = 16;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// Connection attempts in flight at once
+const MAX_HALF_OPEN: usize = 50;
+// Peers are banned after sending this many pieces that fail the hash check
+const MAX_BAD_PIECES: usize = 3;
+// BEP 5 asks for re-announcing to the DHT at least this often
+const DHT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+// How long to wait for piece hashes when there is nothing to download
+// until they arrive
+const HASH_TIMEOUT: Duration = Duration::from_secs(30);
+// How long a connection with nothing to download waits for the peer before
+// passing on our new pieces and the choker's decisions
+const IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// An outbound connection as seen by the upload side: its choker entry, and
+// what the choker and the peer were last told. `have_rx` must date from
+// before our bitfield was sent.
+struct UploadState {
+    choker_id: usize,
+    choke_rx: watch::Receiver<bool>,
+    have_rx: broadcast::Receiver<usize>,
+    interested: bool,
+}
 
 #[derive(Debug)]
 enum TaskMessage {
+    // `corrupters` sent blocks that differ from this, verified, copy
     PieceCompleted {
         index: usize,
         data: Vec<u8>,
+        corrupters: Vec<usize>,
     },
     PieceFailed {
         index: usize,
@@ -24,284 +71,996 @@ enum TaskMessage {
     },
     PeerDisconnected {
         peer_id: usize,
+        misbehaved: bool,
     },
 }
 
+// Runs `future` unless `stop` is raised first. Dropping `stop`'s sender
+// doesn't count.
+async fn unless_stopped<T>(
+    stop: &mut watch::Receiver<bool>,
+    future: impl Future<Output = T>,
+) -> Option<T> {
+    tokio::select! {
+        output = future => Some(output),
+        Ok(_) = stop.wait_for(|&stop| stop) => None,
+    }
+}
+
 pub struct Download {
     torrent: Torrent,
-    peers: Vec<Peer>,
-    piece_manager: PieceManager,
-    downloaded: Vec<u8>,
+    // Addresses to connect to, kept topped up while the download runs
+    pool: PeerPool,
+    // Listen addresses of peers that connected to us
+    inbound_tx: mpsc::Sender<SocketAddrV4>,
+    inbound_rx: mpsc::Receiver<SocketAddrV4>,
+    // Peers we were told about over PEX, on any connection
+    pex_tx: mpsc::Sender<PexMessage>,
+    pex_rx: mpsc::Receiver<PexMessage>,
+    piece_manager: Arc<Mutex<PieceManager>>,
+    storage: Arc<Mutex<Storage>>,
+    output_path: PathBuf,
+    // Announces newly verified pieces to connections we are seeding to
+    have_tx: broadcast::Sender<usize>,
+    // Our id on the wire, shared by every connection and announce
+    peer_id: [u8; 20],
+    // Port we accept peer connections on, for announces
+    port: u16,
+    stats: Arc<TransferStats>,
+    // Moved into a background task once the download starts
+    tracker: Option<Tracker>,
+    tracker_handle: Option<TrackerHandle>,
+    dht: Option<Arc<Dht>>,
+    dht_task: Option<JoinHandle<()>>,
+    // Shared by every connection we upload on, outbound and inbound
+    choker: Arc<Mutex<Choker>>,
+    choker_task: Option<JoinHandle<()>>,
+    // Bandwidth limits for every connection of this torrent
+    limits: TorrentLimits,
+    // BEP 10 extensions offered on every connection of this torrent
+    extensions: Arc<ExtensionRegistry>,
 }
 
 impl Download {
-    pub async fn new(torrent: Torrent) -> Result<Self> {
+    /// Prepares the download and finds the first peers to connect to,
+    /// through the torrent's trackers and, when given, the DHT.
+    pub async fn new(
+        torrent: Torrent,
+        download_dir: impl AsRef<Path>,
+        port: u16,
+        dht: Option<Arc<Dht>>,
+    ) -> Result<Self> {
         println!("Initializing download for: {}", torrent.info.name);
-        let tracker = Tracker::new(&torrent)?;
-        println!("Connecting to tracker at: {}", torrent.announce);
-        let peer_list = tracker.get_peers().await?;
-        println!("Found {} potential peers", peer_list.len());
+        // Private torrents get their peers from the trackers alone
+        let dht = dht.filter(|_| !torrent.info.is_private());
 
-        let info_hash = torrent.info_hash();
-        let mut peers = Vec::new();
-        let mut failed_attempts = 0;
-        const MAX_FAILED_ATTEMPTS: usize = 200;
-        const CONCURRENT_CONNECTS: usize = 50;
-        const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
-
-        // Create a pool of connection futures
-        let mut connection_pool = FuturesUnordered::new();
-
-        // Initialize with first batch of connection attempts
-        for addr in peer_list.iter().take(CONCURRENT_CONNECTS) {
-            connection_pool.push(timeout(
-                CONNECT_TIMEOUT,
-                Peer::connect(*addr, info_hash, generate_peer_id()),
-            ));
-        }
+        // Initialize piece manager and preallocate the output files
+        let mut piece_manager = Self::piece_manager(&torrent);
 
-        let mut peer_index = CONCURRENT_CONNECTS;
+        let mut storage = Storage::create(&torrent, download_dir).await?;
+        Self::resume(&torrent, &mut piece_manager, &mut storage).await?;
 
-        while let Some(result) = connection_pool.next().await {
-            match result {
-                Ok(Ok(peer)) => {
-                    println!("Successfully connected to peer: {}", peer.addr());
-                    peers.push(peer);
+        let peer_id = generate_peer_id();
+        let stats = Arc::new(TransferStats::new(Self::bytes_left(
+            &torrent,
+            &piece_manager,
+        )));
 
-                    if peers.len() >= MAX_PEERS {
-                        break;
+        if piece_manager.is_complete() {
+            println!("All pieces already present on disk");
+            // Still announce so other peers can find us as a seed
+            let tracker = Tracker::new(&torrent, port, peer_id, Arc::clone(&stats)).ok();
+            return Ok(Self {
+                tracker,
+                dht,
+                ..Self::assemble(
+                    torrent,
+                    PeerPool::new(),
+                    piece_manager,
+                    storage,
+                    port,
+                    peer_id,
+                    stats,
+                )
+            });
+        }
+
+        // Either source alone is enough to find peers
+        let mut last_error = None;
+        let mut tracker = match Tracker::new(&torrent, port, peer_id, Arc::clone(&stats)) {
+            Ok(tracker) => Some(tracker),
+            Err(e) => {
+                println!("No usable trackers: {}", e);
+                last_error = Some(e);
+                None
+            }
+        };
+
+        let mut pool = PeerPool::new();
+        if let Some(tracker) = &mut tracker {
+            println!("Contacting {} tracker tier(s)", torrent.trackers().len());
+            match tracker.get_peers().await {
+                Ok(peers) => {
+                    for addr in peers {
+                        pool.add(addr, PeerSource::Tracker);
                     }
                 }
-                _ => {
-                    failed_attempts += 1;
-                    if failed_attempts >= MAX_FAILED_ATTEMPTS {
-                        println!("Reached maximum failed connection attempts");
-                        break;
+                Err(e) => {
+                    println!("Tracker announce failed: {}", e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        if let Some(dht) = &dht {
+            match dht.get_peers(torrent.info_hash()).await {
+                Ok(peers) => {
+                    println!("DHT returned {} peers", peers.len());
+                    for addr in peers {
+                        pool.add(addr, PeerSource::Dht);
                     }
                 }
+                Err(e) => {
+                    println!("DHT lookup failed: {}", e);
+                    last_error = Some(e);
+                }
             }
+        }
+
+        if pool.is_empty() {
+            return Err(
+                last_error.unwrap_or_else(|| BitTorrentError::Peer("No peers available".into()))
+            );
+        }
+        println!("Found {} potential peers", pool.len());
+
+        Ok(Self {
+            tracker,
+            dht,
+            ..Self::assemble(torrent, pool, piece_manager, storage, port, peer_id, stats)
+        })
+    }
 
-            // Add new connection attempt if we have more peers to try
-            if peer_index < peer_list.len() {
-                connection_pool.push(timeout(
-                    CONNECT_TIMEOUT,
-                    Peer::connect(peer_list[peer_index], info_hash, generate_peer_id()),
-                ));
-                peer_index += 1;
+    fn assemble(
+        torrent: Torrent,
+        pool: PeerPool,
+        piece_manager: PieceManager,
+        storage: Storage,
+        port: u16,
+        peer_id: [u8; 20],
+        stats: Arc<TransferStats>,
+    ) -> Self {
+        let (have_tx, _) = broadcast::channel(256);
+        let (inbound_tx, inbound_rx) = mpsc::channel(MAX_PEERS);
+        let (pex_tx, pex_rx) = mpsc::channel(MAX_PEERS);
+        let piece_manager = Arc::new(Mutex::new(piece_manager));
+        let choker = Arc::new(Mutex::new(Choker::default()));
+        // Runs for as long as the torrent is loaded, seeding included
+        let choker_task = Self::spawn_choker(Arc::clone(&choker), Arc::clone(&piece_manager));
+        let mut extensions = ExtensionRegistry::new()
+            .with_listen_port(port)
+            .with_metadata_size(torrent.info_bytes().len());
+        if torrent.info.is_private() {
+            extensions.unregister(UT_PEX);
+        }
+        Self {
+            torrent,
+            pool,
+            inbound_tx,
+            inbound_rx,
+            pex_tx,
+            pex_rx,
+            output_path: storage.path().to_path_buf(),
+            piece_manager,
+            storage: Arc::new(Mutex::new(storage)),
+            have_tx,
+            peer_id,
+            port,
+            stats,
+            tracker: None,
+            tracker_handle: None,
+            dht: None,
+            dht_task: None,
+            choker,
+            choker_task: Some(choker_task),
+            limits: TorrentLimits::default(),
+            extensions: Arc::new(extensions),
+        }
+    }
+
+    // Pieces with the hashes to check them against: SHA-1 for v1, the
+    // merkle trees of the files for v2, and both for hybrid torrents
+    fn piece_manager(torrent: &Torrent) -> PieceManager {
+        let info = &torrent.info;
+        let mut piece_manager = match info.version() {
+            MetaVersion::V2 => {
+                let pieces = info
+                    .v2_files()
+                    .iter()
+                    .flat_map(|file| {
+                        (0..file.length)
+                            .step_by(info.piece_length)
+                            .map(|offset| info.piece_length.min(file.length - offset))
+                    })
+                    .enumerate()
+                    .map(|(index, length)| PieceInfo::v2(index, length))
+                    .collect();
+                PieceManager::from_pieces(info.piece_length, pieces)
             }
+            _ => PieceManager::new(
+                info.piece_length,
+                info.pieces.0.clone(),
+                torrent.total_length(),
+            ),
+        };
 
-            // Break if we have no more pending connections and enough peers
-            if connection_pool.is_empty() || peers.len() >= MAX_PEERS {
-                break;
+        // Every v2 file starts on a piece boundary, in hybrid torrents too
+        let mut first_piece = 0;
+        for file in info.v2_files() {
+            if let Some(root) = file.pieces_root {
+                let layer = torrent.piece_layer(&root);
+                let has_layer = layer.is_some();
+                let added = piece_manager.add_merkle_file(root, first_piece, file.length, layer);
+                if has_layer && !added {
+                    println!("Ignoring invalid piece layer for {}", file.path.join("/"));
+                }
             }
+            first_piece += file.length.div_ceil(info.piece_length);
         }
+        piece_manager
+    }
+
+    fn bytes_left(torrent: &Torrent, piece_manager: &PieceManager) -> u64 {
+        let have: usize = piece_manager
+            .completed_pieces()
+            .into_iter()
+            .filter_map(|index| piece_manager.get_piece(index))
+            .map(|piece| piece.length())
+            .sum();
+        torrent.total_length().saturating_sub(have) as u64
+    }
 
-        if peers.is_empty() {
-            return Err(BitTorrentError::Peer(
-                "Failed to connect to any peers".into(),
-            ));
+    /// Handles needed to serve this torrent's verified pieces to other peers.
+    pub fn seed_context(&self) -> SeedContext {
+        SeedContext {
+            info_hash: self.torrent.info_hash(),
+            hybrid_info_hash: self.torrent.info_hashes().get(1).copied(),
+            piece_manager: Arc::clone(&self.piece_manager),
+            storage: Arc::clone(&self.storage),
+            have_tx: self.have_tx.clone(),
+            stats: Arc::clone(&self.stats),
+            peer_tx: Some(self.inbound_tx.clone()),
+            pex_tx: Some(self.pex_tx.clone()),
+            choker: Arc::clone(&self.choker),
+            limits: self.limits.clone(),
+            extensions: Arc::clone(&self.extensions),
+            metadata: Some(Arc::new(self.torrent.info_bytes())),
+            banned: self.pool.ban_list(),
         }
+    }
 
-        println!("Successfully connected to {} peers", peers.len());
+    /// Offers extension `name` to this torrent's peers, passing its
+    /// messages to `handler`. Returns the id it is received on, or `None`
+    /// if every id is taken. Only connections made afterwards offer it, so
+    /// register before calling `seed_context` and `download_all`.
+    pub fn register_extension(
+        &mut self,
+        name: &str,
+        handler: Arc<dyn ExtensionHandler>,
+    ) -> Option<u8> {
+        Arc::make_mut(&mut self.extensions).register(name, handler)
+    }
 
-        // Initialize piece manager and download buffer
-        let piece_manager = PieceManager::new(
-            torrent.info.piece_length,
-            torrent.info.pieces.0.clone(),
-            torrent.total_length(),
-        );
+    /// Makes this torrent's connections share `global` with other torrents.
+    /// Call before `seed_context` and `download_all`.
+    pub fn set_global_rate_limits(&mut self, global: RateLimits) {
+        self.limits.global = global;
+    }
 
-        let downloaded = vec![0; torrent.total_length()];
+    /// Handle on the limits for this torrent as a whole.
+    pub fn rate_limits(&self) -> RateLimits {
+        self.limits.torrent.clone()
+    }
 
-        Ok(Self {
-            torrent,
-            peers,
-            piece_manager,
-            downloaded,
-        })
+    /// Handle on the limits each of this torrent's connections gets on its
+    /// own.
+    pub fn peer_rate_limits(&self) -> RateLimits {
+        self.limits.peer.clone()
+    }
+
+    /// Sets how many peers we upload to at once, the optimistic unchoke
+    /// included. Takes effect at the next rechoke.
+    pub async fn set_upload_slots(&self, slots: usize) {
+        self.choker.lock().await.set_upload_slots(slots);
     }
 
-    async fn start_download_tasks(
-        peers: Vec<Peer>,
+    // Rechokes every `RECHOKE_INTERVAL`, ranking peers by upload once we
+    // have every piece.
+    fn spawn_choker(
+        choker: Arc<Mutex<Choker>>,
         piece_manager: Arc<Mutex<PieceManager>>,
-        task_concurrency: usize,
-    ) -> mpsc::Receiver<TaskMessage> {
-        let (tx, rx) = mpsc::channel(task_concurrency * 2);
-        let piece_manager_clone = Arc::clone(&piece_manager);
-
-        for (peer_id, mut peer) in peers.into_iter().enumerate() {
-            let tx = tx.clone();
-            let piece_manager = Arc::clone(&piece_manager);
-
-            tokio::spawn(async move {
-                let mut consecutive_failures = 0;
-                const MAX_CONSECUTIVE_FAILURES: usize = 3;
-                let mut failed_pieces = HashSet::new();
-
-                while consecutive_failures < MAX_CONSECUTIVE_FAILURES {
-                    // Get next piece under lock
-                    let piece = {
-                        let mut pm = piece_manager.lock().await;
-                        // Don't request pieces that have failed for this peer
-                        pm.next_piece_excluding(peer_id, &failed_pieces)
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RECHOKE_INTERVAL);
+            loop {
+                interval.tick().await;
+                let seeding = piece_manager.lock().await.is_complete();
+                choker.lock().await.rechoke(seeding);
+            }
+        })
+    }
+
+    // Marks pieces already on disk as completed. A saved resume state is
+    // trusted if the files haven't changed since it was written, otherwise
+    // every piece is re-hashed.
+    async fn resume(
+        torrent: &Torrent,
+        piece_manager: &mut PieceManager,
+        storage: &mut Storage,
+    ) -> Result<()> {
+        if !storage.had_existing_data() {
+            return Ok(());
+        }
+
+        let info_hash = torrent.info_hash();
+        let num_pieces = piece_manager.num_pieces();
+        let stamps = storage.file_stamps().await?;
+
+        if let Some(state) = ResumeState::load(storage.resume_path()).await {
+            if let Some(completed) = state.validate(info_hash, num_pieces, &stamps) {
+                println!("Fast resume: {} pieces already downloaded", completed.len());
+                for &index in completed {
+                    piece_manager.mark_completed(index);
+                }
+                return Ok(());
+            }
+        }
+
+        println!("Checking existing data for {} pieces...", num_pieces);
+        for index in 0..num_pieces {
+            let length = match piece_manager.get_piece(index) {
+                Some(piece) => piece.length(),
+                None => continue,
+            };
+            let data = storage.read_piece(index, length).await?;
+            if piece_manager.verify_piece(index, &data) {
+                piece_manager.mark_completed(index);
+            }
+        }
+        println!(
+            "Recheck complete: {}/{} pieces valid",
+            piece_manager.completed_count(),
+            num_pieces
+        );
+
+        Self::save_resume_state(torrent, piece_manager, storage).await
+    }
+
+    async fn save_resume_state(
+        torrent: &Torrent,
+        piece_manager: &PieceManager,
+        storage: &mut Storage,
+    ) -> Result<()> {
+        storage.flush().await?;
+        let state = ResumeState::new(
+            torrent.info_hash(),
+            piece_manager.num_pieces(),
+            piece_manager.completed_pieces(),
+            storage.file_stamps().await?,
+        );
+        state.save(storage.resume_path()).await
+    }
+
+    // Downloads from the peer, and serves its requests on the way like an
+    // inbound connection, unchoked whenever the choker says so. Raising
+    // `stop` ends the task at its next wait for the peer; either way it
+    // unregisters from the choker and reports `PeerDisconnected`.
+    fn spawn_peer_task(
+        peer_id: usize,
+        mut peer: Peer,
+        context: SeedContext,
+        mut upload: UploadState,
+        tx: mpsc::Sender<TaskMessage>,
+        connected: watch::Receiver<Vec<PexPeer>>,
+        mut stop: watch::Receiver<bool>,
+    ) {
+        tokio::spawn(async move {
+            let piece_manager = Arc::clone(&context.piece_manager);
+            let choker = Arc::clone(&context.choker);
+            let mut consecutive_failures = 0;
+            const MAX_CONSECUTIVE_FAILURES: usize = 3;
+            // Pieces not to ask this peer for again: ones it sent bad or rejected
+            let mut excluded_pieces = HashSet::new();
+            let mut bad_pieces = 0;
+            let mut pex = PexState::new();
+            // Hash requests sent to this peer, and those not answered yet
+            let mut asked_hashes = HashSet::new();
+            let mut pending_hashes = HashSet::new();
+
+            // A broken connection shows up again at the first step below
+            let _ = seed::send_allowed_fast(&mut peer, &context).await;
+
+            while consecutive_failures < MAX_CONSECUTIVE_FAILURES && bad_pieces < MAX_BAD_PIECES {
+                if *stop.borrow() {
+                    break;
+                }
+                if let Err(e) = Self::serve_uploads(&mut peer, &context, &mut upload).await {
+                    println!("Peer {} failed to serve requests: {}", peer_id, e);
+                    break;
+                }
+
+                if let Err(e) = Self::exchange_pex(&mut peer, &mut pex, &connected).await {
+                    if e.is_connection_error() {
+                        break;
+                    }
+                    println!("Peer {} PEX failed: {}", peer_id, e);
+                }
+
+                let interesting = !pending_hashes.is_empty()
+                    || piece_manager
+                        .lock()
+                        .await
+                        .is_interesting(peer_id, &excluded_pieces);
+                // Blocks are requested once the peer unchokes us, and before
+                // that from its allowed fast pieces (BEP 6); meanwhile the
+                // connection idles below
+                let updated = if interesting {
+                    let exchanged = match peer.set_interested(true).await {
+                        Ok(()) => {
+                            Self::exchange_hashes(
+                                &mut peer,
+                                &piece_manager,
+                                &mut asked_hashes,
+                                &mut pending_hashes,
+                            )
+                            .await
+                        }
+                        Err(e) => Err(e),
                     };
+                    match exchanged {
+                        Ok(()) => {
+                            Self::update_requests(
+                                &mut peer,
+                                peer_id,
+                                &piece_manager,
+                                &excluded_pieces,
+                            )
+                            .await
+                        }
+                        Err(e) => Err(e),
+                    }
+                } else {
+                    peer.set_interested(false).await
+                };
+                if let Err(e) = updated {
+                    println!("Peer {} failed to send requests: {}", peer_id, e);
+                    break;
+                }
 
-                    match piece {
-                        Some(piece) => {
-                            println!("Peer {} requesting piece {}", peer_id, piece.index());
-                            match peer.request_piece(&piece).await {
-                                Ok(data) => {
-                                    let verified = {
-                                        let pm = piece_manager.lock().await;
-                                        pm.verify_piece(piece.index(), &data)
-                                    };
-
-                                    if verified {
-                                        consecutive_failures = 0;
-                                        let msg = TaskMessage::PieceCompleted {
-                                            index: piece.index(),
-                                            data,
-                                        };
-                                        if tx.send(msg).await.is_err() {
-                                            break;
-                                        }
-                                    } else {
-                                        consecutive_failures += 1;
-                                        failed_pieces.insert(piece.index());
-                                        let msg = TaskMessage::PieceFailed {
-                                            index: piece.index(),
-                                            error: BitTorrentError::Piece(
-                                                "Verification failed".into(),
-                                            ),
-                                        };
-                                        if tx.send(msg).await.is_err() {
-                                            break;
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    consecutive_failures += 1;
-                                    failed_pieces.insert(piece.index());
-
-                                    if e.is_connection_error() {
-                                        let msg = TaskMessage::PeerDisconnected { peer_id };
-                                        let _ = tx.send(msg).await;
-                                        break;
-                                    } else {
-                                        println!(
-                                            "Peer {} failed to download piece {}: {:?}",
-                                            peer_id,
-                                            piece.index(),
-                                            e
-                                        );
-                                        let msg = TaskMessage::PieceFailed {
-                                            index: piece.index(),
-                                            error: e,
-                                        };
-                                        if tx.send(msg).await.is_err() {
-                                            break;
-                                        }
-                                    }
-                                }
-                            }
+                if peer.requested_blocks().is_empty() {
+                    // Pieces may become available once their hashes arrive
+                    let wait = if pending_hashes.is_empty() {
+                        IDLE_TIMEOUT
+                    } else {
+                        HASH_TIMEOUT
+                    };
+                    match unless_stopped(&mut stop, timeout(wait, peer.recv())).await {
+                        Some(Ok(Ok(Some(_)))) => continue,
+                        // Nothing to fetch right now: stay while either side
+                        // still wants the other's pieces
+                        Some(Err(_))
+                            if wait == IDLE_TIMEOUT && (interesting || peer.peer_interested()) =>
+                        {
+                            continue
                         }
-                        None => {
-                            // No more pieces available for this peer
+                        _ => break,
+                    }
+                }
+
+                let Some(response) = unless_stopped(&mut stop, peer.next_response()).await else {
+                    break;
+                };
+                let (request, block) = match response {
+                    Ok(BlockResponse::Block(request, block)) => (request, block),
+                    Ok(BlockResponse::Rejected(request)) => {
+                        // Free the block for other peers right away, and
+                        // stop asking this one for the piece
+                        piece_manager.lock().await.release_block(peer_id, &request);
+                        excluded_pieces.insert(request.index as usize);
+                        continue;
+                    }
+                    Err(e) => {
+                        // Let other peers fetch whatever this one still owes us
+                        piece_manager.lock().await.release_peer_blocks(peer_id);
+                        consecutive_failures += 1;
+                        if e.is_connection_error() {
                             break;
                         }
+                        println!("Peer {} failed to deliver blocks: {:?}", peer_id, e);
+                        for request in peer.requested_blocks().to_vec() {
+                            let _ = peer.cancel_block(request).await;
+                        }
+                        continue;
+                    }
+                };
+                consecutive_failures = 0;
+                choker
+                    .lock()
+                    .await
+                    .record_download(*peer.addr().ip(), block.len() as u64);
+
+                let assembled = piece_manager
+                    .lock()
+                    .await
+                    .add_block(peer_id, request, &block);
+                let Some(piece) = assembled else {
+                    continue;
+                };
+                let index = piece.index;
+
+                let (verified, corrupters) = {
+                    let mut pm = piece_manager.lock().await;
+                    if pm.verify_piece(index, &piece.data) {
+                        (true, pm.find_corrupters(&piece))
+                    } else {
+                        pm.record_failure(&piece);
+                        (false, Vec::new())
+                    }
+                };
+                let msg = if verified {
+                    TaskMessage::PieceCompleted {
+                        index,
+                        data: piece.data,
+                        corrupters,
+                    }
+                } else {
+                    // Only blame this peer when it sent every block
+                    if piece.contributors() == HashSet::from([peer_id]) {
+                        bad_pieces += 1;
+                        excluded_pieces.insert(index);
+                    }
+                    TaskMessage::PieceFailed {
+                        index,
+                        error: BitTorrentError::Piece("Verification failed".into()),
                     }
+                };
+                if tx.send(msg).await.is_err() {
+                    break;
                 }
+            }
 
-                // Clean up peer when done
-                let mut pm = piece_manager.lock().await;
-                pm.remove_peer(peer_id);
-                println!("Peer {} task completed", peer_id);
-            });
+            // Clean up peer when done
+            choker.lock().await.unregister(upload.choker_id);
+            piece_manager.lock().await.remove_peer(peer_id);
+            println!("Peer {} task completed", peer_id);
+            let misbehaved = bad_pieces >= MAX_BAD_PIECES;
+            let _ = tx
+                .send(TaskMessage::PeerDisconnected {
+                    peer_id,
+                    misbehaved,
+                })
+                .await;
+        });
+    }
+
+    // Tells the peer about pieces we completed and the choker's decisions
+    // since the last call, and the choker about the peer's interest. Then
+    // answers what the peer asked of us meanwhile and passes on its PEX
+    // peers.
+    async fn serve_uploads(
+        peer: &mut Peer,
+        context: &SeedContext,
+        upload: &mut UploadState,
+    ) -> Result<()> {
+        loop {
+            match upload.have_rx.try_recv() {
+                Ok(index) => peer.send_have(index).await?,
+                Err(broadcast::error::TryRecvError::Lagged(_)) => {}
+                Err(_) => break,
+            }
+        }
+
+        // Interest first: it can free up a slot right away
+        if peer.peer_interested() != upload.interested {
+            upload.interested = peer.peer_interested();
+            context
+                .choker
+                .lock()
+                .await
+                .set_interested(upload.choker_id, upload.interested);
+        }
+        if upload.choke_rx.has_changed().unwrap_or(false) {
+            let choking = *upload.choke_rx.borrow_and_update();
+            peer.set_choking(choking).await?;
         }
 
-        rx
+        seed::handle_extended_messages(peer, context).await?;
+        let sent = seed::answer_requests(peer, context).await?;
+        context
+            .choker
+            .lock()
+            .await
+            .record_upload(upload.choker_id, sent);
+        Ok(())
     }
 
-    pub async fn download_all(&mut self) -> Result<Vec<u8>> {
-        println!("\nStarting download of: {}", self.torrent.info.name);
-        println!("Total size: {} bytes", self.torrent.total_length());
-        println!("Number of pieces: {}", self.torrent.info.pieces.0.len());
+    // Cancels requests another peer has already answered, which only
+    // happens in endgame, then fills the pipeline with new block requests:
+    // only for allowed fast pieces while the peer chokes us.
+    async fn update_requests(
+        peer: &mut Peer,
+        peer_id: usize,
+        piece_manager: &Mutex<PieceManager>,
+        excluded_pieces: &HashSet<usize>,
+    ) -> Result<()> {
+        let suggested = peer.take_suggested();
+        let (stale, fresh) = {
+            let mut pm = piece_manager.lock().await;
+            for index in suggested {
+                pm.suggest_piece(peer_id, index);
+            }
+            let stale: Vec<BlockRequest> = peer
+                .requested_blocks()
+                .iter()
+                .filter(|request| pm.is_block_received(request))
+                .copied()
+                .collect();
+            let outstanding = peer.requested_blocks().len() - stale.len();
+            let fresh: Vec<BlockRequest> = (outstanding..peer.pipeline_depth())
+                .map_while(|_| {
+                    if peer.peer_choking() {
+                        pm.pick_allowed_fast_block(peer_id, excluded_pieces, peer.allowed_fast())
+                    } else {
+                        pm.pick_block(peer_id, excluded_pieces)
+                    }
+                })
+                .collect();
+            (stale, fresh)
+        };
 
-        let piece_manager = Arc::new(Mutex::new(std::mem::take(&mut self.piece_manager)));
+        for request in stale {
+            peer.cancel_block(request).await?;
+        }
+        for request in fresh {
+            peer.request_block(request).await?;
+        }
+        Ok(())
+    }
 
-        // Initialize piece manager with peers
-        {
+    // Stores the piece hashes the peer sent since the last call, then asks
+    // it for those of v2 files we still lack, each range once.
+    async fn exchange_hashes(
+        peer: &mut Peer,
+        piece_manager: &Mutex<PieceManager>,
+        asked: &mut HashSet<HashRequest>,
+        pending: &mut HashSet<HashRequest>,
+    ) -> Result<()> {
+        let responses = peer.take_hash_responses();
+        let missing = {
             let mut pm = piece_manager.lock().await;
-            for (peer_id, peer) in self.peers.iter().enumerate() {
-                pm.register_peer(peer_id);
-                if let Some(bitfield) = peer.get_bitfield() {
-                    for piece_index in 0..self.torrent.info.pieces.0.len() {
-                        if bit_set(bitfield, piece_index) {
-                            pm.add_peer_piece(peer_id, piece_index);
+            for response in responses {
+                match response {
+                    HashResponse::Hashes(request, hashes) => {
+                        if !pm.add_hashes(&request, &hashes) {
+                            println!("Peer {} sent hashes that don't verify", peer.addr());
                         }
+                        pending.remove(&request);
+                    }
+                    HashResponse::Rejected(request) => {
+                        pending.remove(&request);
                     }
                 }
             }
+            pm.missing_hashes()
+        };
+
+        if !peer.supports_v2() {
+            return Ok(());
+        }
+        for request in missing {
+            if asked.insert(request) {
+                pending.insert(request);
+                peer.send_hash_request(request).await?;
+            }
         }
+        Ok(())
+    }
 
-        let mut rx = Self::start_download_tasks(
-            std::mem::take(&mut self.peers),
-            Arc::clone(&piece_manager),
-            5,
-        )
-        .await;
+    // Sends the peer our changes to the swarm if `PexState` allows one now.
+    // What it sends us is handled by `serve_uploads`.
+    async fn exchange_pex(
+        peer: &mut Peer,
+        state: &mut PexState,
+        connected: &watch::Receiver<Vec<PexPeer>>,
+    ) -> Result<()> {
+        // Not offered for private torrents
+        if !peer.offers_extension(UT_PEX) || peer.extension_id(UT_PEX).is_none() {
+            return Ok(());
+        }
+        let update = state.update(&connected.borrow(), peer.addr());
+        match update {
+            Some(message) => peer.send_extended(UT_PEX, &message.encode()?).await,
+            None => Ok(()),
+        }
+    }
+
+    // How a connected peer is described to others over PEX. We only
+    // connect out, so every peer we have is reachable.
+    fn pex_peer(&self, peer: &Peer) -> PexPeer {
+        let num_pieces = self.torrent.info.num_pieces();
+        let mut flags = FLAG_REACHABLE;
+        if (0..num_pieces).all(|index| peer.has_piece(index)) {
+            flags |= FLAG_SEED;
+        }
+        PexPeer {
+            addr: peer.addr(),
+            flags,
+        }
+    }
+
+    async fn register_peer(&self, peer_id: usize, peer: &Peer) {
+        let mut pm = self.piece_manager.lock().await;
+        pm.register_peer(peer_id);
+        for piece_index in 0..self.torrent.info.num_pieces() {
+            if peer.has_piece(piece_index) {
+                pm.add_peer_piece(peer_id, piece_index);
+            }
+        }
+    }
 
-        let total_pieces = self.torrent.info.pieces.0.len();
-        let mut completed_pieces = 0;
-        let mut failed_pieces = Vec::new();
+    // Hands the tracker to a background task that re-announces on its
+    // interval and sends any peers it learns about to `peer_tx`.
+    fn start_tracker(&mut self, peer_tx: mpsc::Sender<Vec<SocketAddrV4>>) {
+        if let Some(tracker) = self.tracker.take() {
+            self.tracker_handle = Some(tracker.spawn(peer_tx));
+        }
+    }
+
+    // Announces to the DHT now and every `DHT_ANNOUNCE_INTERVAL` until
+    // shutdown, sending the peers found to `peer_tx`. Hybrid torrents are
+    // announced in their v2 swarm too, so its peers can connect to us, but
+    // we only dial peers that know the info-hash we connect with.
+    fn start_dht(&mut self, peer_tx: mpsc::Sender<Vec<SocketAddrV4>>) {
+        let Some(dht) = self.dht.clone() else {
+            return;
+        };
+        let info_hashes = self.torrent.info_hashes();
+        let port = self.port;
 
-        while let Some(message) = rx.recv().await {
-            match message {
-                TaskMessage::PieceCompleted { index, data } => {
-                    self.store_piece(index, data);
-                    {
+        self.dht_task = Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(DHT_ANNOUNCE_INTERVAL);
+            loop {
+                interval.tick().await;
+                for (i, &info_hash) in info_hashes.iter().enumerate() {
+                    match dht.announce(info_hash, port).await {
+                        Ok(peers) if i == 0 && !peers.is_empty() => {
+                            let _ = peer_tx.send(peers).await;
+                        }
+                        Ok(_) => {}
+                        Err(e) => println!("DHT announce failed: {}", e),
+                    }
+                }
+            }
+        }));
+    }
+
+    /// Tells the trackers we are leaving. Call before dropping the download.
+    pub async fn shutdown(&mut self) {
+        for task in [self.dht_task.take(), self.choker_task.take()]
+            .into_iter()
+            .flatten()
+        {
+            task.abort();
+        }
+        if let Some(handle) = self.tracker_handle.take() {
+            handle.stop().await;
+        }
+    }
+
+    pub async fn download_all(&mut self) -> Result<()> {
+        println!("\nStarting download of: {}", self.torrent.info.name);
+        println!("Total size: {} bytes", self.torrent.total_length());
+        println!("Number of pieces: {}", self.torrent.info.num_pieces());
+
+        let piece_manager = Arc::clone(&self.piece_manager);
+        let total_pieces = self.torrent.info.num_pieces();
+        let mut completed_pieces = piece_manager.lock().await.completed_count();
+        let was_complete = completed_pieces == total_pieces;
+
+        // Open while the tracker or DHT task may still send new peers
+        let (tracker_tx, mut tracker_rx) = mpsc::channel(4);
+        let (dht_tx, mut dht_rx) = mpsc::channel(4);
+        self.start_tracker(tracker_tx);
+        self.start_dht(dht_tx);
+        let mut tracker_open = true;
+        let mut dht_open = true;
+
+        let (tx, mut rx) = mpsc::channel(MAX_PEERS * 2);
+        // Peers we are connected to, as advertised to others over PEX
+        let (connected_tx, connected_rx) = watch::channel(Vec::new());
+        let mut connected: HashMap<usize, PexPeer> = HashMap::new();
+        // Raised to end a peer's task
+        let mut stops: HashMap<usize, watch::Sender<bool>> = HashMap::new();
+        // Every peer connected this session, to ban them once proven bad
+        let mut peer_addrs = HashMap::new();
+        let mut next_peer_id = 0;
+
+        let info_hash = self.torrent.info_hash();
+        let peer_id = self.peer_id;
+        let context = self.seed_context();
+        let mut connecting = FuturesUnordered::new();
+
+        while completed_pieces < total_pieces {
+            // Top up to the target peer count from the pool
+            while connected.len() + connecting.len() < MAX_PEERS && connecting.len() < MAX_HALF_OPEN
+            {
+                let Some(addr) = self.pool.next_to_dial() else {
+                    break;
+                };
+                // Subscribed before taking the bitfield, so no piece is missed
+                let have_rx = self.have_tx.subscribe();
+                let options = ConnectOptions {
+                    extensions: Arc::clone(&self.extensions),
+                    bitfield: Some(piece_manager.lock().await.bitfield()),
+                    num_pieces: Some(total_pieces),
+                };
+                connecting.push(async move {
+                    let connect = Peer::connect_with_options(addr, info_hash, peer_id, options);
+                    (addr, have_rx, timeout(CONNECT_TIMEOUT, connect).await)
+                });
+            }
+
+            let next_retry = self.pool.next_retry();
+            if connected.is_empty()
+                && connecting.is_empty()
+                && next_retry.is_none()
+                && !tracker_open
+                && !dht_open
+            {
+                println!("No peers left to download from");
+                break;
+            }
+
+            tokio::select! {
+                Some(message) = rx.recv() => match message {
+                    TaskMessage::PieceCompleted { index, data, corrupters } => {
+                        self.store_piece(index, &data).await?;
+                        self.stats.add_downloaded(data.len() as u64);
                         let mut pm = piece_manager.lock().await;
                         pm.mark_completed(index);
+                        completed_pieces += 1;
+                        println!(
+                            "Downloaded piece {}/{} ({:.1}%)",
+                            completed_pieces,
+                            total_pieces,
+                            (completed_pieces as f64 / total_pieces as f64) * 100.0
+                        );
+
+                        let _ = self.have_tx.send(index);
+
+                        if completed_pieces % RESUME_SAVE_INTERVAL == 0 {
+                            let mut storage = self.storage.lock().await;
+                            Self::save_resume_state(&self.torrent, &pm, &mut storage).await?;
+                        }
+
+                        // Only now that the piece is stored, as the peer that
+                        // completed it may be among them
+                        for peer_id in corrupters {
+                            let Some(&addr) = peer_addrs.get(&peer_id) else {
+                                continue;
+                            };
+                            println!("Banning {} for corrupting piece {}", addr, index);
+                            self.pool.ban(addr);
+                            if let Some(stop) = stops.get(&peer_id) {
+                                stop.send_replace(true);
+                            }
+                        }
                     }
-                    completed_pieces += 1;
-                    println!(
-                        "Downloaded piece {}/{} ({:.1}%)",
-                        completed_pieces,
-                        total_pieces,
-                        (completed_pieces as f64 / total_pieces as f64) * 100.0
-                    );
-
-                    if completed_pieces == total_pieces {
-                        break;
+                    TaskMessage::PieceFailed { index, error } => {
+                        // Other peers can still fetch it; the piece isn't completed
+                        println!("Failed to download piece {}: {:?}", index, error);
+                        let length = piece_manager
+                            .lock()
+                            .await
+                            .get_piece(index)
+                            .map_or(0, |piece| piece.length());
+                        self.stats.add_wasted(length as u64);
                     }
+                    TaskMessage::PeerDisconnected { peer_id, misbehaved } => {
+                        println!("Peer {} disconnected", peer_id);
+                        stops.remove(&peer_id);
+                        if let Some(peer) = connected.remove(&peer_id) {
+                            if misbehaved {
+                                println!("Banning {} for sending bad data", peer.addr);
+                                self.pool.ban(peer.addr);
+                            } else {
+                                self.pool.disconnected(peer.addr);
+                            }
+                        }
+                        connected_tx.send_replace(connected.values().copied().collect());
+                    }
+                },
+                addrs = tracker_rx.recv(), if tracker_open => match addrs {
+                    Some(addrs) => {
+                        for addr in addrs {
+                            self.pool.add(addr, PeerSource::Tracker);
+                        }
+                    }
+                    None => tracker_open = false,
+                },
+                addrs = dht_rx.recv(), if dht_open => match addrs {
+                    Some(addrs) => {
+                        for addr in addrs {
+                            self.pool.add(addr, PeerSource::Dht);
+                        }
+                    }
+                    None => dht_open = false,
+                },
+                Some(addr) = self.inbound_rx.recv() => {
+                    self.pool.add(addr, PeerSource::Inbound);
                 }
-                TaskMessage::PieceFailed { index, error } => {
-                    println!("Failed to download piece {}: {:?}", index, error);
-                    failed_pieces.push(index);
-                }
-                TaskMessage::PeerDisconnected { peer_id } => {
-                    println!("Peer {} disconnected", peer_id);
+                Some(PexMessage { added, dropped }) = self.pex_rx.recv() => {
+                    for peer in added {
+                        self.pool.add_pex(peer);
+                    }
+                    for addr in dropped {
+                        self.pool.remove_dropped(addr);
+                    }
                 }
+                Some((addr, have_rx, result)) = connecting.next() => match result {
+                    Ok(Ok(mut peer)) => {
+                        println!("Connected to peer: {}", peer.addr());
+                        peer.set_rate_limits(self.limits.for_peer());
+                        self.pool.connect_succeeded(addr);
+                        connected.insert(next_peer_id, self.pex_peer(&peer));
+                        connected_tx.send_replace(connected.values().copied().collect());
+                        peer_addrs.insert(next_peer_id, addr);
+                        self.register_peer(next_peer_id, &peer).await;
+                        let (choker_id, choke_rx) = self.choker.lock().await.register(addr);
+                        let upload = UploadState {
+                            choker_id,
+                            choke_rx,
+                            have_rx,
+                            interested: false,
+                        };
+                        let (stop_tx, stop_rx) = watch::channel(false);
+                        Self::spawn_peer_task(
+                            next_peer_id,
+                            peer,
+                            context.clone(),
+                            upload,
+                            tx.clone(),
+                            connected_rx.clone(),
+                            stop_rx,
+                        );
+                        stops.insert(next_peer_id, stop_tx);
+                        next_peer_id += 1;
+                    }
+                    _ => self.pool.connect_failed(addr),
+                },
+                // Wakes up to redial a candidate whose backoff has passed
+                _ = sleep_until(next_retry.unwrap_or_else(Instant::now)), if next_retry.is_some() => {}
             }
         }
 
-        // Handle endgame mode if needed
-        if !failed_pieces.is_empty() {
-            self.piece_manager = Arc::try_unwrap(piece_manager)
-                .expect("All tasks should be done")
-                .into_inner();
-
+        {
+            let pm = self.piece_manager.lock().await;
+            let mut storage = self.storage.lock().await;
+            Self::save_resume_state(&self.torrent, &pm, &mut storage).await?;
+        }
+        if self.stats.wasted() > 0 {
             println!(
-                "Entering endgame mode for {} remaining pieces",
-                failed_pieces.len()
+                "Discarded {} bytes that failed verification",
+                self.stats.wasted()
             );
-            self.handle_endgame(&failed_pieces).await?;
         }
 
-        let is_complete = failed_pieces.is_empty() && completed_pieces == total_pieces;
-
-        if is_complete {
-            Ok(self.downloaded.clone())
+        if completed_pieces == total_pieces {
+            if !was_complete {
+                if let Some(handle) = &self.tracker_handle {
+                    handle.completed();
+                }
+            }
+            Ok(())
         } else {
             Err(BitTorrentError::Download(
                 "Failed to download all pieces".into(),
@@ -309,56 +1068,252 @@ impl Download {
         }
     }
 
-    async fn handle_endgame(&mut self, failed_pieces: &[usize]) -> Result<()> {
-        for &piece_index in failed_pieces {
-            let piece_info = self
-                .piece_manager
-                .get_piece(piece_index)
-                .ok_or_else(|| BitTorrentError::Piece("Piece info not found".into()))?;
-
-            let mut success = false;
-            for peer in &mut self.peers {
-                if peer.has_piece(piece_index) {
-                    match peer.request_piece(&piece_info).await {
-                        Ok(data) => {
-                            if self.piece_manager.verify_piece(piece_index, &data) {
-                                self.store_piece(piece_index, data);
-                                self.piece_manager.mark_completed(piece_index);
-                                success = true;
-                                break;
-                            }
-                        }
-                        Err(e) => {
-                            println!(
-                                "Endgame: Failed to download piece {} from peer: {:?}",
-                                piece_index, e
-                            );
-                            continue;
-                        }
-                    }
+    async fn store_piece(&mut self, index: usize, data: &[u8]) -> Result<()> {
+        self.storage.lock().await.write_piece(index, data).await
+    }
+
+    pub async fn progress(&self) -> f64 {
+        self.piece_manager.lock().await.progress()
+    }
+
+    pub fn output_path(&self) -> &Path {
+        &self.output_path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Message, MessageId};
+    use crate::peer::PeerCodec;
+    use crate::BLOCK_SIZE;
+    use bytes::BufMut;
+    use futures_util::SinkExt;
+    use sha1::{Digest, Sha1};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::codec::Framed;
+
+    // A single-piece torrent of `data`, set up to download into `dir`
+    async fn single_piece_download(data: &[u8], dir: &Path, pool: PeerPool) -> Download {
+        let hash: [u8; 20] = Sha1::digest(data).into();
+        let mut info = format!(
+            "d6:lengthi{}e4:name1:a12:piece lengthi{}e6:pieces20:",
+            data.len(),
+            data.len()
+        )
+        .into_bytes();
+        info.extend_from_slice(&hash);
+        info.push(b'e');
+        let torrent = Torrent::from_metadata(&info, Vec::new()).unwrap();
+        let piece_manager = Download::piece_manager(&torrent);
+        let storage = Storage::create(&torrent, dir).await.unwrap();
+        let stats = Arc::new(TransferStats::new(data.len() as u64));
+        Download::assemble(torrent, pool, piece_manager, storage, 0, [3; 20], stats)
+    }
+
+    async fn local_listener() -> (TcpListener, SocketAddrV4) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = match listener.local_addr().unwrap() {
+            std::net::SocketAddr::V4(addr) => addr,
+            _ => unreachable!(),
+        };
+        (listener, addr)
+    }
+
+    // Accepts the next connection and echoes its handshake back
+    async fn accept_peer(listener: &TcpListener) -> Framed<TcpStream, PeerCodec> {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut handshake = [0u8; 68];
+        stream.read_exact(&mut handshake).await.unwrap();
+        handshake[48..].copy_from_slice(&[2; 20]);
+        stream.write_all(&handshake).await.unwrap();
+        Framed::new(stream, PeerCodec::new())
+    }
+
+    // A seed on localhost that sends `greeting` after its bitfield, then
+    // answers every request from `data`
+    async fn seeder(data: Vec<u8>, greeting: Message) -> SocketAddrV4 {
+        let (listener, addr) = local_listener().await;
+
+        tokio::spawn(async move {
+            let mut framed = accept_peer(&listener).await;
+            for message in [Message::new(MessageId::Bitfield, vec![0x80]), greeting] {
+                framed.send(message).await.unwrap();
+            }
+            while let Some(Ok(msg)) = framed.next().await {
+                if msg.id != MessageId::Request {
+                    continue;
+                }
+                let request = BlockRequest::from_payload(&msg.payload).unwrap();
+                let begin = request.begin as usize;
+                let mut block = Vec::new();
+                block.put_u32(request.index);
+                block.put_u32(request.begin);
+                block.extend_from_slice(&data[begin..begin + request.length as usize]);
+                if framed
+                    .send(Message::new(MessageId::Piece, block))
+                    .await
+                    .is_err()
+                {
+                    break;
                 }
             }
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_piece_kept_when_its_sender_is_banned_as_corrupter() {
+        let data: Vec<u8> = (0..BLOCK_SIZE * 2).map(|i| (i % 251) as u8).collect();
+        let addr = seeder(data.clone(), Message::new(MessageId::Unchoke, Vec::new())).await;
+        let mut pool = PeerPool::new();
+        pool.add(addr, PeerSource::Tracker);
+        let dir = tempfile::tempdir().unwrap();
+        let mut download = single_piece_download(&data, dir.path(), pool).await;
 
-            if !success {
-                return Err(BitTorrentError::Piece(format!(
-                    "Failed to download piece {} in endgame mode",
-                    piece_index
-                )));
+        // An earlier copy that failed: a bad block from peer 0, the one
+        // about to connect, and a good one from a peer since gone
+        {
+            let mut pm = download.piece_manager.lock().await;
+            let none = HashSet::new();
+            for peer_id in [0, 99] {
+                pm.register_peer(peer_id);
+                pm.add_peer_piece(peer_id, 0);
             }
+            let bad = pm.pick_block(0, &none).unwrap();
+            let good = pm.pick_block(99, &none).unwrap();
+            assert!(pm
+                .add_block(0, bad, &vec![0xff; bad.length as usize])
+                .is_none());
+            let begin = good.begin as usize;
+            let piece = pm
+                .add_block(99, good, &data[begin..begin + good.length as usize])
+                .unwrap();
+            pm.record_failure(&piece);
+            pm.remove_peer(0);
+            pm.remove_peer(99);
         }
 
-        Ok(())
+        // Peer 0 completes the piece itself, and is banned for the bad block
+        timeout(Duration::from_secs(10), download.download_all())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(download.pool.is_banned(addr));
+        let stored = tokio::fs::read(dir.path().join("a")).await.unwrap();
+        assert_eq!(stored, data);
     }
 
-    fn store_piece(&mut self, index: usize, data: Vec<u8>) {
-        let start = index * self.torrent.info.piece_length;
-        if start + data.len() > self.downloaded.len() {
-            self.downloaded.resize(start + data.len(), 0);
-        }
-        self.downloaded[start..start + data.len()].copy_from_slice(&data);
+    #[tokio::test]
+    async fn test_allowed_fast_piece_fetched_while_choked() {
+        let data: Vec<u8> = (0..BLOCK_SIZE * 2).map(|i| (i % 251) as u8).collect();
+        // Never unchokes us
+        let allowed_fast = Message::new(MessageId::AllowedFast, 0u32.to_be_bytes().to_vec());
+        let addr = seeder(data.clone(), allowed_fast).await;
+        let mut pool = PeerPool::new();
+        pool.add(addr, PeerSource::Tracker);
+        let dir = tempfile::tempdir().unwrap();
+        let mut download = single_piece_download(&data, dir.path(), pool).await;
+
+        timeout(Duration::from_secs(10), download.download_all())
+            .await
+            .unwrap()
+            .unwrap();
+        let stored = tokio::fs::read(dir.path().join("a")).await.unwrap();
+        assert_eq!(stored, data);
     }
 
-    pub fn progress(&self) -> f64 {
-        self.piece_manager.progress()
+    #[tokio::test]
+    async fn test_seeds_to_interested_peer_until_it_loses_interest() {
+        let data = b"seedme!!".to_vec();
+        let dir = tempfile::tempdir().unwrap();
+        let download = single_piece_download(&data, dir.path(), PeerPool::new()).await;
+        download
+            .storage
+            .lock()
+            .await
+            .write_piece(0, &data)
+            .await
+            .unwrap();
+        download.piece_manager.lock().await.mark_completed(0);
+
+        let (listener, addr) = local_listener().await;
+        let leecher = tokio::spawn(async move {
+            let mut framed = accept_peer(&listener).await;
+            for message in [
+                Message::new(MessageId::Bitfield, vec![0]),
+                Message::new(MessageId::Interested, Vec::new()),
+            ] {
+                framed.send(message).await.unwrap();
+            }
+            while framed.next().await.unwrap().unwrap().id != MessageId::Unchoke {}
+            framed
+                .send(Message::new(
+                    MessageId::Request,
+                    BlockRequest::new(0, 0, 8).to_payload(),
+                ))
+                .await
+                .unwrap();
+            loop {
+                let msg = framed.next().await.unwrap().unwrap();
+                if msg.id == MessageId::Piece {
+                    assert_eq!(&msg.payload[8..], b"seedme!!");
+                    break;
+                }
+            }
+
+            // Of no use to each other any more, so we get disconnected
+            framed
+                .send(Message::new(MessageId::NotInterested, Vec::new()))
+                .await
+                .unwrap();
+            while let Some(Ok(_)) = framed.next().await {}
+        });
+
+        let options = ConnectOptions {
+            extensions: Arc::clone(&download.extensions),
+            bitfield: Some(download.piece_manager.lock().await.bitfield()),
+            num_pieces: Some(1),
+        };
+        let have_rx = download.have_tx.subscribe();
+        let info_hash = download.torrent.info_hash();
+        let peer = Peer::connect_with_options(addr, info_hash, [3; 20], options)
+            .await
+            .unwrap();
+        download.register_peer(0, &peer).await;
+        let (choker_id, choke_rx) = download.choker.lock().await.register(addr);
+        let upload = UploadState {
+            choker_id,
+            choke_rx,
+            have_rx,
+            interested: false,
+        };
+        let (tx, mut rx) = mpsc::channel(4);
+        let (_connected_tx, connected_rx) = watch::channel(Vec::new());
+        let (_stop_tx, stop_rx) = watch::channel(false);
+        Download::spawn_peer_task(
+            0,
+            peer,
+            download.seed_context(),
+            upload,
+            tx,
+            connected_rx,
+            stop_rx,
+        );
+
+        let message = timeout(Duration::from_secs(10), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            message,
+            TaskMessage::PeerDisconnected {
+                peer_id: 0,
+                misbehaved: false
+            }
+        ));
+        leecher.await.unwrap();
     }
 }
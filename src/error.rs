@@ -13,9 +13,16 @@ pub enum BitTorrentError {
     #[error("Tracker error: {0}")]
     Tracker(String),
 
+    /// The tracker answered, but refused the announce with this reason.
+    #[error("Tracker failure: {0}")]
+    TrackerFailure(String),
+
     #[error("Peer error: {0}")]
     Peer(String),
 
+    #[error("DHT error: {0}")]
+    Dht(String),
+
     #[error("Protocol error: {0}")]
     Protocol(String),
 
@@ -31,6 +38,9 @@ pub enum BitTorrentError {
     #[error("Invalid data: {0}")]
     InvalidData(String),
 
+    #[error("Bencode error: {0}")]
+    Bencode(#[from] crate::bencode::DecodeError),
+
     #[error("Timeout error")]
     Timeout(#[from] Elapsed),
 
@@ -0,0 +1,182 @@
+use crate::error::{BitTorrentError, Result};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+/// Length of a node id and of the compact node info that carries it.
+pub const NODE_ID_LEN: usize = 20;
+const COMPACT_NODE_LEN: usize = NODE_ID_LEN + 6;
+const COMPACT_PEER_LEN: usize = 6;
+
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/// A DHT node's id and UDP address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: [u8; 20],
+    pub addr: SocketAddrV4,
+}
+
+/// A KRPC message (BEP 5): a query (`y` = "q"), response ("r") or error
+/// ("e"), matched to its query by the transaction id `t`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KrpcMessage {
+    pub t: ByteBuf,
+    pub y: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub a: Option<QueryArgs>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r: Option<ResponseValues>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<(i64, String)>,
+}
+
+/// Arguments of every query type; each query uses a subset.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QueryArgs {
+    pub id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implied_port: Option<u8>,
+}
+
+/// Values of every response type; each response uses a subset.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResponseValues {
+    pub id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<ByteBuf>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+}
+
+impl KrpcMessage {
+    pub fn query(transaction: [u8; 2], method: &str, args: QueryArgs) -> Self {
+        Self {
+            t: ByteBuf::from(transaction.to_vec()),
+            y: "q".into(),
+            q: Some(method.into()),
+            a: Some(args),
+            ..Default::default()
+        }
+    }
+
+    pub fn response(transaction: ByteBuf, values: ResponseValues) -> Self {
+        Self {
+            t: transaction,
+            y: "r".into(),
+            r: Some(values),
+            ..Default::default()
+        }
+    }
+
+    pub fn error(transaction: ByteBuf, code: i64, message: &str) -> Self {
+        Self {
+            t: transaction,
+            y: "e".into(),
+            e: Some((code, message.into())),
+            ..Default::default()
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        serde_bencode::from_bytes(data)
+            .map_err(|e| BitTorrentError::Protocol(format!("Invalid KRPC message: {}", e)))
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        serde_bencode::to_bytes(self).map_err(|e| BitTorrentError::Protocol(e.to_string()))
+    }
+}
+
+/// Reads a 20-byte id or info-hash from a KRPC field.
+pub fn parse_id(bytes: &[u8]) -> Option<[u8; 20]> {
+    bytes.try_into().ok()
+}
+
+pub fn encode_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(nodes.len() * COMPACT_NODE_LEN);
+    for node in nodes {
+        out.extend_from_slice(&node.id);
+        out.extend_from_slice(&encode_peer(node.addr));
+    }
+    out
+}
+
+/// Parses compact node info, ignoring a trailing partial entry.
+pub fn decode_nodes(data: &[u8]) -> Vec<NodeInfo> {
+    data.chunks_exact(COMPACT_NODE_LEN)
+        .filter_map(|chunk| {
+            Some(NodeInfo {
+                id: parse_id(&chunk[..NODE_ID_LEN])?,
+                addr: decode_peer(&chunk[NODE_ID_LEN..])?,
+            })
+        })
+        .collect()
+}
+
+pub fn encode_peer(addr: SocketAddrV4) -> [u8; 6] {
+    let mut out = [0u8; COMPACT_PEER_LEN];
+    out[..4].copy_from_slice(&addr.ip().octets());
+    out[4..].copy_from_slice(&addr.port().to_be_bytes());
+    out
+}
+
+pub fn decode_peer(data: &[u8]) -> Option<SocketAddrV4> {
+    if data.len() != COMPACT_PEER_LEN {
+        return None;
+    }
+    let ip = Ipv4Addr::new(data[0], data[1], data[2], data[3]);
+    let port = u16::from_be_bytes([data[4], data[5]]);
+    Some(SocketAddrV4::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_round_trip() {
+        let args = QueryArgs {
+            id: ByteBuf::from(vec![1; 20]),
+            info_hash: Some(ByteBuf::from(vec![2; 20])),
+            ..Default::default()
+        };
+        let encoded = KrpcMessage::query([0, 7], "get_peers", args)
+            .encode()
+            .unwrap();
+        assert!(encoded.starts_with(b"d1:ad2:id20:"));
+        assert!(encoded.ends_with(b"1:q9:get_peers1:t2:\x00\x071:y1:qe"));
+
+        let decoded = KrpcMessage::decode(&encoded).unwrap();
+        assert_eq!(decoded.q.as_deref(), Some("get_peers"));
+        let args = decoded.a.unwrap();
+        assert_eq!(args.info_hash.unwrap().as_ref(), &[2; 20]);
+        assert!(args.target.is_none());
+    }
+
+    #[test]
+    fn test_error_and_compact_nodes() {
+        let error = KrpcMessage::decode(b"d1:eli201e13:Generic Errore1:t2:aa1:y1:ee").unwrap();
+        assert_eq!(error.e, Some((201, "Generic Error".to_string())));
+
+        let nodes = vec![NodeInfo {
+            id: [9; 20],
+            addr: "10.1.2.3:6881".parse().unwrap(),
+        }];
+        assert_eq!(decode_nodes(&encode_nodes(&nodes)), nodes);
+    }
+}
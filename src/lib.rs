@@ -1,21 +1,29 @@
-// src/lib.rs
 pub mod error;
+pub mod bencode;
 pub mod torrent;
+pub mod create;
+pub mod magnet;
+pub mod metadata;
 pub mod tracker;
+pub mod udp_tracker;
+pub mod krpc;
+pub mod dht;
 pub mod peer;
+pub mod pex;
 pub mod piece;
+pub mod merkle;
 pub mod message;
 pub mod download;
 pub mod client;
+pub mod storage;
+pub mod resume;
+pub mod seed;
+pub mod stats;
+pub mod swarm;
+pub mod choker;
+pub mod rate_limit;
 pub mod utils;
 
-#[cfg(test)]
-pub mod tests {
-    pub mod common;
-}
-
-use std::sync::atomic::{AtomicU64, Ordering};
-
 // Standard BitTorrent constants
 pub const BLOCK_SIZE: usize = 16_384; // 16KB
 pub const MAX_MESSAGE_SIZE: usize = 1_048_576; // 1MB
@@ -23,3 +31,7 @@ pub const DEFAULT_PORT_RANGE: (u16, u16) = (6881, 6889);
 pub const PEER_ID_PREFIX: &[u8; 8] = b"-RS0001-";
 pub const MAX_PEERS: usize = 100;
 
+#[cfg(test)]
+pub mod tests {
+    pub mod common;
+}
@@ -0,0 +1,158 @@
+use crate::error::{BitTorrentError, Result};
+use std::net::SocketAddrV4;
+use std::str::FromStr;
+use url::Url;
+
+const BTIH_PREFIX: &str = "urn:btih:";
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// A parsed `magnet:` URI (BEP 9).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MagnetLink {
+    pub info_hash: [u8; 20],
+    /// Suggested name from `dn`, until the metadata says otherwise.
+    pub display_name: Option<String>,
+    /// Trackers from `tr`, in the order given.
+    pub trackers: Vec<String>,
+    /// Peers from `x.pe`. Only IPv4 `ip:port` entries are kept.
+    pub peers: Vec<SocketAddrV4>,
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> Result<Self> {
+        let url = Url::parse(uri)
+            .map_err(|e| BitTorrentError::InvalidData(format!("Invalid magnet link: {}", e)))?;
+        if url.scheme() != "magnet" {
+            return Err(BitTorrentError::InvalidData(format!(
+                "Not a magnet link: {}",
+                uri
+            )));
+        }
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers: Vec<String> = Vec::new();
+        let mut peers = Vec::new();
+
+        // query_pairs percent-decodes keys and values
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    // Other urn types (e.g. btmh for v2) are skipped
+                    if let Some(hash) = value.strip_prefix(BTIH_PREFIX) {
+                        info_hash = Some(parse_btih(hash)?);
+                    }
+                }
+                "dn" => display_name = Some(value.into_owned()),
+                "tr" if !trackers.iter().any(|tracker| *tracker == value) => {
+                    trackers.push(value.into_owned())
+                }
+                "x.pe" => {
+                    if let Ok(addr) = value.parse() {
+                        peers.push(addr);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let info_hash = info_hash.ok_or_else(|| {
+            BitTorrentError::InvalidData("Magnet link has no urn:btih info-hash".into())
+        })?;
+
+        Ok(Self {
+            info_hash,
+            display_name,
+            trackers,
+            peers,
+        })
+    }
+
+    /// Tracker tiers to announce to: one tier per `tr`, so every tracker
+    /// is tried.
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        self.trackers.iter().map(|url| vec![url.clone()]).collect()
+    }
+}
+
+impl FromStr for MagnetLink {
+    type Err = BitTorrentError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+// The info-hash is either 40 hex digits or 32 base32 characters
+fn parse_btih(hash: &str) -> Result<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).ok(),
+        32 => decode_base32(hash),
+        _ => None,
+    };
+
+    bytes
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| BitTorrentError::InvalidData(format!("Invalid btih info-hash: {}", hash)))
+}
+
+fn decode_base32(input: &str) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for c in input.bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&a| a == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+
+    #[test]
+    fn test_parse_hex_magnet() {
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}&dn=Some%20File&tr=udp%3A%2F%2Ftracker.example%3A80\
+             &tr=http%3A%2F%2Fother.example%2Fannounce&x.pe=10.0.0.1%3A6881&x.pe=host%3A1",
+            HASH
+        );
+        let magnet = MagnetLink::parse(&uri).unwrap();
+
+        assert_eq!(hex::encode(magnet.info_hash), HASH);
+        assert_eq!(magnet.display_name.as_deref(), Some("Some File"));
+        assert_eq!(
+            magnet.trackers,
+            vec![
+                "udp://tracker.example:80".to_string(),
+                "http://other.example/announce".to_string()
+            ]
+        );
+        assert_eq!(magnet.peers, vec!["10.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_parse_base32_magnet() {
+        let magnet: MagnetLink = "magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK"
+            .parse()
+            .unwrap();
+        assert_eq!(hex::encode(magnet.info_hash), HASH);
+        assert!(magnet.trackers.is_empty());
+
+        assert!(MagnetLink::parse("magnet:?dn=missing-hash").is_err());
+        assert!(MagnetLink::parse("magnet:?xt=urn:btih:1234").is_err());
+        assert!(MagnetLink::parse("http://example.com/?xt=urn:btih:1234").is_err());
+    }
+}
@@ -1,38 +1,120 @@
+use bittorrent::{
+    client::Client,
+    create::TorrentBuilder,
+    error::{BitTorrentError, Result},
+};
 use std::env;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio;
 use tokio::sync::Mutex;
-use bittorrent::{client::Client, error::Result};
 
 // Usage:
-// cargo run -- path/to/your/file.torrent
+// cargo run -- path/to/your/file.torrent [download_dir]
+// cargo run -- "magnet:?xt=urn:btih:..." [download_dir]
+// cargo run -- make-torrent path/to/share [options]
+
+const MAKE_TORRENT_USAGE: &str = "Usage: bittorrent make-torrent <file|dir> [-o out.torrent] \
+[--announce url[,url...]]... [--comment text] [--created-by text] [--no-created-by] \
+[--creation-date secs] [--no-creation-date] [--private] [--web-seed url]... [--piece-length bytes]";
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Get torrent file path from command line arguments
-    let torrent_path = env::args()
-        .nth(1)
+    // Get torrent file path or magnet link from command line arguments
+    let source = env::args().nth(1).ok_or_else(|| {
+        BitTorrentError::Client("Usage: bittorrent <torrent_file|magnet_uri> [download_dir]".into())
+    })?;
+
+    if source == "make-torrent" {
+        return make_torrent(env::args().skip(2).collect()).await;
+    }
+
+    // Pieces are written straight into the output files as they verify
+    let download_dir = env::args()
+        .nth(2)
         .map(PathBuf::from)
-        .ok_or_else(|| bittorrent::error::BitTorrentError::Client("Usage: bittorrent <torrent_file>".into()))?;
+        .unwrap_or_else(|| PathBuf::from("."));
 
     let client = Arc::new(Mutex::new(Client::new()));
     {
         let mut client_lock = client.lock().await;
-        client_lock.add_torrent(&torrent_path).await?;
+        // Trackers alone still work if the DHT can't be joined
+        if let Err(e) = client_lock
+            .start_dht(download_dir.join(".dht_nodes.json"))
+            .await
+        {
+            println!("DHT unavailable: {}", e);
+        }
+        if source.starts_with("magnet:") {
+            client_lock.add_magnet(&source, &download_dir).await?;
+        } else {
+            client_lock.add_torrent(&source, &download_dir).await?;
+        }
     }
 
     // Start the download
     println!("Starting download...");
-    let data = {
+    let output_path = {
         let mut client_lock = client.lock().await;
-        client_lock.start_download().await?
+        client_lock.start_download().await?;
+        client_lock
+            .output_path()
+            .map(PathBuf::from)
+            .unwrap_or(download_dir)
     };
 
-    // Write data to output file
-    let output_path = torrent_path.with_extension("out");
-    tokio::fs::write(&output_path, data).await?;
-
     println!("Download complete! Saved to: {}", output_path.display());
+
+    // Keep serving what we downloaded until interrupted
+    println!("Seeding... press Ctrl+C to stop");
+    {
+        let mut client_lock = client.lock().await;
+        client_lock.seed().await?;
+    }
+
+    Ok(())
+}
+
+// Each `--announce` adds a tier; commas separate the trackers within it
+async fn make_torrent(args: Vec<String>) -> Result<()> {
+    let usage = || BitTorrentError::Client(MAKE_TORRENT_USAGE.into());
+    let mut args = args.into_iter();
+    let path = PathBuf::from(args.next().ok_or_else(usage)?);
+    let mut output = None;
+    let mut builder = TorrentBuilder::new(&path);
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(usage);
+        builder = match arg.as_str() {
+            "-o" | "--output" => {
+                output = Some(PathBuf::from(value()?));
+                builder
+            }
+            "--announce" => builder.with_tracker_tier(
+                value()?
+                    .split(',')
+                    .filter(|url| !url.is_empty())
+                    .map(String::from)
+                    .collect(),
+            ),
+            "--comment" => builder.with_comment(value()?),
+            "--created-by" => builder.with_created_by(Some(value()?)),
+            "--no-created-by" => builder.with_created_by(None),
+            "--creation-date" => {
+                builder.with_creation_date(Some(value()?.parse().map_err(|_| usage())?))
+            }
+            "--no-creation-date" => builder.with_creation_date(None),
+            "--private" => builder.with_private(true),
+            "--web-seed" => builder.with_web_seed(value()?),
+            "--piece-length" => builder.with_piece_length(value()?.parse().map_err(|_| usage())?),
+            _ => return Err(usage()),
+        };
+    }
+
+    let torrent = builder.build().await?;
+    let output = output.unwrap_or_else(|| PathBuf::from(format!("{}.torrent", torrent.info.name)));
+    torrent.save(&output).await?;
+    println!("{}", torrent);
+    println!("Info hash: {}", hex::encode(torrent.info_hash()));
+    println!("Saved to: {}", output.display());
     Ok(())
 }
@@ -0,0 +1,161 @@
+//! SHA-256 merkle trees over 16 KiB blocks, as BitTorrent v2 (BEP 52) uses
+//! to hash files. Leaves past the end of a file are all-zero hashes.
+
+use crate::BLOCK_SIZE;
+use sha2::{Digest, Sha256};
+
+/// Hashes of one base layer range sent or accepted in a single message.
+pub const MAX_HASHES: usize = 512;
+
+pub fn hash_block(block: &[u8]) -> [u8; 32] {
+    Sha256::digest(block).into()
+}
+
+fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Root of a subtree with `leaves` zero leaves, standing in for the parts
+/// of a layer past the end of the file.
+pub fn pad_hash(leaves: usize) -> [u8; 32] {
+    let mut hash = [0; 32];
+    let mut width = 1;
+    while width < leaves {
+        hash = hash_pair(&hash, &hash);
+        width *= 2;
+    }
+    hash
+}
+
+/// The layer above `layer`, which must have an even number of hashes.
+fn parent_layer(layer: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    layer
+        .chunks_exact(2)
+        .map(|pair| hash_pair(&pair[0], &pair[1]))
+        .collect()
+}
+
+// `hashes` padded with `pad` to `width`, then every layer above it up to
+// the root
+fn layers(hashes: &[[u8; 32]], width: usize, pad: [u8; 32]) -> Vec<Vec<[u8; 32]>> {
+    let mut layer = hashes.to_vec();
+    layer.resize(width.max(hashes.len()).next_power_of_two(), pad);
+    let mut layers = vec![layer];
+    while layers.last().map_or(0, Vec::len) > 1 {
+        let parent = parent_layer(layers.last().expect("at least one layer"));
+        layers.push(parent);
+    }
+    layers
+}
+
+/// Root of the tree whose lowest layer is `hashes` followed by copies of
+/// `pad` up to `width` hashes, rounded up to a power of two.
+pub fn root(hashes: &[[u8; 32]], width: usize, pad: [u8; 32]) -> [u8; 32] {
+    let mut layers = layers(hashes, width, pad);
+    layers.pop().expect("at least one layer")[0]
+}
+
+/// Root of `data` split into blocks, padded with zero leaves to `leaves`.
+/// For a v2 piece, `leaves` is the piece length in blocks, or for a file
+/// of a single piece, its own block count rounded up to a power of two.
+pub fn data_root(data: &[u8], leaves: usize) -> [u8; 32] {
+    let hashes: Vec<[u8; 32]> = data.chunks(BLOCK_SIZE).map(hash_block).collect();
+    root(&hashes, leaves, [0; 32])
+}
+
+/// The `length` hashes of a layer starting at `index`, then the uncle
+/// hashes proving them, lowest first, `uncles` of them at most. The layer
+/// is `hashes` padded with `pad` to `width`.
+pub fn proof(
+    hashes: &[[u8; 32]],
+    width: usize,
+    pad: [u8; 32],
+    index: usize,
+    length: usize,
+    uncles: usize,
+) -> Option<Vec<[u8; 32]>> {
+    let layers = layers(hashes, width, pad);
+    let base = &layers[0];
+    if !length.is_power_of_two() || !index.is_multiple_of(length) || index + length > base.len() {
+        return None;
+    }
+
+    let mut proof = base[index..index + length].to_vec();
+    let mut position = index / length;
+    for layer in layers
+        .iter()
+        .skip(length.trailing_zeros() as usize)
+        .take(uncles)
+    {
+        if layer.len() < 2 {
+            break;
+        }
+        proof.push(layer[position ^ 1]);
+        position /= 2;
+    }
+    Some(proof)
+}
+
+/// Whether `base`, starting at `index` in its layer, and the uncle hashes
+/// above it hash up to `root`.
+pub fn verify(root: &[u8; 32], index: usize, base: &[[u8; 32]], uncles: &[[u8; 32]]) -> bool {
+    if !base.len().is_power_of_two() || !index.is_multiple_of(base.len()) {
+        return false;
+    }
+
+    let mut layer = base.to_vec();
+    while layer.len() > 1 {
+        layer = parent_layer(&layer);
+    }
+    let mut node = layer[0];
+    let mut position = index / base.len();
+    for uncle in uncles {
+        node = if position.is_multiple_of(2) {
+            hash_pair(&node, uncle)
+        } else {
+            hash_pair(uncle, &node)
+        };
+        position /= 2;
+    }
+    position == 0 && node == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roots_pad_with_zero_leaves() {
+        let data = vec![7u8; BLOCK_SIZE * 3 + 10];
+        let leaves: Vec<[u8; 32]> = data.chunks(BLOCK_SIZE).map(hash_block).collect();
+        let left = hash_pair(&leaves[0], &leaves[1]);
+        let right = hash_pair(&leaves[2], &leaves[3]);
+        assert_eq!(data_root(&data, 4), hash_pair(&left, &right));
+
+        // A wider tree pads whole subtrees at once
+        let eight = hash_pair(&hash_pair(&left, &right), &pad_hash(4));
+        assert_eq!(data_root(&data, 8), eight);
+        assert_eq!(root(&[left, right], 4, pad_hash(2)), eight);
+    }
+
+    #[test]
+    fn test_proofs_verify_against_root() {
+        let hashes: Vec<[u8; 32]> = (0..5u8).map(|i| hash_block(&[i])).collect();
+        let pad = pad_hash(1);
+        let tree_root = root(&hashes, 8, pad);
+
+        let proof = proof(&hashes, 8, pad, 4, 2, 3).unwrap();
+        assert_eq!(proof.len(), 4);
+        let (base, uncles) = proof.split_at(2);
+        assert_eq!(base, &[hashes[4], pad]);
+        assert!(verify(&tree_root, 4, base, uncles));
+
+        // Wrong position, a tampered hash or a short proof all fail
+        assert!(!verify(&tree_root, 2, base, uncles));
+        assert!(!verify(&tree_root, 4, &[hashes[3], pad], uncles));
+        assert!(!verify(&tree_root, 4, base, &uncles[..1]));
+    }
+}
@@ -1,8 +1,14 @@
 // src/message.rs
 use bytes::{Buf, BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
 use tokio_util::codec::{Decoder, Encoder};
 use std::io;
 
+/// Extended message id reserved for the BEP 10 handshake itself.
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MessageId {
     Choke = 0,
@@ -14,6 +20,32 @@ pub enum MessageId {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    // Fast extension (BEP 6)
+    Suggest = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    Reject = 16,
+    AllowedFast = 17,
+    Extended = 20,
+    // BitTorrent v2 (BEP 52)
+    HashRequest = 21,
+    Hashes = 22,
+    HashReject = 23,
+}
+
+impl MessageId {
+    /// Whether the message is part of the Fast extension (BEP 6), which
+    /// only peers that both set its bit may send.
+    pub fn is_fast(self) -> bool {
+        matches!(
+            self,
+            MessageId::Suggest
+                | MessageId::HaveAll
+                | MessageId::HaveNone
+                | MessageId::Reject
+                | MessageId::AllowedFast
+        )
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -31,16 +63,132 @@ impl Message {
     }
 }
 
-pub struct PeerCodec {
-    // For reading partial messages
-    partial_msg: BytesMut,
+/// A block within a piece, as carried by `Request` and `Cancel` messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
 }
 
-impl PeerCodec {
-    pub fn new() -> Self {
+impl BlockRequest {
+    pub fn new(index: u32, begin: u32, length: u32) -> Self {
+        Self {
+            index,
+            begin,
+            length,
+        }
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        if payload.len() != 12 {
+            return None;
+        }
+        let mut payload = payload;
+        Some(Self {
+            index: payload.get_u32(),
+            begin: payload.get_u32(),
+            length: payload.get_u32(),
+        })
+    }
+
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(12);
+        payload.put_u32(self.index);
+        payload.put_u32(self.begin);
+        payload.put_u32(self.length);
+        payload
+    }
+}
+
+/// A range of hashes in the merkle tree of a v2 file, as carried by
+/// `HashRequest`, `Hashes` and `HashReject` messages (BEP 52).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HashRequest {
+    pub pieces_root: [u8; 32],
+    /// Layer the hashes are taken from, 0 being the 16 KiB blocks.
+    pub base_layer: u32,
+    pub index: u32,
+    /// Number of hashes, a power of two.
+    pub length: u32,
+    /// Layers above the base layer to send uncle hashes for.
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    pub const PAYLOAD_LEN: usize = 48;
+
+    pub fn new(
+        pieces_root: [u8; 32],
+        base_layer: u32,
+        index: u32,
+        length: u32,
+        proof_layers: u32,
+    ) -> Self {
         Self {
-            partial_msg: BytesMut::new(),
+            pieces_root,
+            base_layer,
+            index,
+            length,
+            proof_layers,
+        }
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        if payload.len() != Self::PAYLOAD_LEN {
+            return None;
         }
+        let (root, mut payload) = payload.split_at(32);
+        Some(Self {
+            pieces_root: root.try_into().ok()?,
+            base_layer: payload.get_u32(),
+            index: payload.get_u32(),
+            length: payload.get_u32(),
+            proof_layers: payload.get_u32(),
+        })
+    }
+
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(Self::PAYLOAD_LEN);
+        payload.extend_from_slice(&self.pieces_root);
+        payload.put_u32(self.base_layer);
+        payload.put_u32(self.index);
+        payload.put_u32(self.length);
+        payload.put_u32(self.proof_layers);
+        payload
+    }
+}
+
+/// Payload of the BEP 10 extended handshake.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    /// Extension names mapped to the message id the sender wants to
+    /// receive them on. An id of 0 means the extension is disabled.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+    /// Size of the info dictionary, sent by peers that have it (BEP 9).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+    /// TCP port the sender accepts connections on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    /// Client name and version, e.g. "uTorrent 3.5.5".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<ByteBuf>,
+    /// How many requests the sender queues before dropping more.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
+    /// The receiver's IP as the sender sees it: 4 bytes, or 16 for IPv6.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+}
+
+#[derive(Debug, Default)]
+pub struct PeerCodec;
+
+impl PeerCodec {
+    pub fn new() -> Self {
+        Self
     }
 }
 
@@ -49,28 +197,29 @@ impl Decoder for PeerCodec {
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        // Need at least 4 bytes for message length
-        if src.len() < 4 {
-            return Ok(None);
-        }
-
-        // Read but don't consume message length
-        let mut length_bytes = [0u8; 4];
-        length_bytes.copy_from_slice(&src[..4]);
-        let length = u32::from_be_bytes(length_bytes) as usize;
+        // Skip keepalives; a message may already be buffered behind them
+        let length = loop {
+            // Need at least 4 bytes for message length
+            if src.len() < 4 {
+                return Ok(None);
+            }
 
-        // Check if we have the full message
-        if src.len() < 4 + length {
-            return Ok(None);
-        }
+            // Read but don't consume message length
+            let mut length_bytes = [0u8; 4];
+            length_bytes.copy_from_slice(&src[..4]);
+            let length = u32::from_be_bytes(length_bytes) as usize;
 
-        // Consume message length
-        src.advance(4);
+            // Check if we have the full message
+            if src.len() < 4 + length {
+                return Ok(None);
+            }
 
-        // Handle keepalive message
-        if length == 0 {
-            return Ok(None);
-        }
+            // Consume message length
+            src.advance(4);
+            if length > 0 {
+                break length;
+            }
+        };
 
         // Need at least 1 byte for message ID
         if length < 1 {
@@ -91,6 +240,15 @@ impl Decoder for PeerCodec {
             6 => MessageId::Request,
             7 => MessageId::Piece,
             8 => MessageId::Cancel,
+            13 => MessageId::Suggest,
+            14 => MessageId::HaveAll,
+            15 => MessageId::HaveNone,
+            16 => MessageId::Reject,
+            17 => MessageId::AllowedFast,
+            20 => MessageId::Extended,
+            21 => MessageId::HashRequest,
+            22 => MessageId::Hashes,
+            23 => MessageId::HashReject,
             n => {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidData,
@@ -0,0 +1,422 @@
+use crate::{
+    bencode::Decoder,
+    dht::Dht,
+    error::{BitTorrentError, Result},
+    magnet::MagnetLink,
+    peer::Peer,
+    stats::TransferStats,
+    torrent::Torrent,
+    tracker::{AnnounceEvent, Tracker},
+    utils::generate_peer_id,
+};
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+use tokio::time::{timeout, Duration};
+
+/// Extension name for metadata exchange (BEP 9).
+pub const UT_METADATA: &str = "ut_metadata";
+/// The id we ask peers to send `ut_metadata` messages to us on.
+pub const UT_METADATA_ID: u8 = 1;
+
+const METADATA_PIECE_SIZE: usize = 16 * 1024;
+// Refuse to allocate for an advertised size beyond this
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+const MSG_REQUEST: u8 = 0;
+const MSG_DATA: u8 = 1;
+const MSG_REJECT: u8 = 2;
+
+// Time allowed per peer to connect and deliver the whole info dictionary
+const METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+const CONCURRENT_FETCHES: usize = 10;
+const STOPPED_TIMEOUT: Duration = Duration::from_secs(5);
+// Nesting allowed in a ut_metadata message header
+const MAX_HEADER_DEPTH: usize = 16;
+
+// The bencoded dictionary at the start of every ut_metadata message. Data
+// messages are followed by the raw piece bytes.
+#[derive(Debug, Serialize, Deserialize)]
+struct MetadataMessage {
+    msg_type: u8,
+    piece: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+/// Resolves a magnet link into a full torrent: finds peers through the
+/// link's trackers and `x.pe` entries (and the DHT, if given), then
+/// downloads the info dictionary from the first peer that can provide it.
+pub async fn fetch_torrent(magnet: &MagnetLink, port: u16, dht: Option<&Dht>) -> Result<Torrent> {
+    let info_hash = magnet.info_hash;
+    let peer_id = generate_peer_id();
+    let mut candidates = magnet.peers.clone();
+
+    // The size is unknown until we have the metadata, so report a
+    // placeholder rather than 0, which trackers take to mean "seeding"
+    let stats = Arc::new(TransferStats::new(1));
+    let mut tracker = if magnet.trackers.is_empty() {
+        None
+    } else {
+        Some(Tracker::with_tiers(
+            magnet.tracker_tiers(),
+            info_hash,
+            port,
+            peer_id,
+            stats,
+        )?)
+    };
+    if let Some(tracker) = &mut tracker {
+        match tracker.get_peers().await {
+            Ok(peers) => candidates.extend(
+                peers
+                    .into_iter()
+                    .filter(|addr| !magnet.peers.contains(addr)),
+            ),
+            Err(e) => println!("Tracker announce for metadata failed: {}", e),
+        }
+    }
+    if let Some(dht) = dht {
+        match dht.get_peers(info_hash).await {
+            Ok(peers) => {
+                for peer in peers {
+                    if !candidates.contains(&peer) {
+                        candidates.push(peer);
+                    }
+                }
+            }
+            Err(e) => println!("DHT lookup for metadata failed: {}", e),
+        }
+    }
+
+    if candidates.is_empty() {
+        return Err(BitTorrentError::Peer(
+            "No peers to fetch metadata from".into(),
+        ));
+    }
+    println!(
+        "Fetching metadata from {} candidate peers",
+        candidates.len()
+    );
+
+    let mut candidates = candidates.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    for addr in candidates.by_ref().take(CONCURRENT_FETCHES) {
+        attempts.push(fetch_from_peer(addr, info_hash, peer_id));
+    }
+
+    let mut result = Err(BitTorrentError::Peer(
+        "Could not fetch metadata from any peer".into(),
+    ));
+    while let Some(attempt) = attempts.next().await {
+        match attempt {
+            Ok(info) => {
+                result = Torrent::from_metadata(&info, magnet.tracker_tiers());
+                break;
+            }
+            Err(e) => {
+                println!("Metadata fetch failed: {}", e);
+                if let Some(addr) = candidates.next() {
+                    attempts.push(fetch_from_peer(addr, info_hash, peer_id));
+                }
+            }
+        }
+    }
+
+    // The download announces again under its own peer id
+    if let Some(tracker) = &mut tracker {
+        let _ = timeout(STOPPED_TIMEOUT, tracker.announce(AnnounceEvent::Stopped)).await;
+    }
+
+    result
+}
+
+async fn fetch_from_peer(
+    addr: SocketAddrV4,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+) -> Result<Vec<u8>> {
+    timeout(METADATA_TIMEOUT, async {
+        let mut peer = Peer::connect(addr, info_hash, peer_id).await?;
+        fetch_metadata(&mut peer, info_hash).await
+    })
+    .await?
+}
+
+/// Downloads the info dictionary from a connected peer with `ut_metadata`
+/// and checks it against `info_hash`.
+pub async fn fetch_metadata(peer: &mut Peer, info_hash: [u8; 20]) -> Result<Vec<u8>> {
+    if !peer.supports_extensions() {
+        return Err(BitTorrentError::Peer(
+            "Peer does not support the extension protocol".into(),
+        ));
+    }
+
+    while !peer.has_extended_handshake() {
+        if peer.recv().await?.is_none() {
+            return Err(BitTorrentError::Peer(
+                "Peer disconnected before extended handshake".into(),
+            ));
+        }
+    }
+
+    if peer.extension_id(UT_METADATA).is_none() {
+        return Err(BitTorrentError::Peer(
+            "Peer does not support ut_metadata".into(),
+        ));
+    }
+    let size = peer
+        .metadata_size()
+        .filter(|&size| size > 0 && size <= MAX_METADATA_SIZE)
+        .ok_or_else(|| BitTorrentError::Peer("Peer sent no usable metadata_size".into()))?;
+
+    let num_pieces = size.div_ceil(METADATA_PIECE_SIZE);
+    for piece in 0..num_pieces {
+        send_metadata_message(peer, MSG_REQUEST, piece).await?;
+    }
+
+    let mut metadata = vec![0u8; size];
+    let mut received = vec![false; num_pieces];
+    let mut remaining = num_pieces;
+
+    while remaining > 0 {
+        if peer.recv().await?.is_none() {
+            return Err(BitTorrentError::Peer(
+                "Peer disconnected during metadata transfer".into(),
+            ));
+        }
+
+        for (id, payload) in peer.take_extended_messages() {
+            if id != UT_METADATA_ID {
+                continue;
+            }
+            let (header, data) = parse_message(&payload)?;
+
+            match header.msg_type {
+                MSG_DATA => {
+                    let piece = header.piece;
+                    if piece >= num_pieces || received[piece] {
+                        continue;
+                    }
+                    let begin = piece * METADATA_PIECE_SIZE;
+                    let end = std::cmp::min(begin + METADATA_PIECE_SIZE, size);
+                    if data.len() != end - begin {
+                        return Err(BitTorrentError::Protocol(format!(
+                            "Metadata piece {} has wrong length {}",
+                            piece,
+                            data.len()
+                        )));
+                    }
+                    metadata[begin..end].copy_from_slice(data);
+                    received[piece] = true;
+                    remaining -= 1;
+                }
+                MSG_REJECT => {
+                    return Err(BitTorrentError::Peer(format!(
+                        "Peer rejected metadata piece {}",
+                        header.piece
+                    )));
+                }
+                // We have none to serve yet
+                MSG_REQUEST => send_metadata_message(peer, MSG_REJECT, header.piece).await?,
+                _ => {}
+            }
+        }
+    }
+
+    let hash: [u8; 20] = Sha1::digest(&metadata).into();
+    if hash != info_hash {
+        return Err(BitTorrentError::InvalidData(
+            "Metadata does not match info-hash".into(),
+        ));
+    }
+
+    Ok(metadata)
+}
+
+/// Answers a `ut_metadata` message from the peer: a request gets its piece
+/// of `metadata`, the info dictionary, or a reject if we don't have it.
+/// Other messages are ignored.
+pub async fn serve_metadata(
+    peer: &mut Peer,
+    metadata: Option<&[u8]>,
+    payload: &[u8],
+) -> Result<()> {
+    let (header, _) = parse_message(payload)?;
+    if header.msg_type != MSG_REQUEST || peer.extension_id(UT_METADATA).is_none() {
+        return Ok(());
+    }
+
+    let begin = header.piece.saturating_mul(METADATA_PIECE_SIZE);
+    let Some(metadata) = metadata.filter(|metadata| begin < metadata.len()) else {
+        return send_metadata_message(peer, MSG_REJECT, header.piece).await;
+    };
+    let end = std::cmp::min(begin + METADATA_PIECE_SIZE, metadata.len());
+    let reply = MetadataMessage {
+        msg_type: MSG_DATA,
+        piece: header.piece,
+        total_size: Some(metadata.len()),
+    };
+    let mut payload =
+        serde_bencode::to_bytes(&reply).map_err(|e| BitTorrentError::Protocol(e.to_string()))?;
+    payload.extend_from_slice(&metadata[begin..end]);
+    peer.send_extended(UT_METADATA, &payload).await
+}
+
+// Splits a ut_metadata message into its header and the piece data after it
+fn parse_message(payload: &[u8]) -> Result<(MetadataMessage, &[u8])> {
+    let header_length = bencode_length(payload, MAX_HEADER_DEPTH)
+        .ok_or_else(|| BitTorrentError::Protocol("Malformed ut_metadata message".into()))?;
+    let header = serde_bencode::from_bytes(&payload[..header_length])
+        .map_err(|e| BitTorrentError::Protocol(format!("Invalid ut_metadata: {}", e)))?;
+    Ok((header, &payload[header_length..]))
+}
+
+async fn send_metadata_message(peer: &mut Peer, msg_type: u8, piece: usize) -> Result<()> {
+    let message = MetadataMessage {
+        msg_type,
+        piece,
+        total_size: None,
+    };
+    let payload =
+        serde_bencode::to_bytes(&message).map_err(|e| BitTorrentError::Protocol(e.to_string()))?;
+    peer.send_extended(UT_METADATA, &payload).await
+}
+
+// Length of the bencoded value at the start of `data`, or `None` if it is
+// malformed, truncated or nested deeper than `depth`.
+fn bencode_length(data: &[u8], depth: usize) -> Option<usize> {
+    Decoder::new(data)
+        .with_max_depth(depth)
+        .skip()
+        .ok()
+        .map(<[u8]>::len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{ExtendedHandshake, Message, MessageId};
+    use crate::peer::PeerCodec;
+    use crate::torrent::{FileMode, Info, PieceHashes};
+    use futures_util::SinkExt;
+    use std::collections::BTreeMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_util::codec::Framed;
+
+    // Enough pieces that the info dictionary spans two metadata pieces
+    fn sample_info() -> Vec<u8> {
+        let info = Info {
+            name: "magnet-file".into(),
+            piece_length: 16384,
+            pieces: PieceHashes(vec![[7; 20]; 1000]),
+            files: FileMode::SingleFile {
+                length: 16384 * 1000,
+            },
+            meta_version: None,
+            file_tree: None,
+            private: None,
+            md5sum: None,
+            extra: BTreeMap::new(),
+        };
+        serde_bencode::to_bytes(&info).unwrap()
+    }
+
+    // A peer that answers every ut_metadata request from `metadata`
+    async fn metadata_seeder(metadata: Vec<u8>) -> SocketAddrV4 {
+        const REMOTE_UT_METADATA_ID: u8 = 3;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = match listener.local_addr().unwrap() {
+            std::net::SocketAddr::V4(addr) => addr,
+            _ => unreachable!(),
+        };
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; 68];
+            stream.read_exact(&mut handshake).await.unwrap();
+            // Echo the info-hash back with the extension protocol bit set
+            handshake[20..28].copy_from_slice(&[0, 0, 0, 0, 0, 0x10, 0, 0]);
+            handshake[48..].copy_from_slice(&[2; 20]);
+            stream.write_all(&handshake).await.unwrap();
+
+            let mut framed = Framed::new(stream, PeerCodec::new());
+            let mut ours = ExtendedHandshake {
+                metadata_size: Some(metadata.len()),
+                ..Default::default()
+            };
+            ours.m.insert(UT_METADATA.into(), REMOTE_UT_METADATA_ID);
+            let mut payload = vec![0];
+            payload.extend(serde_bencode::to_bytes(&ours).unwrap());
+            framed
+                .send(Message::new(MessageId::Extended, payload))
+                .await
+                .unwrap();
+
+            while let Some(Ok(msg)) = framed.next().await {
+                if msg.id != MessageId::Extended || msg.payload[0] != REMOTE_UT_METADATA_ID {
+                    continue;
+                }
+                let request: MetadataMessage =
+                    serde_bencode::from_bytes(&msg.payload[1..]).unwrap();
+                let begin = request.piece * METADATA_PIECE_SIZE;
+                let end = std::cmp::min(begin + METADATA_PIECE_SIZE, metadata.len());
+                let header = MetadataMessage {
+                    msg_type: MSG_DATA,
+                    piece: request.piece,
+                    total_size: Some(metadata.len()),
+                };
+                let mut payload = vec![UT_METADATA_ID];
+                payload.extend(serde_bencode::to_bytes(&header).unwrap());
+                payload.extend_from_slice(&metadata[begin..end]);
+                framed
+                    .send(Message::new(MessageId::Extended, payload))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_fetch_torrent_from_magnet_peer() {
+        let info = sample_info();
+        let info_hash: [u8; 20] = Sha1::digest(&info).into();
+        let addr = metadata_seeder(info).await;
+
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}&dn=ignored&x.pe={}",
+            hex::encode(info_hash),
+            addr
+        );
+        let magnet = MagnetLink::parse(&uri).unwrap();
+        let torrent = fetch_torrent(&magnet, 6881, None).await.unwrap();
+
+        assert_eq!(torrent.info_hash(), info_hash);
+        assert_eq!(torrent.info.name, "magnet-file");
+        assert_eq!(torrent.info.pieces.0.len(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_metadata_checked_against_info_hash() {
+        let addr = metadata_seeder(sample_info()).await;
+        let mut peer = Peer::connect(addr, [5; 20], [1; 20]).await.unwrap();
+
+        match fetch_metadata(&mut peer, [5; 20]).await {
+            Err(BitTorrentError::InvalidData(_)) => {}
+            other => panic!("expected an info-hash mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_bencode_length() {
+        let message = b"d8:msg_typei1e5:piecei0eeRAWDATA";
+        assert_eq!(bencode_length(message, 4), Some(message.len() - 7));
+        assert_eq!(bencode_length(b"d5:piece", 4), None);
+        assert_eq!(bencode_length(b"lllleeee", 2), None);
+    }
+}
@@ -1,20 +1,126 @@
 use crate::{
     error::{BitTorrentError, Result},
-    message::{Message, MessageId},
-    piece::PieceInfo,
-    utils::bit_set,
+    message::{
+        BlockRequest, ExtendedHandshake, HashRequest, Message, MessageId, EXTENDED_HANDSHAKE_ID,
+    },
+    metadata::{UT_METADATA, UT_METADATA_ID},
+    pex::{UT_PEX, UT_PEX_ID},
+    rate_limit::{RateLimits, Throttled},
+    utils::{bit_set, set_bit},
     BLOCK_SIZE,
 };
 use bytes::{Buf, BufMut, BytesMut};
 use futures_util::{SinkExt, StreamExt};
-use std::net::{SocketAddr, SocketAddrV4};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio::time::{timeout, Duration};
+use tokio::time::{timeout, Duration, Instant};
 use tokio_util::codec::{Decoder, Encoder, Framed};
 
 const PROTOCOL: &[u8] = b"BitTorrent protocol";
 
+// Requests queued beyond this are dropped, like most clients' reqq limit
+const MAX_QUEUED_REQUESTS: usize = 250;
+// Extension messages nobody has taken yet are dropped beyond this
+const MAX_QUEUED_EXTENDED: usize = 64;
+// Hash requests and answers nobody has taken yet are dropped beyond this
+const MAX_QUEUED_HASHES: usize = 64;
+
+// Reserved bit 20 from the right (byte 5, 0x10) advertises BEP 10
+const EXTENSION_PROTOCOL_BIT: (usize, u8) = (5, 0x10);
+// Reserved bit 3 from the right (byte 7, 0x04) advertises BEP 6
+const FAST_EXTENSION_BIT: (usize, u8) = (7, 0x04);
+// Reserved bit 5 from the right (byte 7, 0x10) advertises BitTorrent v2
+const V2_PROTOCOL_BIT: (usize, u8) = (7, 0x10);
+/// Pieces we let each peer request while it is choked, as BEP 6 suggests.
+pub const ALLOWED_FAST_COUNT: usize = 10;
+// Suggested pieces beyond this are dropped
+const MAX_SUGGESTED: usize = 32;
+
+// Built-in extensions, with the ids we want them sent to us on
+const BUILTIN_EXTENSIONS: &[(&str, u8)] = &[(UT_METADATA, UT_METADATA_ID), (UT_PEX, UT_PEX_ID)];
+// Sent as `v` in the extended handshake
+const CLIENT_VERSION: &str = concat!("bittorrent-rs ", env!("CARGO_PKG_VERSION"));
+
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_BLOCK_RETRIES: u32 = 3;
+// How often the pipeline depth is recomputed from measured throughput
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Bounds for the number of block requests kept in flight to one peer.
+#[derive(Debug, Clone, Copy)]
+pub struct PipelineConfig {
+    pub min_depth: usize,
+    pub max_depth: usize,
+    // Keep this many seconds' worth of data (at the measured rate) requested
+    pub queue_time: Duration,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            min_depth: 5,
+            max_depth: 250,
+            queue_time: Duration::from_secs(3),
+        }
+    }
+}
+
+impl PipelineConfig {
+    /// Pipeline depth that keeps `queue_time` worth of data outstanding at
+    /// `bytes_per_sec`, clamped to the configured bounds.
+    pub fn depth_for_rate(&self, bytes_per_sec: f64) -> usize {
+        let blocks = bytes_per_sec * self.queue_time.as_secs_f64() / BLOCK_SIZE as f64;
+        (blocks.ceil() as usize).clamp(self.min_depth, self.max_depth)
+    }
+}
+
+#[derive(Debug)]
+struct RequestPipeline {
+    config: PipelineConfig,
+    depth: usize,
+    window_start: Instant,
+    window_bytes: usize,
+}
+
+impl RequestPipeline {
+    fn new(config: PipelineConfig) -> Self {
+        Self {
+            config,
+            depth: config.min_depth,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    fn depth(&self) -> usize {
+        self.depth
+    }
+
+    fn record(&mut self, bytes: usize) {
+        self.window_bytes += bytes;
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= RATE_WINDOW {
+            let rate = self.window_bytes as f64 / elapsed.as_secs_f64();
+            self.depth = self.config.depth_for_rate(rate);
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+        }
+    }
+
+    // Back off to the minimum after a stall
+    fn reset(&mut self) {
+        self.depth = self.config.min_depth;
+        self.window_start = Instant::now();
+        self.window_bytes = 0;
+    }
+}
+
 #[derive(Debug)]
 struct Handshake {
     pstrlen: u8,
@@ -26,15 +132,31 @@ struct Handshake {
 
 impl Handshake {
     fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        let mut reserved = [0; 8];
+        reserved[EXTENSION_PROTOCOL_BIT.0] |= EXTENSION_PROTOCOL_BIT.1;
+        reserved[FAST_EXTENSION_BIT.0] |= FAST_EXTENSION_BIT.1;
+        reserved[V2_PROTOCOL_BIT.0] |= V2_PROTOCOL_BIT.1;
         Self {
             pstrlen: 19,
             pstr: *b"BitTorrent protocol",
-            reserved: [0; 8],
+            reserved,
             info_hash,
             peer_id,
         }
     }
 
+    fn supports_extensions(&self) -> bool {
+        self.reserved[EXTENSION_PROTOCOL_BIT.0] & EXTENSION_PROTOCOL_BIT.1 != 0
+    }
+
+    fn supports_fast(&self) -> bool {
+        self.reserved[FAST_EXTENSION_BIT.0] & FAST_EXTENSION_BIT.1 != 0
+    }
+
+    fn supports_v2(&self) -> bool {
+        self.reserved[V2_PROTOCOL_BIT.0] & V2_PROTOCOL_BIT.1 != 0
+    }
+
     async fn write_to(&self, stream: &mut TcpStream) -> Result<()> {
         stream.write_u8(self.pstrlen).await?;
         stream.write_all(&self.pstr).await?;
@@ -52,7 +174,7 @@ impl Handshake {
 
         let mut pstr = [0u8; 19];
         stream.read_exact(&mut pstr).await?;
-        if &pstr != PROTOCOL {
+        if pstr != PROTOCOL {
             return Err(BitTorrentError::Protocol("Invalid protocol string".into()));
         }
 
@@ -75,15 +197,194 @@ impl Handshake {
     }
 }
 
+/// Receives the messages of one BEP 10 extension, from every connection
+/// that uses the registry it is registered in.
+pub trait ExtensionHandler: Send + Sync {
+    /// Called with each message the peer at `addr` sends on the extension.
+    fn on_message(&self, addr: SocketAddrV4, payload: &[u8]) -> Result<()>;
+
+    /// Called when the extended handshake of a peer supporting the
+    /// extension arrives.
+    fn on_handshake(&self, _addr: SocketAddrV4, _handshake: &ExtendedHandshake) {}
+}
+
+#[derive(Clone)]
+struct Extension {
+    name: String,
+    id: u8,
+    // Without one, messages are queued for `Peer::take_extended_messages`
+    handler: Option<Arc<dyn ExtensionHandler>>,
+}
+
+impl fmt::Debug for Extension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extension")
+            .field("name", &self.name)
+            .field("id", &self.id)
+            .field("handler", &self.handler.is_some())
+            .finish()
+    }
+}
+
+/// The BEP 10 extensions we offer, with the ids we want them sent to us
+/// on, and what else we say about ourselves in the extended handshake.
+/// `ut_metadata` and `ut_pex` are always present.
+#[derive(Debug, Clone)]
+pub struct ExtensionRegistry {
+    extensions: Vec<Extension>,
+    listen_port: Option<u16>,
+    metadata_size: Option<usize>,
+}
+
+impl Default for ExtensionRegistry {
+    fn default() -> Self {
+        Self {
+            extensions: BUILTIN_EXTENSIONS
+                .iter()
+                .map(|&(name, id)| Extension {
+                    name: name.to_string(),
+                    id,
+                    handler: None,
+                })
+                .collect(),
+            listen_port: None,
+            metadata_size: None,
+        }
+    }
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Advertises `port` as where we accept connections (`p`).
+    pub fn with_listen_port(mut self, port: u16) -> Self {
+        self.listen_port = Some(port);
+        self
+    }
+
+    /// Advertises that we serve an info dictionary of `size` bytes over
+    /// `ut_metadata` (`metadata_size`).
+    pub fn with_metadata_size(mut self, size: usize) -> Self {
+        self.metadata_size = Some(size);
+        self
+    }
+
+    /// Sends messages for `name` to `handler`, returning the id peers are
+    /// told to use for it. Registering a name again replaces its handler.
+    /// Returns `None` once all 255 ids are taken.
+    pub fn register(&mut self, name: &str, handler: Arc<dyn ExtensionHandler>) -> Option<u8> {
+        if let Some(extension) = self.extensions.iter_mut().find(|ext| ext.name == name) {
+            extension.handler = Some(handler);
+            return Some(extension.id);
+        }
+        // Id 0 is the extended handshake itself
+        let id = (1..=u8::MAX).find(|&id| self.get(id).is_none())?;
+        self.extensions.push(Extension {
+            name: name.to_string(),
+            id,
+            handler: Some(handler),
+        });
+        Some(id)
+    }
+
+    /// Stops offering `name`, built-in or not. Returns whether it was
+    /// offered.
+    pub fn unregister(&mut self, name: &str) -> bool {
+        let before = self.extensions.len();
+        self.extensions.retain(|ext| ext.name != name);
+        self.extensions.len() != before
+    }
+
+    /// The id we receive `name` on.
+    pub fn id(&self, name: &str) -> Option<u8> {
+        self.extensions
+            .iter()
+            .find(|ext| ext.name == name)
+            .map(|ext| ext.id)
+    }
+
+    fn get(&self, id: u8) -> Option<&Extension> {
+        self.extensions.iter().find(|ext| ext.id == id)
+    }
+
+    fn handshake_map(&self) -> BTreeMap<String, u8> {
+        self.extensions
+            .iter()
+            .map(|ext| (ext.name.clone(), ext.id))
+            .collect()
+    }
+}
+
+/// What we tell a peer about ourselves when connecting to it.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    /// Extensions offered in the extended handshake.
+    pub extensions: Arc<ExtensionRegistry>,
+    /// Our pieces, sent before anything else so the peer can request from
+    /// us. Needs `num_pieces`.
+    pub bitfield: Option<Vec<u8>>,
+    /// The torrent's piece count, if we know it.
+    pub num_pieces: Option<usize>,
+}
+
+/// The peer's answer to one of our block requests.
+#[derive(Debug)]
+pub enum BlockResponse {
+    Block(BlockRequest, Vec<u8>),
+    /// The peer won't send the block (BEP 6).
+    Rejected(BlockRequest),
+}
+
+/// The peer's answer to one of our hash requests (BEP 52).
+#[derive(Debug, Clone)]
+pub enum HashResponse {
+    /// The requested hashes, followed by the uncle hashes proving them.
+    Hashes(HashRequest, Vec<[u8; 32]>),
+    Rejected(HashRequest),
+}
+
 #[derive(Debug)]
 pub struct Peer {
     addr: SocketAddrV4,
-    stream: Framed<TcpStream, PeerCodec>,
+    stream: Framed<Throttled<TcpStream>, PeerCodec>,
     bitfield: Vec<u8>,
+    // Set by `HaveAll`, standing in for a full bitfield
+    has_all: bool,
+    // The torrent's piece count, bounding the bitfield and `Have` indices
+    num_pieces: Option<usize>,
     am_choking: bool,
     am_interested: bool,
     peer_choking: bool,
     peer_interested: bool,
+    // Block requests the peer has sent us that haven't been served yet
+    pending_requests: VecDeque<BlockRequest>,
+    // Block requests we sent that haven't been answered yet, oldest first
+    requested: Vec<BlockRequest>,
+    pipeline: RequestPipeline,
+    // Set from the reserved bits of the peer's handshake
+    supports_extensions: bool,
+    // Both sides set the BEP 6 bit
+    supports_fast: bool,
+    // The peer set the BitTorrent v2 bit
+    supports_v2: bool,
+    // Pieces the peer suggested we download, oldest first
+    suggested: VecDeque<usize>,
+    // Pieces the peer lets us request while it chokes us
+    allowed_fast: HashSet<u32>,
+    // Pieces we let the peer request while we choke it
+    allowed_fast_sent: HashSet<u32>,
+    // Extensions we offer and dispatch messages for
+    extensions: Arc<ExtensionRegistry>,
+    // The peer's BEP 10 handshake, once received
+    remote_extensions: Option<ExtendedHandshake>,
+    // Extension messages (by our local id) waiting to be taken
+    extended_messages: VecDeque<(u8, Vec<u8>)>,
+    // Hash requests the peer has sent us that haven't been answered yet
+    hash_requests: VecDeque<HashRequest>,
+    // Answers to our hash requests waiting to be taken
+    hash_responses: VecDeque<HashResponse>,
 }
 
 impl Peer {
@@ -91,6 +392,30 @@ impl Peer {
         addr: SocketAddrV4,
         info_hash: [u8; 20],
         peer_id: [u8; 20],
+    ) -> Result<Self> {
+        Self::connect_with_extensions(addr, info_hash, peer_id, Arc::default()).await
+    }
+
+    /// Like `connect`, offering the extensions in `extensions`.
+    pub async fn connect_with_extensions(
+        addr: SocketAddrV4,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+        extensions: Arc<ExtensionRegistry>,
+    ) -> Result<Self> {
+        let options = ConnectOptions {
+            extensions,
+            ..Default::default()
+        };
+        Self::connect_with_options(addr, info_hash, peer_id, options).await
+    }
+
+    /// Like `connect`, telling the peer what `options` holds.
+    pub async fn connect_with_options(
+        addr: SocketAddrV4,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+        options: ConnectOptions,
     ) -> Result<Self> {
         println!("Initiating connection to peer: {}", addr);
         let mut stream = TcpStream::connect(addr).await?;
@@ -106,44 +431,295 @@ impl Peer {
         println!("Successful handshake: {}", addr);
 
         // Now switch to message protocol
-        let mut framed = Framed::new(stream, PeerCodec::new());
+        let framed = Framed::new(Throttled::new(stream), PeerCodec::new());
+        let mut peer = Self::from_framed(addr, framed, &received);
+        peer.num_pieces = options.num_pieces;
+        if let (Some(bitfield), Some(num_pieces)) = (options.bitfield, options.num_pieces) {
+            peer.send_bitfield(bitfield, num_pieces).await?;
+        }
+        peer.extensions = options.extensions;
+        peer.send_extended_handshake().await?;
 
-        // Wait for bitfield message
-        let bitfield_msg = framed
-            .next()
-            .await
-            .ok_or_else(|| BitTorrentError::Peer("Peer disconnected before bitfield".into()))??;
+        // Wait for the first message, normally the bitfield. Some peers
+        // send none, or lead with their extended handshake instead.
+        let first =
+            peer.stream.next().await.ok_or_else(|| {
+                BitTorrentError::Peer("Peer disconnected before bitfield".into())
+            })??;
+        peer.handle_message(first).await?;
 
-        let bitfield = if bitfield_msg.id == MessageId::Bitfield {
-            bitfield_msg.payload
-        } else {
-            Vec::new() // Some peers might not send a bitfield
-        };
+        Ok(peer)
+    }
 
-        Ok(Self {
+    /// Completes the handshake for an inbound connection. The remote side
+    /// speaks first, so its info-hash must be one of `info_hashes` before we
+    /// answer with our own handshake. Returns the peer and the info-hash it
+    /// asked for.
+    pub async fn accept(
+        mut stream: TcpStream,
+        addr: SocketAddrV4,
+        peer_id: [u8; 20],
+        info_hashes: &[[u8; 20]],
+    ) -> Result<(Self, [u8; 20])> {
+        let received = Handshake::read_from(&mut stream).await?;
+        if !info_hashes.contains(&received.info_hash) {
+            return Err(BitTorrentError::Protocol("Unknown info hash".into()));
+        }
+
+        let handshake = Handshake::new(received.info_hash, peer_id);
+        handshake.write_to(&mut stream).await?;
+        println!("Accepted handshake from peer: {}", addr);
+
+        let framed = Framed::new(Throttled::new(stream), PeerCodec::new());
+        Ok((
+            Self::from_framed(addr, framed, &received),
+            received.info_hash,
+        ))
+    }
+
+    fn from_framed(
+        addr: SocketAddrV4,
+        stream: Framed<Throttled<TcpStream>, PeerCodec>,
+        handshake: &Handshake,
+    ) -> Self {
+        Self {
             addr,
-            stream: framed,
-            bitfield,
+            stream,
+            bitfield: Vec::new(),
+            has_all: false,
+            num_pieces: None,
             am_choking: true,
             am_interested: false,
             peer_choking: true,
             peer_interested: false,
-        })
+            pending_requests: VecDeque::new(),
+            requested: Vec::new(),
+            pipeline: RequestPipeline::new(PipelineConfig::default()),
+            supports_extensions: handshake.supports_extensions(),
+            // Our own handshake always sets the bit
+            supports_fast: handshake.supports_fast(),
+            supports_v2: handshake.supports_v2(),
+            suggested: VecDeque::new(),
+            allowed_fast: HashSet::new(),
+            allowed_fast_sent: HashSet::new(),
+            extensions: Arc::default(),
+            remote_extensions: None,
+            extended_messages: VecDeque::new(),
+            hash_requests: VecDeque::new(),
+            hash_responses: VecDeque::new(),
+        }
+    }
+
+    /// Sets the torrent's piece count, if `connect_with_options` wasn't told
+    /// it. From then on a
+    /// bitfield of the wrong size or a `Have` beyond the last piece is a
+    /// protocol error; before, `Have`s outside the bitfield are ignored.
+    pub fn set_num_pieces(&mut self, num_pieces: usize) -> Result<()> {
+        if !self.bitfield.is_empty() && self.bitfield.len() != num_pieces.div_ceil(8) {
+            return Err(BitTorrentError::Protocol(
+                "Bitfield length doesn't match the torrent".into(),
+            ));
+        }
+        self.num_pieces = Some(num_pieces);
+        Ok(())
+    }
+
+    pub async fn send_message(&mut self, message: Message) -> Result<()> {
+        self.stream.send(message).await
     }
 
-    pub async fn request_piece(&mut self, piece: &PieceInfo) -> Result<Vec<u8>> {
-        println!(
-            "Requesting piece {} ({} bytes) from peer {}",
-            piece.index(),
-            piece.length(),
-            self.addr
+    /// Sends our pieces, as `HaveAll` or `HaveNone` when the peer supports
+    /// them and they fit.
+    pub async fn send_bitfield(&mut self, bitfield: Vec<u8>, num_pieces: usize) -> Result<()> {
+        let message = if self.supports_fast && (0..num_pieces).all(|i| bit_set(&bitfield, i)) {
+            Message::new(MessageId::HaveAll, Vec::new())
+        } else if self.supports_fast && bitfield.iter().all(|&byte| byte == 0) {
+            Message::new(MessageId::HaveNone, Vec::new())
+        } else {
+            Message::new(MessageId::Bitfield, bitfield)
+        };
+        self.send_message(message).await
+    }
+
+    /// Lets the peer request `pieces` even while we choke it. Does nothing
+    /// unless it supports the Fast extension.
+    pub async fn send_allowed_fast(&mut self, pieces: &[u32]) -> Result<()> {
+        if !self.supports_fast {
+            return Ok(());
+        }
+        for &index in pieces {
+            if self.allowed_fast_sent.insert(index) {
+                self.send_message(Message::new(
+                    MessageId::AllowedFast,
+                    index.to_be_bytes().to_vec(),
+                ))
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells the peer we won't serve `request`. Without the Fast extension
+    /// there is no such message, and the request is just dropped.
+    pub async fn reject_request(&mut self, request: BlockRequest) -> Result<()> {
+        if !self.supports_fast {
+            return Ok(());
+        }
+        self.send_message(Message::new(MessageId::Reject, request.to_payload()))
+            .await
+    }
+
+    pub async fn send_have(&mut self, index: usize) -> Result<()> {
+        let payload = (index as u32).to_be_bytes().to_vec();
+        self.send_message(Message::new(MessageId::Have, payload))
+            .await
+    }
+
+    /// Tells the peer whether we want pieces from it, if that changed.
+    pub async fn set_interested(&mut self, interested: bool) -> Result<()> {
+        if self.am_interested == interested {
+            return Ok(());
+        }
+        let id = if interested {
+            MessageId::Interested
+        } else {
+            MessageId::NotInterested
+        };
+        self.send_message(Message::new(id, Vec::new())).await?;
+        self.am_interested = interested;
+        Ok(())
+    }
+
+    pub async fn set_choking(&mut self, choking: bool) -> Result<()> {
+        if self.am_choking == choking {
+            return Ok(());
+        }
+        let id = if choking {
+            MessageId::Choke
+        } else {
+            MessageId::Unchoke
+        };
+        self.send_message(Message::new(id, Vec::new())).await?;
+        self.am_choking = choking;
+
+        // Choking discards everything the peer has asked for so far, except
+        // allowed-fast pieces; with BEP 6 each request is rejected explicitly
+        if choking {
+            let (kept, dropped): (VecDeque<_>, VecDeque<_>) = self
+                .pending_requests
+                .drain(..)
+                .partition(|request| self.allowed_fast_sent.contains(&request.index));
+            self.pending_requests = kept;
+            for request in dropped {
+                self.reject_request(request).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn send_block(&mut self, request: BlockRequest, data: &[u8]) -> Result<()> {
+        let mut payload = Vec::with_capacity(8 + data.len());
+        payload.put_u32(request.index);
+        payload.put_u32(request.begin);
+        payload.extend_from_slice(data);
+        self.send_message(Message::new(MessageId::Piece, payload))
+            .await
+    }
+
+    /// Sends our BEP 10 handshake, if the peer supports the extension
+    /// protocol. Must follow the bitfield, if one is sent.
+    pub async fn send_extended_handshake(&mut self) -> Result<()> {
+        if !self.supports_extensions {
+            return Ok(());
+        }
+        let handshake = ExtendedHandshake {
+            m: self.extensions.handshake_map(),
+            metadata_size: self.extensions.metadata_size,
+            p: self.extensions.listen_port,
+            v: Some(ByteBuf::from(CLIENT_VERSION)),
+            reqq: Some(MAX_QUEUED_REQUESTS as u32),
+            yourip: Some(ByteBuf::from(self.addr.ip().octets().to_vec())),
+        };
+        let mut payload = vec![EXTENDED_HANDSHAKE_ID];
+        payload.extend(
+            serde_bencode::to_bytes(&handshake)
+                .map_err(|e| BitTorrentError::Protocol(e.to_string()))?,
         );
+        self.send_message(Message::new(MessageId::Extended, payload))
+            .await
+    }
+
+    /// Sends an extension message under the id the peer assigned to `name`.
+    pub async fn send_extended(&mut self, name: &str, payload: &[u8]) -> Result<()> {
+        let id = self
+            .extension_id(name)
+            .ok_or_else(|| BitTorrentError::Peer(format!("Peer does not support {}", name)))?;
+        let mut message = Vec::with_capacity(1 + payload.len());
+        message.push(id);
+        message.extend_from_slice(payload);
+        self.send_message(Message::new(MessageId::Extended, message))
+            .await
+    }
+
+    /// Drains the extension messages received since the last call, as
+    /// (our local extension id, payload) pairs.
+    pub fn take_extended_messages(&mut self) -> Vec<(u8, Vec<u8>)> {
+        self.extended_messages.drain(..).collect()
+    }
 
-        if !self.has_piece(piece.index()) {
-            return Err(BitTorrentError::Peer("Peer doesn't have piece".into()));
+    /// Reads and handles the next message, returning its id, or `None` once
+    /// the peer has closed the connection.
+    pub async fn recv(&mut self) -> Result<Option<MessageId>> {
+        match self.stream.next().await {
+            Some(Ok(message)) => {
+                let id = message.id;
+                self.handle_message(message).await?;
+                Ok(Some(id))
+            }
+            Some(Err(e)) => Err(e),
+            None => Ok(None),
         }
+    }
+
+    /// Drains the block requests received since the last call.
+    pub fn take_requests(&mut self) -> Vec<BlockRequest> {
+        self.pending_requests.drain(..).collect()
+    }
+
+    /// Asks for a range of merkle tree hashes of a v2 file.
+    pub async fn send_hash_request(&mut self, request: HashRequest) -> Result<()> {
+        self.send_message(Message::new(MessageId::HashRequest, request.to_payload()))
+            .await
+    }
+
+    /// Answers `request` with the hashes it asked for and their proof.
+    pub async fn send_hashes(&mut self, request: HashRequest, hashes: &[[u8; 32]]) -> Result<()> {
+        let mut payload = request.to_payload();
+        for hash in hashes {
+            payload.extend_from_slice(hash);
+        }
+        self.send_message(Message::new(MessageId::Hashes, payload))
+            .await
+    }
+
+    pub async fn reject_hash_request(&mut self, request: HashRequest) -> Result<()> {
+        self.send_message(Message::new(MessageId::HashReject, request.to_payload()))
+            .await
+    }
+
+    /// Drains the hash requests received since the last call.
+    pub fn take_hash_requests(&mut self) -> Vec<HashRequest> {
+        self.hash_requests.drain(..).collect()
+    }
+
+    /// Drains the answers to our hash requests received since the last call.
+    pub fn take_hash_responses(&mut self) -> Vec<HashResponse> {
+        self.hash_responses.drain(..).collect()
+    }
 
-        // Try to get unchoked with timeout
+    /// Tells the peer we're interested, if we haven't yet, and waits until
+    /// it unchokes us.
+    pub async fn wait_for_unchoke(&mut self) -> Result<()> {
         if !self.am_interested || self.peer_choking {
             if !self.am_interested {
                 self.stream
@@ -197,33 +773,66 @@ impl Peer {
             }
         }
 
-        let mut data = Vec::with_capacity(piece.length());
-        let mut offset = 0;
-        let mut retries = 0;
-        const MAX_BLOCK_RETRIES: u32 = 3;
+        Ok(())
+    }
 
-        while offset < piece.length() {
-            let block_size = std::cmp::min(BLOCK_SIZE, piece.length() - offset);
+    /// Whether the pipeline has room for another block request.
+    pub fn can_request(&self) -> bool {
+        self.requested.len() < self.pipeline_depth()
+    }
 
-            // Send block request
-            let mut request = vec![0u8; 12];
-            (&mut request[0..4]).put_u32(piece.index() as u32);
-            (&mut request[4..8]).put_u32(offset as u32);
-            (&mut request[8..12]).put_u32(block_size as u32);
+    /// Block requests sent to the peer that it hasn't answered yet.
+    pub fn requested_blocks(&self) -> &[BlockRequest] {
+        &self.requested
+    }
 
-            let block_result = timeout(
-                Duration::from_secs(30),
-                self.request_block(request.clone(), piece.index(), offset),
-            )
-            .await;
+    pub async fn request_block(&mut self, request: BlockRequest) -> Result<()> {
+        self.send_message(Message::new(MessageId::Request, request.to_payload()))
+            .await?;
+        self.requested.push(request);
+        Ok(())
+    }
+
+    /// Withdraws an outstanding request with `Cancel`. Does nothing if the
+    /// request was already answered.
+    pub async fn cancel_block(&mut self, request: BlockRequest) -> Result<()> {
+        let Some(position) = self.requested.iter().position(|sent| *sent == request) else {
+            return Ok(());
+        };
+        self.requested.remove(position);
+        self.send_message(Message::new(MessageId::Cancel, request.to_payload()))
+            .await
+    }
 
-            match block_result {
-                Ok(Ok(block_data)) => {
-                    data.extend_from_slice(&block_data);
-                    offset += block_size;
-                    retries = 0;
+    /// Waits for the next block answering one of our requests. A rejected
+    /// request is an error; use `next_response` to handle rejects.
+    pub async fn next_block(&mut self) -> Result<(BlockRequest, Vec<u8>)> {
+        match self.next_response().await? {
+            BlockResponse::Block(request, block) => Ok((request, block)),
+            BlockResponse::Rejected(request) => Err(BitTorrentError::Peer(format!(
+                "Peer rejected request for {:?}",
+                request
+            ))),
+        }
+    }
+
+    /// Waits for the peer to answer one of our requests, with a block or
+    /// (BEP 6) a reject, handling other messages on the way. Outstanding
+    /// requests are re-sent when nothing arrives in time. If the peer chokes
+    /// us it discards our requests, so they are dropped here too and an
+    /// error returned.
+    pub async fn next_response(&mut self) -> Result<BlockResponse> {
+        let mut retries = 0;
+
+        loop {
+            let msg = match timeout(BLOCK_TIMEOUT, self.stream.next()).await {
+                Ok(Some(msg)) => msg?,
+                Ok(None) => {
+                    return Err(BitTorrentError::Peer(
+                        "Peer disconnected during block transfer".into(),
+                    ))
                 }
-                _ => {
+                Err(_) => {
                     retries += 1;
                     if retries >= MAX_BLOCK_RETRIES {
                         return Err(BitTorrentError::Peer(format!(
@@ -231,69 +840,87 @@ impl Peer {
                             MAX_BLOCK_RETRIES
                         )));
                     }
-                    // Small delay before retry
-                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    // Re-send everything still outstanding
+                    self.pipeline.reset();
+                    for request in self.requested.clone() {
+                        self.send_message(Message::new(MessageId::Request, request.to_payload()))
+                            .await?;
+                    }
                     continue;
                 }
-            }
-        }
-
-        Ok(data)
-    }
+            };
 
-    async fn request_block(
-        &mut self,
-        request: Vec<u8>,
-        expected_index: usize,
-        expected_begin: usize,
-    ) -> Result<Vec<u8>> {
-        self.stream
-            .send(Message::new(MessageId::Request, request))
-            .await?;
-
-        // Wait for piece data
-        while let Some(msg) = self.stream.next().await {
-            match msg {
-                Ok(msg) => match msg.id {
-                    MessageId::Piece => {
-                        if msg.payload.len() < 8 {
-                            continue;
-                        }
-
-                        let index = (&msg.payload[0..4]).get_u32() as usize;
-                        let begin = (&msg.payload[4..8]).get_u32() as usize;
-
-                        if index != expected_index || begin != expected_begin {
-                            continue;
-                        }
-
-                        return Ok(msg.payload[8..].to_vec());
+            match msg.id {
+                MessageId::Piece => {
+                    if msg.payload.len() < 8 {
+                        continue;
                     }
-                    MessageId::Choke => {
-                        self.peer_choking = true;
-                        return Err(BitTorrentError::Peer(
-                            "Peer choked us during transfer".into(),
-                        ));
-                    }
-                    _ => {
-                        self.handle_message(msg).await?;
+                    let block_index = (&msg.payload[0..4]).get_u32();
+                    let begin = (&msg.payload[4..8]).get_u32();
+                    let mut payload = msg.payload;
+                    let block = payload.split_off(8);
+
+                    // Stale or unrequested blocks are dropped
+                    let Some(position) = self.requested.iter().position(|request| {
+                        request.index == block_index
+                            && request.begin == begin
+                            && request.length as usize == block.len()
+                    }) else {
+                        continue;
+                    };
+                    let request = self.requested.remove(position);
+                    self.pipeline.record(block.len());
+                    return Ok(BlockResponse::Block(request, block));
+                }
+                MessageId::Reject => {
+                    self.check_fast(msg.id)?;
+                    let request = BlockRequest::from_payload(&msg.payload).ok_or_else(|| {
+                        BitTorrentError::Protocol("Invalid reject message".into())
+                    })?;
+                    if let Some(position) = self.requested.iter().position(|sent| *sent == request)
+                    {
+                        self.requested.remove(position);
+                        return Ok(BlockResponse::Rejected(request));
                     }
-                },
-                Err(e) => return Err(e),
+                }
+                MessageId::Choke => {
+                    self.peer_choking = true;
+                    self.requested.clear();
+                    return Err(BitTorrentError::Peer(
+                        "Peer choked us during transfer".into(),
+                    ));
+                }
+                _ => {
+                    self.handle_message(msg).await?;
+                }
             }
         }
+    }
 
-        Err(BitTorrentError::Peer(
-            "Peer disconnected during block transfer".into(),
-        ))
+    /// Limits the connection's bandwidth from here on; every limiter in
+    /// `limits` applies. Handshakes already exchanged aren't counted.
+    pub fn set_rate_limits(&mut self, limits: Vec<RateLimits>) {
+        self.stream.get_mut().set_limits(limits);
+    }
+
+    pub fn set_pipeline_config(&mut self, config: PipelineConfig) {
+        self.pipeline = RequestPipeline::new(config);
+    }
+
+    /// Number of block requests currently allowed in flight to this peer.
+    /// Never more than the peer said it queues (`reqq`).
+    pub fn pipeline_depth(&self) -> usize {
+        self.pipeline.depth().min(self.reqq().unwrap_or(usize::MAX))
     }
 
     pub fn has_piece(&self, index: usize) -> bool {
-        bit_set(&self.bitfield, index)
+        self.has_all || bit_set(&self.bitfield, index)
     }
 
+    /// The peer's bitfield, or `None` if it sent none or sent `HaveAll`
+    /// instead; use `has_piece` to cover both.
     pub fn get_bitfield(&self) -> Option<&[u8]> {
-        if self.bitfield.is_empty() {
+        if self.has_all || self.bitfield.is_empty() {
             None
         } else {
             Some(&self.bitfield)
@@ -301,49 +928,318 @@ impl Peer {
     }
 
     async fn handle_message(&mut self, message: Message) -> Result<()> {
+        self.check_fast(message.id)?;
         match message.id {
             MessageId::Choke => self.peer_choking = true,
             MessageId::Unchoke => self.peer_choking = false,
             MessageId::Interested => self.peer_interested = true,
             MessageId::NotInterested => self.peer_interested = false,
             MessageId::Have => {
-                if message.payload.len() != 4 {
-                    return Err(BitTorrentError::Protocol("Invalid have message".into()));
-                }
-                let piece_index = (&message.payload[0..4]).get_u32() as usize;
-                if piece_index / 8 < self.bitfield.len() {
-                    self.bitfield[piece_index / 8] |= 1 << (7 - (piece_index % 8));
+                let index = Self::piece_index(&message.payload, "have")? as usize;
+                if let Some(num_pieces) = self.num_pieces {
+                    if index >= num_pieces {
+                        return Err(BitTorrentError::Protocol(format!(
+                            "Have for piece {} of {}",
+                            index, num_pieces
+                        )));
+                    }
+                    // Peers that started with `HaveNone`, or nothing, have
+                    // no bitfield to set the bit in yet
+                    if self.bitfield.is_empty() {
+                        self.bitfield = vec![0; num_pieces.div_ceil(8)];
+                    }
                 }
+                set_bit(&mut self.bitfield, index);
             }
             MessageId::Bitfield => {
-                if !self.bitfield.is_empty() {
+                if self.has_all || !self.bitfield.is_empty() {
                     return Err(BitTorrentError::Protocol(
                         "Received duplicate bitfield".into(),
                     ));
                 }
+                let expected = self.num_pieces.map(|n| n.div_ceil(8));
+                if expected.is_some_and(|len| len != message.payload.len()) {
+                    return Err(BitTorrentError::Protocol(
+                        "Bitfield length doesn't match the torrent".into(),
+                    ));
+                }
                 self.bitfield = message.payload;
             }
+            MessageId::HaveAll => {
+                self.has_all = true;
+                self.bitfield.clear();
+            }
+            MessageId::HaveNone => {
+                self.has_all = false;
+                self.bitfield.clear();
+            }
+            MessageId::Request => {
+                let request = BlockRequest::from_payload(&message.payload)
+                    .ok_or_else(|| BitTorrentError::Protocol("Invalid request message".into()))?;
+                // Requests sent while choked are dropped per the spec, or
+                // rejected under BEP 6, unless the piece is allowed fast
+                let allowed = !self.am_choking || self.allowed_fast_sent.contains(&request.index);
+                if allowed && self.pending_requests.len() < MAX_QUEUED_REQUESTS {
+                    self.pending_requests.push_back(request);
+                } else {
+                    self.reject_request(request).await?;
+                }
+            }
+            MessageId::Suggest => {
+                let index = Self::piece_index(&message.payload, "suggest")?;
+                if self.suggested.len() >= MAX_SUGGESTED {
+                    self.suggested.pop_front();
+                }
+                self.suggested.push_back(index as usize);
+            }
+            MessageId::AllowedFast => {
+                let index = Self::piece_index(&message.payload, "allowed fast")?;
+                self.allowed_fast.insert(index);
+            }
+            MessageId::Reject => {
+                // Answers outside `next_response` leave nothing outstanding
+                if let Some(request) = BlockRequest::from_payload(&message.payload) {
+                    self.requested.retain(|sent| *sent != request);
+                }
+            }
+            MessageId::Cancel => {
+                if let Some(request) = BlockRequest::from_payload(&message.payload) {
+                    self.pending_requests.retain(|pending| *pending != request);
+                }
+            }
+            MessageId::HashRequest => {
+                let request = HashRequest::from_payload(&message.payload).ok_or_else(|| {
+                    BitTorrentError::Protocol("Invalid hash request message".into())
+                })?;
+                if self.hash_requests.len() < MAX_QUEUED_HASHES {
+                    self.hash_requests.push_back(request);
+                } else {
+                    self.reject_hash_request(request).await?;
+                }
+            }
+            MessageId::Hashes => {
+                let invalid = || BitTorrentError::Protocol("Invalid hashes message".into());
+                if message.payload.len() < HashRequest::PAYLOAD_LEN {
+                    return Err(invalid());
+                }
+                let (header, hashes) = message.payload.split_at(HashRequest::PAYLOAD_LEN);
+                let request = HashRequest::from_payload(header).ok_or_else(invalid)?;
+                if !hashes.len().is_multiple_of(32) {
+                    return Err(invalid());
+                }
+                let hashes = hashes
+                    .chunks_exact(32)
+                    .map(|hash| hash.try_into().expect("chunk of 32 bytes"))
+                    .collect();
+                if self.hash_responses.len() < MAX_QUEUED_HASHES {
+                    self.hash_responses
+                        .push_back(HashResponse::Hashes(request, hashes));
+                }
+            }
+            MessageId::HashReject => {
+                let request = HashRequest::from_payload(&message.payload).ok_or_else(|| {
+                    BitTorrentError::Protocol("Invalid hash reject message".into())
+                })?;
+                if self.hash_responses.len() < MAX_QUEUED_HASHES {
+                    self.hash_responses
+                        .push_back(HashResponse::Rejected(request));
+                }
+            }
+            MessageId::Extended => {
+                let Some((&id, payload)) = message.payload.split_first() else {
+                    return Err(BitTorrentError::Protocol("Empty extended message".into()));
+                };
+                if id == EXTENDED_HANDSHAKE_ID {
+                    let handshake: ExtendedHandshake =
+                        serde_bencode::from_bytes(payload).map_err(|e| {
+                            BitTorrentError::Protocol(format!("Invalid extended handshake: {}", e))
+                        })?;
+                    for extension in &self.extensions.extensions {
+                        let supported = handshake.m.get(&extension.name).is_some_and(|&id| id != 0);
+                        if let (true, Some(handler)) = (supported, &extension.handler) {
+                            handler.on_handshake(self.addr, &handshake);
+                        }
+                    }
+                    self.remote_extensions = Some(handshake);
+                } else {
+                    // Messages on ids we never offered are ignored
+                    match self.extensions.get(id).map(|ext| ext.handler.clone()) {
+                        Some(Some(handler)) => handler.on_message(self.addr, payload)?,
+                        Some(None) if self.extended_messages.len() < MAX_QUEUED_EXTENDED => {
+                            self.extended_messages.push_back((id, payload.to_vec()));
+                        }
+                        _ => {}
+                    }
+                }
+            }
             // Ignore other messages
             _ => {}
         }
         Ok(())
     }
 
+    // BEP 6 has the connection closed on Fast messages from a peer that
+    // didn't negotiate the extension
+    fn check_fast(&self, id: MessageId) -> Result<()> {
+        if id.is_fast() && !self.supports_fast {
+            return Err(BitTorrentError::Protocol(format!(
+                "Received {:?} without the Fast extension",
+                id
+            )));
+        }
+        Ok(())
+    }
+
+    fn piece_index(payload: &[u8], name: &str) -> Result<u32> {
+        match payload.try_into() {
+            Ok(bytes) => Ok(u32::from_be_bytes(bytes)),
+            Err(_) => Err(BitTorrentError::Protocol(format!(
+                "Invalid {} message",
+                name
+            ))),
+        }
+    }
+
     pub fn addr(&self) -> SocketAddrV4 {
         self.addr
     }
+
+    /// Whether both sides support the Fast extension (BEP 6).
+    pub fn supports_fast(&self) -> bool {
+        self.supports_fast
+    }
+
+    /// Whether the peer supports BitTorrent v2 and answers hash requests.
+    pub fn supports_v2(&self) -> bool {
+        self.supports_v2
+    }
+
+    /// Drains the pieces the peer suggested since the last call.
+    pub fn take_suggested(&mut self) -> Vec<usize> {
+        self.suggested.drain(..).collect()
+    }
+
+    /// Pieces the peer lets us request while it chokes us.
+    pub fn allowed_fast(&self) -> &HashSet<u32> {
+        &self.allowed_fast
+    }
+
+    pub fn peer_choking(&self) -> bool {
+        self.peer_choking
+    }
+
+    pub fn am_choking(&self) -> bool {
+        self.am_choking
+    }
+
+    pub fn peer_interested(&self) -> bool {
+        self.peer_interested
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.supports_extensions
+    }
+
+    /// Offers the extensions in `extensions` instead of the built-in ones.
+    /// Call before sending the extended handshake.
+    pub fn set_extensions(&mut self, extensions: Arc<ExtensionRegistry>) {
+        self.extensions = extensions;
+    }
+
+    /// Whether we offer `name` to the peer.
+    pub fn offers_extension(&self, name: &str) -> bool {
+        self.extensions.id(name).is_some()
+    }
+
+    /// Whether the peer's BEP 10 handshake has arrived.
+    pub fn has_extended_handshake(&self) -> bool {
+        self.remote_extensions.is_some()
+    }
+
+    /// The message id the peer wants `name` sent on, if it supports it.
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.remote_extensions
+            .as_ref()
+            .and_then(|handshake| handshake.m.get(name).copied())
+            .filter(|&id| id != 0)
+    }
+
+    /// Port the peer accepts connections on, as advertised in its handshake.
+    pub fn listen_port(&self) -> Option<u16> {
+        self.remote_extensions
+            .as_ref()
+            .and_then(|handshake| handshake.p)
+            .filter(|&port| port != 0)
+    }
+
+    /// The client name and version the peer gave in its handshake.
+    pub fn client_version(&self) -> Option<String> {
+        self.remote_extensions
+            .as_ref()
+            .and_then(|handshake| handshake.v.as_ref())
+            .map(|v| String::from_utf8_lossy(v).into_owned())
+    }
+
+    /// How many requests the peer queues, as advertised in its handshake.
+    pub fn reqq(&self) -> Option<usize> {
+        self.remote_extensions
+            .as_ref()
+            .and_then(|handshake| handshake.reqq)
+            .filter(|&reqq| reqq != 0)
+            .map(|reqq| reqq as usize)
+    }
+
+    /// Our IP address as the peer sees it, if it told us.
+    pub fn your_ip(&self) -> Option<Ipv4Addr> {
+        let yourip = self.remote_extensions.as_ref()?.yourip.as_ref()?;
+        let octets: [u8; 4] = yourip.as_slice().try_into().ok()?;
+        Some(Ipv4Addr::from(octets))
+    }
+
+    /// Size of the info dictionary, as advertised in the peer's handshake.
+    pub fn metadata_size(&self) -> Option<usize> {
+        self.remote_extensions
+            .as_ref()
+            .and_then(|handshake| handshake.metadata_size)
+    }
 }
 
-#[derive(Debug)]
-pub struct PeerCodec {
-    partial_msg: BytesMut,
+/// The BEP 6 allowed-fast set for a peer at `ip`: `count` piece indices
+/// derived from its /24 network and the info-hash.
+pub fn allowed_fast_set(
+    ip: Ipv4Addr,
+    info_hash: &[u8; 20],
+    num_pieces: usize,
+    count: usize,
+) -> Vec<u32> {
+    let count = count.min(num_pieces);
+    let mut pieces = Vec::with_capacity(count);
+
+    let mut x = Vec::with_capacity(24);
+    x.extend_from_slice(&(u32::from(ip) & 0xFFFF_FF00).to_be_bytes());
+    x.extend_from_slice(info_hash);
+    while pieces.len() < count {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if pieces.len() >= count {
+                break;
+            }
+            let y = u32::from_be_bytes(chunk.try_into().expect("chunk of 4 bytes"));
+            let index = y % num_pieces as u32;
+            if !pieces.contains(&index) {
+                pieces.push(index);
+            }
+        }
+    }
+    pieces
 }
 
+#[derive(Debug, Default)]
+pub struct PeerCodec;
+
 impl PeerCodec {
     pub fn new() -> Self {
-        Self {
-            partial_msg: BytesMut::new(),
-        }
+        Self
     }
 }
 
@@ -352,26 +1248,27 @@ impl Decoder for PeerCodec {
     type Error = BitTorrentError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
-        // Normal messages start with 4-byte length
-        if src.len() < 4 {
-            return Ok(None);
-        }
-
-        let mut length_bytes = [0u8; 4];
-        length_bytes.copy_from_slice(&src[..4]);
-        let length = u32::from_be_bytes(length_bytes) as usize;
+        // Skip keepalives; a message may already be buffered behind them
+        let length = loop {
+            // Normal messages start with 4-byte length
+            if src.len() < 4 {
+                return Ok(None);
+            }
 
-        // Check if we have the full message
-        if src.len() < 4 + length {
-            return Ok(None);
-        }
+            let mut length_bytes = [0u8; 4];
+            length_bytes.copy_from_slice(&src[..4]);
+            let length = u32::from_be_bytes(length_bytes) as usize;
 
-        src.advance(4); // Consume length
+            // Check if we have the full message
+            if src.len() < 4 + length {
+                return Ok(None);
+            }
 
-        // Handle keepalive message
-        if length == 0 {
-            return Ok(None);
-        }
+            src.advance(4); // Consume length
+            if length > 0 {
+                break length;
+            }
+        };
 
         let msg_type = src[0];
         src.advance(1);
@@ -386,6 +1283,15 @@ impl Decoder for PeerCodec {
             6 => MessageId::Request,
             7 => MessageId::Piece,
             8 => MessageId::Cancel,
+            13 => MessageId::Suggest,
+            14 => MessageId::HaveAll,
+            15 => MessageId::HaveNone,
+            16 => MessageId::Reject,
+            17 => MessageId::AllowedFast,
+            20 => MessageId::Extended,
+            21 => MessageId::HashRequest,
+            22 => MessageId::Hashes,
+            23 => MessageId::HashReject,
             n => {
                 return Err(BitTorrentError::Protocol(format!(
                     "Unknown message type: {}",
@@ -415,3 +1321,452 @@ impl Encoder<Message> for PeerCodec {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::piece::PieceManager;
+    use tokio::net::TcpListener;
+
+    async fn local_listener() -> (TcpListener, SocketAddrV4) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = match listener.local_addr().unwrap() {
+            std::net::SocketAddr::V4(addr) => addr,
+            _ => unreachable!(),
+        };
+        (listener, addr)
+    }
+
+    // Accepts the next connection and answers its handshake as a remote
+    // peer supporting every extension we do
+    async fn accept_peer(listener: &TcpListener) -> (Handshake, Framed<TcpStream, PeerCodec>) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let handshake = Handshake::read_from(&mut stream).await.unwrap();
+        Handshake::new(handshake.info_hash, [2; 20])
+            .write_to(&mut stream)
+            .await
+            .unwrap();
+        (handshake, Framed::new(stream, PeerCodec::new()))
+    }
+
+    #[test]
+    fn test_pipeline_depth_tracks_rate() {
+        let config = PipelineConfig::default();
+        assert_eq!(config.depth_for_rate(0.0), 5);
+        // 1 MiB/s for 3 seconds is 192 blocks
+        assert_eq!(config.depth_for_rate(1024.0 * 1024.0), 192);
+        assert_eq!(config.depth_for_rate(100.0 * 1024.0 * 1024.0), 250);
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_blocks_reassembled_out_of_order() {
+        let piece_length = BLOCK_SIZE * 4 + 100;
+        let payload: Vec<u8> = (0..piece_length).map(|i| (i % 251) as u8).collect();
+        let (listener, addr) = local_listener().await;
+
+        let served = payload.clone();
+        let seeder = tokio::spawn(async move {
+            let (_, mut framed) = accept_peer(&listener).await;
+            framed
+                .send(Message::new(MessageId::Bitfield, vec![0x80]))
+                .await
+                .unwrap();
+            framed
+                .send(Message::new(MessageId::Unchoke, Vec::new()))
+                .await
+                .unwrap();
+
+            // Wait until the whole pipeline is in flight, then answer backwards
+            let mut requests = Vec::new();
+            while requests.len() < 5 {
+                let msg = framed.next().await.unwrap().unwrap();
+                if msg.id == MessageId::Request {
+                    requests.push(BlockRequest::from_payload(&msg.payload).unwrap());
+                }
+            }
+            for request in requests.iter().rev() {
+                let begin = request.begin as usize;
+                let mut block = Vec::new();
+                block.put_u32(request.index);
+                block.put_u32(request.begin);
+                block.extend_from_slice(&served[begin..begin + request.length as usize]);
+                framed
+                    .send(Message::new(MessageId::Piece, block))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let hash: [u8; 20] = Sha1::digest(&payload).into();
+        let mut piece_manager = PieceManager::new(piece_length, vec![hash], piece_length);
+        let none = HashSet::new();
+
+        let mut peer = Peer::connect(addr, [1; 20], [3; 20]).await.unwrap();
+        piece_manager.register_peer(0);
+        assert!(peer.has_piece(0));
+        piece_manager.add_peer_piece(0, 0);
+        peer.wait_for_unchoke().await.unwrap();
+
+        let piece = loop {
+            while peer.can_request() {
+                let Some(request) = piece_manager.pick_block(0, &none) else {
+                    break;
+                };
+                peer.request_block(request).await.unwrap();
+            }
+            let (request, block) = peer.next_block().await.unwrap();
+            if let Some(piece) = piece_manager.add_block(0, request, &block) {
+                break piece;
+            }
+        };
+
+        assert_eq!(piece.data, payload);
+        assert!(piece_manager.verify_piece(0, &piece.data));
+        seeder.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_blocks_are_withdrawn() {
+        let (listener, addr) = local_listener().await;
+        let requests: Vec<BlockRequest> = (0..5)
+            .map(|i| BlockRequest::new(0, i * BLOCK_SIZE as u32, BLOCK_SIZE as u32))
+            .collect();
+
+        let expected = requests.clone();
+        let seeder = tokio::spawn(async move {
+            let (_, mut framed) = accept_peer(&listener).await;
+            framed
+                .send(Message::new(MessageId::Bitfield, vec![0x80]))
+                .await
+                .unwrap();
+            framed
+                .send(Message::new(MessageId::Unchoke, Vec::new()))
+                .await
+                .unwrap();
+
+            let mut received = Vec::new();
+            let mut cancels = Vec::new();
+            while cancels.len() < 3 {
+                let msg = framed.next().await.unwrap().unwrap();
+                let request = BlockRequest::from_payload(&msg.payload);
+                match msg.id {
+                    MessageId::Request => received.push(request.unwrap()),
+                    MessageId::Cancel => cancels.push(request.unwrap()),
+                    _ => {}
+                }
+            }
+            assert_eq!(received, expected);
+            assert_eq!(cancels, expected[..3]);
+
+            // A cancelled block that was already on its way, then a live one
+            for request in [expected[0], expected[3]] {
+                let mut block = Vec::new();
+                block.put_u32(request.index);
+                block.put_u32(request.begin);
+                block.extend_from_slice(&[7; BLOCK_SIZE]);
+                framed
+                    .send(Message::new(MessageId::Piece, block))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let mut peer = Peer::connect(addr, [1; 20], [3; 20]).await.unwrap();
+        peer.wait_for_unchoke().await.unwrap();
+        for &request in &requests {
+            assert!(peer.can_request());
+            peer.request_block(request).await.unwrap();
+        }
+        for &request in &requests[..3] {
+            peer.cancel_block(request).await.unwrap();
+        }
+        assert_eq!(peer.requested_blocks(), &requests[3..]);
+
+        let (request, block) = peer.next_block().await.unwrap();
+        assert_eq!(request, requests[3]);
+        assert_eq!(block, vec![7; BLOCK_SIZE]);
+        assert_eq!(peer.requested_blocks(), &requests[4..]);
+        seeder.await.unwrap();
+    }
+
+    #[test]
+    fn test_allowed_fast_set_matches_spec() {
+        // The example from BEP 6
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        let info_hash = [0xaa; 20];
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(allowed_fast_set(ip, &info_hash, 1313, 9)[7..], [353, 508]);
+        assert_eq!(allowed_fast_set(ip, &info_hash, 3, 10).len(), 3);
+    }
+
+    #[test]
+    fn test_codec_skips_keepalives() {
+        let mut buffer = BytesMut::from(&[0, 0, 0, 0, 0, 0, 0, 1, 14][..]);
+        let message = PeerCodec::new().decode(&mut buffer).unwrap().unwrap();
+        assert_eq!(message.id, MessageId::HaveAll);
+        assert!(buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fast_extension_messages() {
+        let (listener, addr) = local_listener().await;
+        let request = BlockRequest::new(3, 0, BLOCK_SIZE as u32);
+
+        let seeder = tokio::spawn(async move {
+            let (handshake, mut framed) = accept_peer(&listener).await;
+            assert!(handshake.supports_fast());
+            for message in [
+                Message::new(MessageId::HaveAll, Vec::new()),
+                Message::new(MessageId::Suggest, 5u32.to_be_bytes().to_vec()),
+                Message::new(MessageId::AllowedFast, 7u32.to_be_bytes().to_vec()),
+                Message::new(MessageId::Unchoke, Vec::new()),
+            ] {
+                framed.send(message).await.unwrap();
+            }
+
+            loop {
+                let msg = framed.next().await.unwrap().unwrap();
+                if msg.id == MessageId::Request {
+                    framed
+                        .send(Message::new(MessageId::Reject, msg.payload))
+                        .await
+                        .unwrap();
+                    break;
+                }
+            }
+        });
+
+        let mut peer = Peer::connect(addr, [1; 20], [3; 20]).await.unwrap();
+        assert!(peer.supports_fast());
+        assert!(peer.has_piece(0) && peer.has_piece(1000));
+        assert_eq!(peer.get_bitfield(), None);
+
+        peer.wait_for_unchoke().await.unwrap();
+        assert_eq!(peer.take_suggested(), vec![5]);
+        assert!(peer.allowed_fast().contains(&7));
+
+        // The reject comes back at once instead of after a timeout
+        peer.request_block(request).await.unwrap();
+        match peer.next_response().await.unwrap() {
+            BlockResponse::Rejected(rejected) => assert_eq!(rejected, request),
+            other => panic!("expected a reject, got {:?}", other),
+        }
+        assert!(peer.requested_blocks().is_empty());
+        seeder.await.unwrap();
+    }
+
+    #[derive(Default)]
+    struct Recorder(std::sync::Mutex<Vec<Vec<u8>>>);
+
+    impl ExtensionHandler for Recorder {
+        fn on_message(&self, _addr: SocketAddrV4, payload: &[u8]) -> Result<()> {
+            self.0.lock().unwrap().push(payload.to_vec());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_extended_handshake_and_dispatch() {
+        let (listener, addr) = local_listener().await;
+
+        let seeder = tokio::spawn(async move {
+            let (_, mut framed) = accept_peer(&listener).await;
+            let msg = framed.next().await.unwrap().unwrap();
+            assert_eq!(msg.payload[0], EXTENDED_HANDSHAKE_ID);
+            let ours: ExtendedHandshake = serde_bencode::from_bytes(&msg.payload[1..]).unwrap();
+            assert_eq!(ours.m.get(UT_PEX), Some(&UT_PEX_ID));
+            assert!(!ours.m.contains_key(UT_METADATA));
+            assert_eq!(ours.p, Some(6881));
+            assert_eq!(ours.reqq, Some(MAX_QUEUED_REQUESTS as u32));
+            assert_eq!(ours.yourip.unwrap().as_slice(), &[127, 0, 0, 1]);
+            let test_id = ours.m["x_test"];
+
+            let theirs = ExtendedHandshake {
+                v: Some(ByteBuf::from("Test 1.0")),
+                reqq: Some(2),
+                yourip: Some(ByteBuf::from(vec![203, 0, 113, 7])),
+                m: BTreeMap::from([("x_test".to_string(), 9)]),
+                ..Default::default()
+            };
+            let mut payload = vec![EXTENDED_HANDSHAKE_ID];
+            payload.extend(serde_bencode::to_bytes(&theirs).unwrap());
+            framed
+                .send(Message::new(MessageId::Extended, payload))
+                .await
+                .unwrap();
+            framed
+                .send(Message::new(MessageId::Extended, vec![test_id, 1, 2, 3]))
+                .await
+                .unwrap();
+            // Never offered, so dropped
+            framed
+                .send(Message::new(MessageId::Extended, vec![99, 4]))
+                .await
+                .unwrap();
+            framed
+                .send(Message::new(MessageId::Unchoke, Vec::new()))
+                .await
+                .unwrap();
+        });
+
+        let recorder = Arc::new(Recorder::default());
+        let mut extensions = ExtensionRegistry::new().with_listen_port(6881);
+        let test_id = extensions.register("x_test", recorder.clone()).unwrap();
+        assert_eq!(extensions.id("x_test"), Some(test_id));
+        assert!(extensions.unregister(UT_METADATA));
+        assert!(!extensions.unregister(UT_METADATA));
+
+        let mut peer = Peer::connect_with_extensions(addr, [1; 20], [3; 20], Arc::new(extensions))
+            .await
+            .unwrap();
+        while peer.recv().await.unwrap() != Some(MessageId::Unchoke) {}
+
+        assert_eq!(peer.client_version().as_deref(), Some("Test 1.0"));
+        assert_eq!(peer.reqq(), Some(2));
+        assert_eq!(peer.pipeline_depth(), 2);
+        assert_eq!(peer.your_ip(), Some(Ipv4Addr::new(203, 0, 113, 7)));
+        assert_eq!(peer.extension_id("x_test"), Some(9));
+        assert!(peer.offers_extension(UT_PEX));
+        assert!(!peer.offers_extension(UT_METADATA));
+        assert_eq!(*recorder.0.lock().unwrap(), vec![vec![1, 2, 3]]);
+        assert!(peer.take_extended_messages().is_empty());
+        seeder.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_sends_bitfield_first() {
+        let (listener, addr) = local_listener().await;
+
+        let leecher = tokio::spawn(async move {
+            let (_, mut framed) = accept_peer(&listener).await;
+            let first = framed.next().await.unwrap().unwrap();
+            assert_eq!(first.id, MessageId::Bitfield);
+            assert_eq!(first.payload, vec![0x40]);
+
+            framed
+                .send(Message::new(MessageId::HaveNone, Vec::new()))
+                .await
+                .unwrap();
+            framed
+                .send(Message::new(MessageId::Interested, Vec::new()))
+                .await
+                .unwrap();
+            while framed.next().await.unwrap().unwrap().id != MessageId::Unchoke {}
+            framed
+                .send(Message::new(
+                    MessageId::Request,
+                    BlockRequest::new(1, 0, 4).to_payload(),
+                ))
+                .await
+                .unwrap();
+            while framed.next().await.is_some() {}
+        });
+
+        let options = ConnectOptions {
+            bitfield: Some(vec![0x40]),
+            num_pieces: Some(3),
+            ..Default::default()
+        };
+        let mut peer = Peer::connect_with_options(addr, [1; 20], [3; 20], options)
+            .await
+            .unwrap();
+        peer.set_choking(false).await.unwrap();
+        while peer.recv().await.unwrap() != Some(MessageId::Request) {}
+
+        assert!(peer.peer_interested());
+        assert_eq!(peer.take_requests(), vec![BlockRequest::new(1, 0, 4)]);
+        drop(peer);
+        leecher.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_piece_indices_bounded_by_torrent() {
+        let (listener, addr) = local_listener().await;
+
+        let seeder = tokio::spawn(async move {
+            // One connection with a valid bitfield, one with a bitfield too long
+            for bitfield in [vec![0x80], vec![0x80, 0]] {
+                let (_, mut framed) = accept_peer(&listener).await;
+                framed
+                    .send(Message::new(MessageId::Bitfield, bitfield))
+                    .await
+                    .unwrap();
+                for index in [2, u32::MAX] {
+                    let _ = framed
+                        .send(Message::new(MessageId::Have, index.to_be_bytes().to_vec()))
+                        .await;
+                }
+                while let Some(Ok(_)) = framed.next().await {}
+            }
+        });
+
+        let options = ConnectOptions {
+            num_pieces: Some(3),
+            ..Default::default()
+        };
+        let mut peer = Peer::connect_with_options(addr, [1; 20], [3; 20], options.clone())
+            .await
+            .unwrap();
+        assert_eq!(peer.recv().await.unwrap(), Some(MessageId::Have));
+        assert!(peer.has_piece(0) && peer.has_piece(2));
+        assert!(matches!(
+            peer.recv().await,
+            Err(BitTorrentError::Protocol(_))
+        ));
+        assert_eq!(peer.get_bitfield(), Some(&[0xA0][..]));
+        drop(peer);
+
+        let result = Peer::connect_with_options(addr, [1; 20], [3; 20], options).await;
+        assert!(matches!(result, Err(BitTorrentError::Protocol(_))));
+        seeder.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fast_messages_need_the_fast_bit() {
+        let (listener, addr) = local_listener().await;
+
+        let seeder = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; 68];
+            stream.read_exact(&mut handshake).await.unwrap();
+            // No reserved bits set
+            handshake[20..28].copy_from_slice(&[0; 8]);
+            stream.write_all(&handshake).await.unwrap();
+
+            let mut framed = Framed::new(stream, PeerCodec::new());
+            let _ = framed
+                .send(Message::new(MessageId::HaveAll, Vec::new()))
+                .await;
+            while let Some(Ok(_)) = framed.next().await {}
+        });
+
+        let result = Peer::connect(addr, [1; 20], [3; 20]).await;
+        assert!(matches!(result, Err(BitTorrentError::Protocol(_))));
+        seeder.await.unwrap();
+    }
+
+    #[test]
+    fn test_register_reuses_free_ids_until_full() {
+        let recorder = Arc::new(Recorder::default());
+        let mut extensions = ExtensionRegistry::new();
+        let builtin = extensions.extensions.len();
+        for i in builtin..255 {
+            assert!(extensions
+                .register(&format!("x_{}", i), recorder.clone())
+                .is_some());
+        }
+        assert_eq!(extensions.register("x_full", recorder.clone()), None);
+        // Re-registering a known name still works when full
+        assert_eq!(
+            extensions.register("x_254", recorder.clone()),
+            extensions.id("x_254")
+        );
+
+        let freed = extensions.id("x_10").unwrap();
+        assert!(extensions.unregister("x_10"));
+        assert_eq!(extensions.register("x_new", recorder), Some(freed));
+    }
+}
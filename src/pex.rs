@@ -0,0 +1,215 @@
+use crate::{
+    error::{BitTorrentError, Result},
+    krpc::{decode_peer, encode_peer},
+};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::HashSet;
+use std::net::SocketAddrV4;
+use tokio::time::{Duration, Instant};
+
+/// Extension name for peer exchange (BEP 11).
+pub const UT_PEX: &str = "ut_pex";
+/// The id we ask peers to send `ut_pex` messages to us on.
+pub const UT_PEX_ID: u8 = 2;
+
+/// Minimum time between two PEX messages to the same peer.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+// Most added or dropped entries allowed in one message
+const MAX_PEX_PEERS: usize = 50;
+
+pub const FLAG_ENCRYPTION: u8 = 0x01;
+pub const FLAG_SEED: u8 = 0x02;
+pub const FLAG_UTP: u8 = 0x04;
+pub const FLAG_HOLEPUNCH: u8 = 0x08;
+/// Set when the peer accepted an outgoing connection, i.e. is connectable.
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+/// A peer in an `added` list, with its `added.f` flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PexPeer {
+    pub addr: SocketAddrV4,
+    pub flags: u8,
+}
+
+impl PexPeer {
+    pub fn is_seed(&self) -> bool {
+        self.flags & FLAG_SEED != 0
+    }
+
+    pub fn is_reachable(&self) -> bool {
+        self.flags & FLAG_REACHABLE != 0
+    }
+}
+
+/// A `ut_pex` message: peers connected and disconnected since the last one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PexMessage {
+    pub added: Vec<PexPeer>,
+    pub dropped: Vec<SocketAddrV4>,
+}
+
+// Wire form; IPv6 lists (added6, dropped6) are ignored
+#[derive(Serialize, Deserialize)]
+struct RawPexMessage {
+    #[serde(default)]
+    added: ByteBuf,
+    #[serde(rename = "added.f", default)]
+    added_flags: ByteBuf,
+    #[serde(default)]
+    dropped: ByteBuf,
+}
+
+impl PexMessage {
+    pub fn decode(payload: &[u8]) -> Result<Self> {
+        let raw: RawPexMessage = serde_bencode::from_bytes(payload)
+            .map_err(|e| BitTorrentError::Protocol(format!("Invalid ut_pex message: {}", e)))?;
+
+        let added = raw
+            .added
+            .chunks_exact(6)
+            .enumerate()
+            .filter_map(|(i, chunk)| {
+                Some(PexPeer {
+                    addr: decode_peer(chunk)?,
+                    // Flags are optional; missing ones mean nothing is known
+                    flags: raw.added_flags.get(i).copied().unwrap_or(0),
+                })
+            })
+            .collect();
+        let dropped = raw
+            .dropped
+            .chunks_exact(6)
+            .filter_map(decode_peer)
+            .collect();
+
+        Ok(Self { added, dropped })
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let raw = RawPexMessage {
+            added: ByteBuf::from(
+                self.added
+                    .iter()
+                    .flat_map(|peer| encode_peer(peer.addr))
+                    .collect::<Vec<u8>>(),
+            ),
+            added_flags: ByteBuf::from(
+                self.added
+                    .iter()
+                    .map(|peer| peer.flags)
+                    .collect::<Vec<u8>>(),
+            ),
+            dropped: ByteBuf::from(
+                self.dropped
+                    .iter()
+                    .flat_map(|&addr| encode_peer(addr))
+                    .collect::<Vec<u8>>(),
+            ),
+        };
+        serde_bencode::to_bytes(&raw).map_err(|e| BitTorrentError::Protocol(e.to_string()))
+    }
+}
+
+/// What we last told one peer, so each update carries only the changes
+/// and goes out at most once per `PEX_INTERVAL`.
+#[derive(Debug, Default)]
+pub struct PexState {
+    sent: HashSet<SocketAddrV4>,
+    last_sent: Option<Instant>,
+}
+
+impl PexState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The message to send now, given the peers we are currently connected
+    /// to, or `None` if it's too soon or nothing changed. `recipient` is
+    /// left out of its own update.
+    pub fn update(&mut self, connected: &[PexPeer], recipient: SocketAddrV4) -> Option<PexMessage> {
+        if self
+            .last_sent
+            .is_some_and(|last| last.elapsed() < PEX_INTERVAL)
+        {
+            return None;
+        }
+
+        let current: HashSet<SocketAddrV4> = connected.iter().map(|peer| peer.addr).collect();
+        let added: Vec<PexPeer> = connected
+            .iter()
+            .filter(|peer| peer.addr != recipient && !self.sent.contains(&peer.addr))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        let dropped: Vec<SocketAddrV4> = self
+            .sent
+            .iter()
+            .filter(|addr| !current.contains(addr))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+
+        self.sent.extend(added.iter().map(|peer| peer.addr));
+        for addr in &dropped {
+            self.sent.remove(addr);
+        }
+        self.last_sent = Some(Instant::now());
+
+        Some(PexMessage { added, dropped })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(last_octet: u8, flags: u8) -> PexPeer {
+        PexPeer {
+            addr: SocketAddrV4::new([10, 0, 0, last_octet].into(), 6881),
+            flags,
+        }
+    }
+
+    #[test]
+    fn test_pex_message_round_trip() {
+        let message = PexMessage {
+            added: vec![peer(1, FLAG_SEED | FLAG_REACHABLE), peer(2, 0)],
+            dropped: vec![peer(3, 0).addr],
+        };
+        let decoded = PexMessage::decode(&message.encode().unwrap()).unwrap();
+        assert_eq!(decoded, message);
+        assert!(decoded.added[0].is_seed() && decoded.added[0].is_reachable());
+
+        // Flags may be missing entirely
+        let decoded = PexMessage::decode(b"d5:added6:\x0a\x00\x00\x01\x1a\xe1e").unwrap();
+        assert_eq!(decoded.added, vec![peer(1, 0)]);
+    }
+
+    #[test]
+    fn test_pex_updates_are_diffs_and_rate_limited() {
+        let recipient = peer(9, 0).addr;
+        let mut state = PexState::new();
+
+        let first = state
+            .update(&[peer(1, 0), peer(2, 0), peer(9, 0)], recipient)
+            .unwrap();
+        assert_eq!(first.added, vec![peer(1, 0), peer(2, 0)]);
+        assert!(first.dropped.is_empty());
+
+        // Too soon for another message, even though something changed
+        assert!(state.update(&[peer(1, 0), peer(3, 0)], recipient).is_none());
+
+        state.last_sent = Instant::now().checked_sub(PEX_INTERVAL);
+        let second = state.update(&[peer(1, 0), peer(3, 0)], recipient).unwrap();
+        assert_eq!(second.added, vec![peer(3, 0)]);
+        assert_eq!(second.dropped, vec![peer(2, 0).addr]);
+
+        state.last_sent = Instant::now().checked_sub(PEX_INTERVAL);
+        assert!(state.update(&[peer(1, 0), peer(3, 0)], recipient).is_none());
+    }
+}
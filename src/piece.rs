@@ -1,3 +1,9 @@
+use crate::{
+    merkle::{self, MAX_HASHES},
+    message::{BlockRequest, HashRequest},
+    utils::set_bit,
+    BLOCK_SIZE,
+};
 use sha1::{Digest, Sha1};
 use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
@@ -5,16 +11,32 @@ use std::collections::{HashMap, HashSet};
 #[derive(Debug, Clone)]
 pub struct PieceInfo {
     index: usize,
-    hash: [u8; 20],
+    // SHA-1 of the piece; v2-only torrents have none
+    hash: Option<[u8; 20]>,
+    // Root of the v2 merkle tree over the piece's blocks, padded to
+    // `merkle_leaves` leaves, once the file's piece layer is known
+    merkle_root: Option<[u8; 32]>,
+    merkle_leaves: usize,
     length: usize,
     priority: i32,
 }
 
 impl PieceInfo {
     pub fn new(index: usize, hash: [u8; 20], length: usize) -> Self {
+        Self {
+            hash: Some(hash),
+            ..Self::v2(index, length)
+        }
+    }
+
+    /// A piece of a v2-only torrent, verified against its merkle root once
+    /// `PieceManager::add_merkle_file` supplies it.
+    pub fn v2(index: usize, length: usize) -> Self {
         Self {
             index,
-            hash,
+            hash: None,
+            merkle_root: None,
+            merkle_leaves: 0,
             length,
             priority: 0,
         }
@@ -35,6 +57,19 @@ impl PieceInfo {
     pub fn set_priority(&mut self, priority: i32) {
         self.priority = priority;
     }
+
+    /// Whether any hash to check the piece against is known yet.
+    pub fn is_verifiable(&self) -> bool {
+        self.hash.is_some() || self.merkle_root.is_some()
+    }
+}
+
+// A v2 file bigger than one piece, whose piece hashes form a layer of its
+// merkle tree
+#[derive(Debug, Clone)]
+struct MerkleFile {
+    first_piece: usize,
+    num_pieces: usize,
 }
 
 // Keep the existing Ord implementation style for PieceInfo
@@ -60,6 +95,62 @@ impl PartialEq for PieceInfo {
 
 impl Eq for PieceInfo {}
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BlockState {
+    Missing,
+    // Requested from these peers; more than one only in endgame
+    Requested(HashSet<usize>),
+    // Received from this peer
+    Received(usize),
+}
+
+// A piece being assembled from blocks, possibly sent by different peers
+#[derive(Debug, Clone)]
+struct PartialPiece {
+    data: Vec<u8>,
+    blocks: Vec<BlockState>,
+}
+
+impl PartialPiece {
+    fn new(length: usize) -> Self {
+        Self {
+            data: vec![0u8; length],
+            blocks: vec![BlockState::Missing; length.div_ceil(BLOCK_SIZE)],
+        }
+    }
+
+    fn block_request(&self, index: usize, block: usize) -> BlockRequest {
+        let begin = block * BLOCK_SIZE;
+        let length = BLOCK_SIZE.min(self.data.len() - begin);
+        BlockRequest::new(index as u32, begin as u32, length as u32)
+    }
+}
+
+// Sender and hash of each block of a copy that failed verification
+type FailedCopy = Vec<(usize, [u8; 20])>;
+
+/// A piece whose blocks have all arrived, ready to be verified.
+#[derive(Debug)]
+pub struct AssembledPiece {
+    pub index: usize,
+    pub data: Vec<u8>,
+    /// The peer each block came from, in block order.
+    pub senders: Vec<usize>,
+}
+
+impl AssembledPiece {
+    /// Peers that sent at least one of its blocks.
+    pub fn contributors(&self) -> HashSet<usize> {
+        self.senders.iter().copied().collect()
+    }
+
+    fn block_hashes(&self) -> impl Iterator<Item = [u8; 20]> + '_ {
+        self.data
+            .chunks(BLOCK_SIZE)
+            .map(|block| Sha1::digest(block).into())
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct PieceManager {
     piece_length: usize,
@@ -69,6 +160,15 @@ pub struct PieceManager {
     peer_pieces: HashMap<usize, HashSet<usize>>,
     // Track downloaded pieces
     completed_pieces: HashSet<usize>,
+    // Pieces with at least one block requested or received
+    partial: HashMap<usize, PartialPiece>,
+    // Failed copies of each piece, kept until a good copy shows which
+    // blocks were bad
+    failed_copies: HashMap<usize, Vec<FailedCopy>>,
+    // Pieces each peer suggested (BEP 6), tried before the rarest one
+    suggested: HashMap<usize, Vec<usize>>,
+    // v2 files bigger than a piece, by pieces root
+    merkle_files: HashMap<[u8; 32], MerkleFile>,
 }
 
 impl PieceManager {
@@ -93,29 +193,273 @@ impl PieceManager {
             })
             .collect();
 
+        Self::from_pieces(piece_length, pieces)
+    }
+
+    /// A manager for `pieces`, which must be in index order. v2-only
+    /// torrents need this, as each file's last piece may be short.
+    pub fn from_pieces(piece_length: usize, pieces: Vec<PieceInfo>) -> Self {
         Self {
             piece_length,
-            num_pieces,
+            num_pieces: pieces.len(),
             pieces,
             peer_pieces: HashMap::new(),
             completed_pieces: HashSet::new(),
+            partial: HashMap::new(),
+            failed_copies: HashMap::new(),
+            suggested: HashMap::new(),
+            merkle_files: HashMap::new(),
         }
     }
 
-    pub fn next_piece_excluding(
+    /// Verifies the pieces of the v2 file with `pieces_root`, `length`
+    /// bytes long from `first_piece` on, against its merkle tree as well.
+    /// Files bigger than a piece need their piece layer for that; without
+    /// one, or if `layer` doesn't match the root, it must be fetched from
+    /// peers with `missing_hashes` first. Returns whether `layer` was used.
+    pub fn add_merkle_file(
+        &mut self,
+        pieces_root: [u8; 32],
+        first_piece: usize,
+        length: usize,
+        layer: Option<Vec<[u8; 32]>>,
+    ) -> bool {
+        let num_pieces = length.div_ceil(self.piece_length);
+        if num_pieces == 0 || first_piece + num_pieces > self.num_pieces {
+            return false;
+        }
+        if num_pieces == 1 {
+            // The piece is the whole tree
+            let piece = &mut self.pieces[first_piece];
+            piece.merkle_root = Some(pieces_root);
+            piece.merkle_leaves = length.div_ceil(BLOCK_SIZE).next_power_of_two();
+            return true;
+        }
+
+        let leaves = self.piece_length / BLOCK_SIZE;
+        for piece in &mut self.pieces[first_piece..first_piece + num_pieces] {
+            piece.merkle_leaves = leaves;
+        }
+        self.merkle_files.insert(
+            pieces_root,
+            MerkleFile {
+                first_piece,
+                num_pieces,
+            },
+        );
+
+        let Some(layer) = layer else {
+            return false;
+        };
+        let width = num_pieces.next_power_of_two();
+        if layer.len() != num_pieces
+            || merkle::root(&layer, width, merkle::pad_hash(leaves)) != pieces_root
+        {
+            return false;
+        }
+        for (piece, hash) in self.pieces[first_piece..].iter_mut().zip(layer) {
+            piece.merkle_root = Some(hash);
+        }
+        true
+    }
+
+    // Index of the piece layer in v2 merkle trees, counting up from blocks
+    fn piece_layer(&self) -> u32 {
+        (self.piece_length / BLOCK_SIZE).trailing_zeros()
+    }
+
+    /// Requests for the piece hashes of v2 files we don't have yet, each
+    /// with the proof up to the file's pieces root.
+    pub fn missing_hashes(&self) -> Vec<HashRequest> {
+        let mut requests = Vec::new();
+        for (root, file) in &self.merkle_files {
+            let width = file.num_pieces.next_power_of_two();
+            let length = width.min(MAX_HASHES);
+            for start in (0..file.num_pieces).step_by(length) {
+                let end = (start + length).min(file.num_pieces);
+                let missing = self.pieces[file.first_piece + start..file.first_piece + end]
+                    .iter()
+                    .any(|piece| piece.merkle_root.is_none());
+                if missing {
+                    requests.push(HashRequest::new(
+                        *root,
+                        self.piece_layer(),
+                        start as u32,
+                        length as u32,
+                        width.trailing_zeros(),
+                    ));
+                }
+            }
+        }
+        requests
+    }
+
+    /// Stores the piece hashes a peer sent for `request`, if they and the
+    /// uncle hashes after them prove out against the file's pieces root.
+    pub fn add_hashes(&mut self, request: &HashRequest, hashes: &[[u8; 32]]) -> bool {
+        let Some(file) = self.merkle_files.get(&request.pieces_root).cloned() else {
+            return false;
+        };
+        let length = request.length as usize;
+        if request.base_layer != self.piece_layer() || hashes.len() < length {
+            return false;
+        }
+        let (base, uncles) = hashes.split_at(length);
+        let index = request.index as usize;
+        if !merkle::verify(&request.pieces_root, index, base, uncles) {
+            return false;
+        }
+
+        // Hashes past the end of the file are padding
+        let pieces = file.first_piece + index.min(file.num_pieces)
+            ..file.first_piece + (index + length).min(file.num_pieces);
+        for (piece, hash) in self.pieces[pieces].iter_mut().zip(base) {
+            piece.merkle_root = Some(*hash);
+        }
+        true
+    }
+
+    /// The hashes answering a peer's hash request, with their proof, if we
+    /// have that file's whole piece layer. Only piece layer hashes are
+    /// served.
+    pub fn hashes_for(&self, request: &HashRequest) -> Option<Vec<[u8; 32]>> {
+        let file = self.merkle_files.get(&request.pieces_root)?;
+        let length = request.length as usize;
+        if request.base_layer != self.piece_layer() || length > MAX_HASHES {
+            return None;
+        }
+        let layer: Vec<[u8; 32]> = self.pieces
+            [file.first_piece..file.first_piece + file.num_pieces]
+            .iter()
+            .map(|piece| piece.merkle_root)
+            .collect::<Option<_>>()?;
+
+        // Layers the requested hashes cover need no uncles
+        let uncles =
+            (request.proof_layers as usize).saturating_sub(length.trailing_zeros() as usize);
+        merkle::proof(
+            &layer,
+            file.num_pieces.next_power_of_two(),
+            merkle::pad_hash(self.piece_length / BLOCK_SIZE),
+            request.index as usize,
+            length,
+            uncles,
+        )
+    }
+
+    /// Picks the next block to request from `peer_id`: a missing block of
+    /// a piece already under way, else the first block of the rarest piece
+    /// the peer has. In endgame, once every remaining block is requested,
+    /// blocks already requested from other peers are handed out too,
+    /// fewest requesters first.
+    pub fn pick_block(
         &mut self,
         peer_id: usize,
         excluded_pieces: &HashSet<usize>,
-    ) -> Option<PieceInfo> {
+    ) -> Option<BlockRequest> {
+        self.pick_block_where(peer_id, |index| !excluded_pieces.contains(index))
+    }
+
+    /// Like `pick_block`, but only from `allowed_fast`: the pieces a peer
+    /// that chokes us still lets us request (BEP 6).
+    pub fn pick_allowed_fast_block(
+        &mut self,
+        peer_id: usize,
+        excluded_pieces: &HashSet<usize>,
+        allowed_fast: &HashSet<u32>,
+    ) -> Option<BlockRequest> {
+        self.pick_block_where(peer_id, |&index| {
+            !excluded_pieces.contains(&index) && allowed_fast.contains(&(index as u32))
+        })
+    }
+
+    // `pick_block` among the pieces `allowed` accepts
+    fn pick_block_where(
+        &mut self,
+        peer_id: usize,
+        allowed: impl Fn(&usize) -> bool,
+    ) -> Option<BlockRequest> {
         let peer_pieces = self.peer_pieces.get(&peer_id)?;
+        let wanted = |index: &usize| {
+            peer_pieces.contains(index) && !self.completed_pieces.contains(index) && allowed(index)
+        };
+
+        let missing = self
+            .partial
+            .iter()
+            .filter(|(index, _)| wanted(index))
+            .find_map(|(&index, partial)| {
+                let block = partial
+                    .blocks
+                    .iter()
+                    .position(|state| *state == BlockState::Missing)?;
+                Some((index, block))
+            });
+
+        let (index, block) = match missing {
+            Some(found) => found,
+            None => match self.rarest_new_piece(peer_id, &allowed) {
+                Some(index) => {
+                    let length = self.pieces[index].length();
+                    self.partial.insert(index, PartialPiece::new(length));
+                    (index, 0)
+                }
+                None if self.is_endgame() => self
+                    .partial
+                    .iter()
+                    .filter(|(index, _)| wanted(index))
+                    .flat_map(|(&index, partial)| {
+                        partial
+                            .blocks
+                            .iter()
+                            .enumerate()
+                            .filter_map(move |(block, state)| match state {
+                                BlockState::Requested(peers) if !peers.contains(&peer_id) => {
+                                    Some((peers.len(), index, block))
+                                }
+                                _ => None,
+                            })
+                    })
+                    .min()
+                    .map(|(_, index, block)| (index, block))?,
+                None => return None,
+            },
+        };
+
+        let partial = self.partial.get_mut(&index)?;
+        match &mut partial.blocks[block] {
+            BlockState::Requested(peers) => {
+                peers.insert(peer_id);
+            }
+            state => *state = BlockState::Requested(HashSet::from([peer_id])),
+        }
+        Some(partial.block_request(index, block))
+    }
+
+    // Rarest piece the peer has that nobody has started yet
+    fn rarest_new_piece(&self, peer_id: usize, allowed: &impl Fn(&usize) -> bool) -> Option<usize> {
+        let peer_pieces = self.peer_pieces.get(&peer_id)?;
+        let wanted = |index: &usize| {
+            !self.completed_pieces.contains(index)
+                && allowed(index)
+                && !self.partial.contains_key(index)
+                // v2 pieces wait for their hashes
+                && self.pieces.get(*index).is_some_and(PieceInfo::is_verifiable)
+        };
+
+        let suggested = self.suggested.get(&peer_id).and_then(|suggested| {
+            suggested
+                .iter()
+                .find(|&index| peer_pieces.contains(index) && wanted(index))
+        });
+        if let Some(&index) = suggested {
+            return Some(index);
+        }
 
-        // Find rarest piece this peer has that we haven't downloaded and hasn't failed
         let mut piece_counts = HashMap::new();
         for pieces in self.peer_pieces.values() {
             for &piece_index in pieces {
-                if !self.completed_pieces.contains(&piece_index)
-                    && !excluded_pieces.contains(&piece_index)
-                {
+                if wanted(&piece_index) {
                     *piece_counts.entry(piece_index).or_insert(0) += 1;
                 }
             }
@@ -123,11 +467,144 @@ impl PieceManager {
 
         peer_pieces
             .iter()
-            .filter(|&&index| {
-                !self.completed_pieces.contains(&index) && !excluded_pieces.contains(&index)
+            .filter(|&index| wanted(index))
+            .min_by_key(|&index| piece_counts.get(index).unwrap_or(&0))
+            .copied()
+    }
+
+    /// Stores a block `peer_id` sent for `request`. Returns the whole piece
+    /// once its last block is in; it stays reserved until `mark_completed`
+    /// or `reset_piece`. Blocks we already have are ignored.
+    pub fn add_block(
+        &mut self,
+        peer_id: usize,
+        request: BlockRequest,
+        data: &[u8],
+    ) -> Option<AssembledPiece> {
+        let index = request.index as usize;
+        let partial = self.partial.get_mut(&index)?;
+        let begin = request.begin as usize;
+        let block = begin / BLOCK_SIZE;
+        // The request must match the block exactly, offset and length
+        if matches!(partial.blocks.get(block)?, BlockState::Received(_))
+            || partial.block_request(index, block) != request
+            || data.len() != request.length as usize
+        {
+            return None;
+        }
+
+        partial.data[begin..begin + data.len()].copy_from_slice(data);
+        partial.blocks[block] = BlockState::Received(peer_id);
+
+        let senders = partial
+            .blocks
+            .iter()
+            .map(|state| match state {
+                BlockState::Received(sender) => Some(*sender),
+                _ => None,
+            })
+            .collect::<Option<Vec<usize>>>()?;
+        Some(AssembledPiece {
+            index,
+            data: std::mem::take(&mut partial.data),
+            senders,
+        })
+    }
+
+    /// Whether the block for `request` no longer needs downloading.
+    pub fn is_block_received(&self, request: &BlockRequest) -> bool {
+        let index = request.index as usize;
+        if self.completed_pieces.contains(&index) {
+            return true;
+        }
+        self.partial.get(&index).is_some_and(|partial| {
+            matches!(
+                partial.blocks.get(request.begin as usize / BLOCK_SIZE),
+                Some(BlockState::Received(_))
+            )
+        })
+    }
+
+    /// Withdraws a request to `peer_id`, making the block available again
+    /// if nobody else was asked for it.
+    pub fn release_block(&mut self, peer_id: usize, request: &BlockRequest) {
+        let Some(partial) = self.partial.get_mut(&(request.index as usize)) else {
+            return;
+        };
+        if let Some(state) = partial.blocks.get_mut(request.begin as usize / BLOCK_SIZE) {
+            Self::release(state, peer_id);
+        }
+    }
+
+    /// Withdraws every request to `peer_id`. Blocks it already sent are kept.
+    pub fn release_peer_blocks(&mut self, peer_id: usize) {
+        for partial in self.partial.values_mut() {
+            for state in &mut partial.blocks {
+                Self::release(state, peer_id);
+            }
+        }
+    }
+
+    fn release(state: &mut BlockState, peer_id: usize) {
+        if let BlockState::Requested(peers) = state {
+            peers.remove(&peer_id);
+            if peers.is_empty() {
+                *state = BlockState::Missing;
+            }
+        }
+    }
+
+    /// Throws away an assembled piece that failed verification, so it is
+    /// downloaded again from scratch.
+    pub fn reset_piece(&mut self, index: usize) {
+        self.partial.remove(&index);
+    }
+
+    /// Remembers who sent each block of a piece that failed verification,
+    /// then discards it so it is downloaded again.
+    pub fn record_failure(&mut self, piece: &AssembledPiece) {
+        let blocks = piece
+            .senders
+            .iter()
+            .copied()
+            .zip(piece.block_hashes())
+            .collect();
+        self.failed_copies
+            .entry(piece.index)
+            .or_default()
+            .push(blocks);
+        self.reset_piece(piece.index);
+    }
+
+    /// Compares a verified piece with its earlier failed copies, if any,
+    /// and returns the peers that sent blocks differing from the good data.
+    pub fn find_corrupters(&mut self, piece: &AssembledPiece) -> Vec<usize> {
+        let Some(copies) = self.failed_copies.remove(&piece.index) else {
+            return Vec::new();
+        };
+        let good: Vec<[u8; 20]> = piece.block_hashes().collect();
+
+        let mut corrupters: Vec<usize> = copies
+            .iter()
+            .flat_map(|blocks| blocks.iter().zip(&good))
+            .filter(|((_, hash), good)| hash != *good)
+            .map(|((sender, _), _)| *sender)
+            .collect();
+        corrupters.sort_unstable();
+        corrupters.dedup();
+        corrupters
+    }
+
+    /// Whether every block we still need has been requested from some peer.
+    pub fn is_endgame(&self) -> bool {
+        !self.is_complete()
+            && (0..self.num_pieces).all(|index| {
+                self.completed_pieces.contains(&index)
+                    || self
+                        .partial
+                        .get(&index)
+                        .is_some_and(|partial| !partial.blocks.contains(&BlockState::Missing))
             })
-            .min_by_key(|&&index| piece_counts.get(&index).unwrap_or(&0))
-            .map(|&index| self.pieces[index].clone())
     }
 
     pub fn register_peer(&mut self, peer_id: usize) {
@@ -140,43 +617,80 @@ impl PieceManager {
         }
     }
 
-    pub fn remove_peer(&mut self, peer_id: usize) {
-        self.peer_pieces.remove(&peer_id);
+    /// Whether `peer_id` has a piece we still lack, other than
+    /// `excluded_pieces`.
+    pub fn is_interesting(&self, peer_id: usize, excluded_pieces: &HashSet<usize>) -> bool {
+        self.peer_pieces.get(&peer_id).is_some_and(|pieces| {
+            pieces.iter().any(|index| {
+                !self.completed_pieces.contains(index) && !excluded_pieces.contains(index)
+            })
+        })
     }
 
-    pub fn next_piece(&self, peer_id: usize) -> Option<PieceInfo> {
-        let peer_pieces = self.peer_pieces.get(&peer_id)?;
-
-        // Find rarest piece this peer has that we haven't downloaded
-        let mut piece_counts = HashMap::new();
-        for pieces in self.peer_pieces.values() {
-            for &piece_index in pieces {
-                if !self.completed_pieces.contains(&piece_index) {
-                    *piece_counts.entry(piece_index).or_insert(0) += 1;
-                }
-            }
+    /// Records a piece `peer_id` suggested; new pieces are started from its
+    /// suggestions first, as long as it has them.
+    pub fn suggest_piece(&mut self, peer_id: usize, piece_index: usize) {
+        if piece_index >= self.num_pieces || !self.peer_pieces.contains_key(&peer_id) {
+            return;
+        }
+        let suggested = self.suggested.entry(peer_id).or_default();
+        if !suggested.contains(&piece_index) {
+            suggested.push(piece_index);
         }
+    }
 
-        peer_pieces
-            .iter()
-            .filter(|&&index| !self.completed_pieces.contains(&index))
-            .min_by_key(|&&index| piece_counts.get(&index).unwrap_or(&0))
-            .map(|&index| self.pieces[index].clone())
+    pub fn remove_peer(&mut self, peer_id: usize) {
+        self.peer_pieces.remove(&peer_id);
+        self.suggested.remove(&peer_id);
+        self.release_peer_blocks(peer_id);
     }
 
+    /// Checks `data` against every hash known for the piece: SHA-1, merkle
+    /// root or, in hybrid torrents, both. Fails if none is known.
     pub fn verify_piece(&self, index: usize, data: &[u8]) -> bool {
         let piece = &self.pieces[index];
-        let mut hasher = Sha1::new();
-        hasher.update(data);
-        let hash: [u8; 20] = hasher
-            .finalize()
-            .try_into()
-            .expect("SHA1 output is always 20 bytes");
-        hash == piece.hash
+        let v1 = piece.hash.map(|hash| {
+            let mut hasher = Sha1::new();
+            hasher.update(data);
+            let digest: [u8; 20] = hasher.finalize().into();
+            digest == hash
+        });
+        let v2 = piece
+            .merkle_root
+            .map(|root| merkle::data_root(data, piece.merkle_leaves) == root);
+        piece.is_verifiable() && v1 != Some(false) && v2 != Some(false)
     }
 
     pub fn mark_completed(&mut self, index: usize) {
         self.completed_pieces.insert(index);
+        self.partial.remove(&index);
+    }
+
+    pub fn is_completed(&self, index: usize) -> bool {
+        self.completed_pieces.contains(&index)
+    }
+
+    /// Our own bitfield, as sent to peers after the handshake.
+    pub fn bitfield(&self) -> Vec<u8> {
+        let mut bitfield = vec![0u8; self.num_pieces.div_ceil(8)];
+        for &index in &self.completed_pieces {
+            set_bit(&mut bitfield, index);
+        }
+        bitfield
+    }
+
+    pub fn completed_count(&self) -> usize {
+        self.completed_pieces.len()
+    }
+
+    pub fn completed_pieces(&self) -> Vec<usize> {
+        let mut completed: Vec<usize> = self.completed_pieces.iter().copied().collect();
+        completed.sort_unstable();
+        completed
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.num_pieces
     }
 
     pub fn is_complete(&self) -> bool {
@@ -187,6 +701,10 @@ impl PieceManager {
         self.completed_pieces.len() as f64 / self.num_pieces as f64
     }
 
+    pub fn piece_length(&self) -> usize {
+        self.piece_length
+    }
+
     pub fn get_piece(&self, index: usize) -> Option<&PieceInfo> {
         self.pieces.get(index)
     }
@@ -217,26 +735,178 @@ mod tests {
         manager.add_peer_piece(2, 2);
 
         // Piece 1 should be most common (both peers have it)
-        let next = manager.next_piece(1).unwrap();
-        assert_eq!(next.index(), 0);
+        let none = HashSet::new();
+        let next = manager.pick_block(1, &none).unwrap();
+        assert_eq!(next, BlockRequest::new(0, 0, 1024));
+        let piece = manager.add_block(1, next, &[0; 1024]).unwrap();
+        assert_eq!(piece.index, 0);
 
         manager.mark_completed(0);
 
         // Now should select piece 1 since 0 is completed
-        let next = manager.next_piece(1).unwrap();
-        assert_eq!(next.index(), 1);
+        let next = manager.pick_block(1, &none).unwrap();
+        assert_eq!(next, BlockRequest::new(1, 0, 1024));
 
         assert!(!manager.is_complete());
         manager.mark_completed(1);
+        assert!(!manager.is_interesting(1, &none));
+        assert!(!manager.is_interesting(2, &HashSet::from([2])));
+        assert!(manager.is_interesting(2, &none));
         manager.mark_completed(2);
         assert!(manager.is_complete());
     }
 
+    #[test]
+    fn test_allowed_fast_limits_picks() {
+        let hashes = vec![[0; 20], [1; 20], [2; 20]];
+        let mut manager = PieceManager::new(1024, hashes, 3072);
+        manager.register_peer(1);
+        for index in 0..3 {
+            manager.add_peer_piece(1, index);
+        }
+
+        let none = HashSet::new();
+        let allowed = HashSet::from([2, 7]);
+        let next = manager.pick_allowed_fast_block(1, &none, &allowed).unwrap();
+        assert_eq!(next, BlockRequest::new(2, 0, 1024));
+        assert_eq!(manager.pick_allowed_fast_block(1, &none, &allowed), None);
+        assert_eq!(
+            manager.pick_allowed_fast_block(1, &none, &HashSet::new()),
+            None
+        );
+        assert!(manager.pick_block(1, &none).is_some());
+    }
+
+    #[test]
+    fn test_blocks_shared_across_peers() {
+        // Two blocks in piece 0, one short block in piece 1
+        let length = BLOCK_SIZE * 2 + 100;
+        let mut manager = PieceManager::new(BLOCK_SIZE * 2, vec![[0; 20], [1; 20]], length);
+        let none = HashSet::new();
+        manager.register_peer(1);
+        manager.add_peer_piece(1, 0);
+        manager.register_peer(2);
+        manager.add_peer_piece(2, 0);
+
+        let first = manager.pick_block(1, &none).unwrap();
+        assert_eq!(first, BlockRequest::new(0, 0, BLOCK_SIZE as u32));
+        let second = manager.pick_block(1, &none).unwrap();
+        assert_eq!(second.begin, BLOCK_SIZE as u32);
+        assert!(manager.add_block(1, first, &[1; BLOCK_SIZE]).is_none());
+
+        // Peer 1 drops out; peer 2 picks up where it left off
+        manager.remove_peer(1);
+        assert_eq!(manager.pick_block(2, &none), Some(second));
+        let piece = manager.add_block(2, second, &[2; BLOCK_SIZE]).unwrap();
+        assert_eq!(piece.index, 0);
+        assert_eq!(&piece.data[BLOCK_SIZE - 1..BLOCK_SIZE + 1], &[1, 2]);
+        assert_eq!(piece.contributors(), HashSet::from([1, 2]));
+
+        // Still reserved until marked completed; a bad piece starts over
+        assert!(manager.pick_block(2, &none).is_none());
+        manager.reset_piece(0);
+        assert_eq!(manager.pick_block(2, &none), Some(first));
+    }
+
+    #[test]
+    fn test_smart_ban_finds_corrupter() {
+        let good = vec![1u8; BLOCK_SIZE * 2];
+        let hash: [u8; 20] = Sha1::digest(&good).into();
+        let mut manager = PieceManager::new(good.len(), vec![hash], good.len());
+        let none = HashSet::new();
+        for peer_id in 1..=3 {
+            manager.register_peer(peer_id);
+            manager.add_peer_piece(peer_id, 0);
+        }
+
+        // Peer 1 sends a good first block, peer 2 a bad second one
+        let first = manager.pick_block(1, &none).unwrap();
+        let second = manager.pick_block(2, &none).unwrap();
+        manager.add_block(1, first, &good[..BLOCK_SIZE]);
+        let bad = manager.add_block(2, second, &[0; BLOCK_SIZE]).unwrap();
+        assert!(!manager.verify_piece(0, &bad.data));
+        manager.record_failure(&bad);
+
+        // Peer 3 sends the whole piece again, correctly
+        let mut retry = None;
+        while let Some(request) = manager.pick_block(3, &none) {
+            let begin = request.begin as usize;
+            retry = manager.add_block(3, request, &good[begin..begin + BLOCK_SIZE]);
+        }
+        let retry = retry.unwrap();
+        assert!(manager.verify_piece(0, &retry.data));
+        assert_eq!(manager.find_corrupters(&retry), vec![2]);
+        assert!(manager.find_corrupters(&retry).is_empty());
+    }
+
+    #[test]
+    fn test_endgame_duplicates_blocks() {
+        let mut manager = PieceManager::new(BLOCK_SIZE, vec![[0; 20]], BLOCK_SIZE);
+        let none = HashSet::new();
+        for peer_id in 1..=2 {
+            manager.register_peer(peer_id);
+            manager.add_peer_piece(peer_id, 0);
+        }
+
+        let request = manager.pick_block(1, &none).unwrap();
+        assert!(manager.is_endgame());
+        assert!(manager.pick_block(1, &none).is_none());
+        assert_eq!(manager.pick_block(2, &none), Some(request));
+
+        // The first copy wins; peer 2's request is now redundant
+        assert!(manager.add_block(1, request, &[0; BLOCK_SIZE]).is_some());
+        assert!(manager.is_block_received(&request));
+        assert!(manager.add_block(2, request, &[0; BLOCK_SIZE]).is_none());
+    }
+
+    #[test]
+    fn test_v2_piece_hashes_from_peers() {
+        // A v2 file of three pieces of two blocks, the last one short
+        let piece_length = BLOCK_SIZE * 2;
+        let data: Vec<u8> = (0..piece_length * 2 + 100).map(|i| (i / 7) as u8).collect();
+        let layer: Vec<[u8; 32]> = data
+            .chunks(piece_length)
+            .map(|piece| merkle::data_root(piece, 2))
+            .collect();
+        let root = merkle::root(&layer, 4, merkle::pad_hash(2));
+        let pieces = || {
+            vec![
+                PieceInfo::v2(0, piece_length),
+                PieceInfo::v2(1, piece_length),
+                PieceInfo::v2(2, 100),
+            ]
+        };
+
+        let mut seeder = PieceManager::from_pieces(piece_length, pieces());
+        assert!(seeder.add_merkle_file(root, 0, data.len(), Some(layer)));
+        let mut leecher = PieceManager::from_pieces(piece_length, pieces());
+        assert!(!leecher.add_merkle_file(root, 0, data.len(), None));
+
+        // Nothing is downloaded before the hashes arrive
+        let none = HashSet::new();
+        leecher.register_peer(1);
+        leecher.add_peer_piece(1, 0);
+        assert!(leecher.pick_block(1, &none).is_none());
+
+        let requests = leecher.missing_hashes();
+        assert_eq!(requests, vec![HashRequest::new(root, 1, 0, 4, 2)]);
+        let hashes = seeder.hashes_for(&requests[0]).unwrap();
+        let mut forged = hashes.clone();
+        forged[1] = [0; 32];
+        assert!(!leecher.add_hashes(&requests[0], &forged));
+        assert!(leecher.add_hashes(&requests[0], &hashes));
+        assert!(leecher.missing_hashes().is_empty());
+
+        assert!(leecher.verify_piece(2, &data[piece_length * 2..]));
+        assert!(!leecher.verify_piece(1, &data[..piece_length]));
+        assert!(leecher.pick_block(1, &none).is_some());
+    }
+
     #[test]
     fn test_piece_verification() {
         let mut hasher = Sha1::new();
         hasher.update(b"test data");
-        let hash = hasher.finalize().try_into().unwrap();
+        let hash: [u8; 20] = hasher.finalize().into();
 
         let hashes = vec![hash];
         let manager = PieceManager::new(1024, hashes, 1024);
@@ -0,0 +1,334 @@
+//! Bandwidth limiting for peer connections. Every byte of the message
+//! stream counts, protocol overhead included; only the initial handshake
+//! is exempt.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Duration, Instant, Sleep};
+
+// Waiting streams wake up once this fraction of a second's worth of tokens
+// is available, rather than for every byte
+const GRANTS_PER_SEC: u64 = 20;
+const MAX_GRANT: u64 = 16 * 1024;
+// Longest single wait, so a raised limit takes effect quickly
+const MAX_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: u64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        self.last_refill = now;
+    }
+}
+
+/// A token bucket limiting a byte rate, holding at most one second's worth
+/// of tokens. The rate can be changed at any time.
+#[derive(Debug)]
+pub struct RateLimiter {
+    // Bytes per second, 0 for unlimited; shared with forks
+    rate: Arc<AtomicU64>,
+    bucket: Mutex<Bucket>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl RateLimiter {
+    /// A limiter allowing `bytes_per_sec`, or anything if `None`.
+    pub fn new(bytes_per_sec: Option<u64>) -> Self {
+        Self::with_rate(Arc::new(AtomicU64::new(bytes_per_sec.unwrap_or(0))))
+    }
+
+    fn with_rate(rate: Arc<AtomicU64>) -> Self {
+        Self {
+            rate,
+            bucket: Mutex::new(Bucket {
+                // Full, whatever the rate is once one is set
+                tokens: f64::INFINITY,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        match self.rate.load(Ordering::Relaxed) {
+            0 => None,
+            rate => Some(rate),
+        }
+    }
+
+    pub fn set_rate(&self, bytes_per_sec: Option<u64>) {
+        self.rate
+            .store(bytes_per_sec.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// A limiter with a bucket of its own that always has this one's rate.
+    /// Setting the rate on either changes it for both.
+    pub fn fork(&self) -> Self {
+        Self::with_rate(Arc::clone(&self.rate))
+    }
+
+    // Bytes that may pass right now, or `None` if unlimited
+    fn available(&self) -> Option<u64> {
+        let rate = self.rate()?;
+        let mut bucket = self.bucket.lock().expect("rate limiter lock poisoned");
+        bucket.refill(rate);
+        Some(bucket.tokens.max(0.0) as u64)
+    }
+
+    // Tokens may go negative when several streams share the bucket; the
+    // debt is paid off before anyone is let through again
+    fn consume(&self, bytes: usize) {
+        if self.rate().is_some() {
+            self.bucket
+                .lock()
+                .expect("rate limiter lock poisoned")
+                .tokens -= bytes as f64;
+        }
+    }
+
+    // How long until a grant's worth of tokens is available
+    fn delay(&self) -> Duration {
+        let Some(rate) = self.rate() else {
+            return Duration::ZERO;
+        };
+        let mut bucket = self.bucket.lock().expect("rate limiter lock poisoned");
+        bucket.refill(rate);
+        let grant = (rate / GRANTS_PER_SEC).clamp(1, MAX_GRANT) as f64;
+        let missing = (grant - bucket.tokens).max(0.0);
+        Duration::from_secs_f64(missing / rate as f64).min(MAX_DELAY)
+    }
+}
+
+/// An upload and a download limiter. Clones share the same buckets, so a
+/// clone can be kept to adjust the limits while connections use them.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    pub upload: Arc<RateLimiter>,
+    pub download: Arc<RateLimiter>,
+}
+
+impl RateLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_upload_limit(&self, bytes_per_sec: Option<u64>) {
+        self.upload.set_rate(bytes_per_sec);
+    }
+
+    pub fn set_download_limit(&self, bytes_per_sec: Option<u64>) {
+        self.download.set_rate(bytes_per_sec);
+    }
+
+    /// Separate buckets with the same, shared, rates.
+    pub fn fork(&self) -> Self {
+        Self {
+            upload: Arc::new(self.upload.fork()),
+            download: Arc::new(self.download.fork()),
+        }
+    }
+}
+
+/// The limits a torrent's connections are subject to: the global and
+/// per-torrent buckets shared by all of them, and `peer`, whose rates every
+/// connection gets a bucket of its own with.
+#[derive(Debug, Clone, Default)]
+pub struct TorrentLimits {
+    pub global: RateLimits,
+    pub torrent: RateLimits,
+    pub peer: RateLimits,
+}
+
+impl TorrentLimits {
+    /// Limits for one new connection.
+    pub fn for_peer(&self) -> Vec<RateLimits> {
+        vec![self.global.clone(), self.torrent.clone(), self.peer.fork()]
+    }
+}
+
+/// A stream whose reads and writes draw tokens from every limiter it is
+/// given, waiting when any of them runs dry.
+#[derive(Debug)]
+pub struct Throttled<S> {
+    inner: S,
+    limits: Vec<RateLimits>,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    /// Wraps `inner` without any limits yet.
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            limits: Vec::new(),
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: Vec<RateLimits>) {
+        self.limits = limits;
+    }
+}
+
+// Waits until every limiter in the chosen direction has tokens, returning
+// how many bytes may pass, or `None` if none of them limits
+fn poll_allowance(
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    limits: &[RateLimits],
+    limiter: fn(&RateLimits) -> &RateLimiter,
+    cx: &mut Context<'_>,
+) -> Poll<Option<u64>> {
+    loop {
+        if let Some(sleep) = delay {
+            ready!(sleep.as_mut().poll(cx));
+            *delay = None;
+        }
+
+        let allowed = limits
+            .iter()
+            .filter_map(|limits| limiter(limits).available())
+            .min();
+        if allowed != Some(0) {
+            return Poll::Ready(allowed);
+        }
+
+        let wait = limits
+            .iter()
+            .map(|limits| limiter(limits).delay())
+            .max()
+            .unwrap_or_default();
+        *delay = Some(Box::pin(sleep(wait)));
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let allowed = ready!(poll_allowance(
+            &mut this.read_delay,
+            &this.limits,
+            |limits| &limits.download,
+            cx
+        ));
+
+        let before = buf.filled().len();
+        match allowed {
+            Some(allowed) if (allowed as usize) < buf.remaining() => {
+                let mut chunk = vec![0; allowed as usize];
+                let mut limited = ReadBuf::new(&mut chunk);
+                ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+                buf.put_slice(limited.filled());
+            }
+            _ => ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?,
+        }
+
+        let read = buf.filled().len() - before;
+        for limits in &this.limits {
+            limits.download.consume(read);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        let allowed = ready!(poll_allowance(
+            &mut this.write_delay,
+            &this.limits,
+            |limits| &limits.upload,
+            cx
+        ));
+
+        let length = allowed.map_or(buf.len(), |allowed| buf.len().min(allowed as usize));
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..length]))?;
+        for limits in &this.limits {
+            limits.upload.consume(written);
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_limits_writes() {
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        let global = RateLimits::new();
+        let peer = RateLimits::new();
+        global.set_upload_limit(Some(40_000));
+        peer.set_upload_limit(Some(10_000));
+
+        let mut stream = Throttled::new(client);
+        stream.set_limits(vec![global.clone(), peer.fork()]);
+
+        // The first second's worth goes out at once, the rest at the
+        // lowest of the rates
+        let start = Instant::now();
+        stream.write_all(&[7; 15_000]).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        let mut received = [0; 15_000];
+        server.read_exact(&mut received).await.unwrap();
+
+        // Lifting the rate on the template applies to its forks
+        peer.set_upload_limit(None);
+        let start = Instant::now();
+        stream.write_all(&[7; 20_000]).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_limits_reads() {
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        let limits = RateLimits::new();
+        limits.set_download_limit(Some(10_000));
+        let mut stream = Throttled::new(client);
+        stream.set_limits(vec![limits]);
+
+        server.write_all(&[1; 15_000]).await.unwrap();
+        let start = Instant::now();
+        let mut received = [0; 15_000];
+        stream.read_exact(&mut received).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+}
@@ -0,0 +1,139 @@
+use crate::error::{BitTorrentError, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Size and modification time of one payload file, used to detect whether
+/// the data on disk changed since the resume state was written.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub length: u64,
+    pub mtime_secs: u64,
+    pub mtime_nanos: u32,
+}
+
+impl FileStamp {
+    pub async fn read(path: impl AsRef<Path>) -> Result<Self> {
+        let metadata = tokio::fs::metadata(path).await?;
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(Self {
+            length: metadata.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+        })
+    }
+}
+
+/// Fast-resume state persisted next to a download.
+///
+/// The completed set is only trusted while every file still has the size
+/// and mtime recorded here; any write after the state was saved changes an
+/// mtime and forces a full recheck instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumeState {
+    pub info_hash: String,
+    pub num_pieces: usize,
+    pub completed: Vec<usize>,
+    pub files: Vec<FileStamp>,
+}
+
+impl ResumeState {
+    pub fn new(
+        info_hash: [u8; 20],
+        num_pieces: usize,
+        completed: Vec<usize>,
+        files: Vec<FileStamp>,
+    ) -> Self {
+        Self {
+            info_hash: hex::encode(info_hash),
+            num_pieces,
+            completed,
+            files,
+        }
+    }
+
+    /// Loads a saved state, returning `None` if there is none or it can't
+    /// be parsed. A broken resume file only costs a recheck.
+    pub async fn load(path: impl AsRef<Path>) -> Option<Self> {
+        let data = tokio::fs::read(path).await.ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    pub async fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let data =
+            serde_json::to_vec(self).map_err(|e| BitTorrentError::InvalidData(e.to_string()))?;
+
+        // Write then rename so a crash never leaves a half-written state
+        let tmp = temp_path(path);
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+
+    /// Returns the completed pieces if this state still describes the data
+    /// on disk for the given torrent.
+    pub fn validate(
+        &self,
+        info_hash: [u8; 20],
+        num_pieces: usize,
+        files: &[FileStamp],
+    ) -> Option<&[usize]> {
+        let valid = self.info_hash == hex::encode(info_hash)
+            && self.num_pieces == num_pieces
+            && self.files == files
+            && self.completed.iter().all(|&index| index < num_pieces);
+        valid.then_some(self.completed.as_slice())
+    }
+}
+
+/// Sibling path to write to before renaming over `path`.
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stamp(length: u64, mtime_secs: u64) -> FileStamp {
+        FileStamp {
+            length,
+            mtime_secs,
+            mtime_nanos: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resume_state_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.resume");
+        let files = vec![stamp(100, 1_700_000_000)];
+        let state = ResumeState::new([7; 20], 4, vec![0, 2], files.clone());
+        state.save(&path).await.unwrap();
+
+        let loaded = ResumeState::load(&path).await.unwrap();
+        assert_eq!(loaded.validate([7; 20], 4, &files), Some(&[0, 2][..]));
+    }
+
+    #[test]
+    fn test_resume_state_rejects_changed_files() {
+        let files = vec![stamp(100, 1_700_000_000)];
+        let state = ResumeState::new([7; 20], 4, vec![0, 2], files);
+
+        assert!(state
+            .validate([7; 20], 4, &[stamp(100, 1_700_000_001)])
+            .is_none());
+        assert!(state
+            .validate([7; 20], 4, &[stamp(99, 1_700_000_000)])
+            .is_none());
+        assert!(state
+            .validate([8; 20], 4, &[stamp(100, 1_700_000_000)])
+            .is_none());
+    }
+}
@@ -0,0 +1,629 @@
+use crate::{
+    choker::Choker,
+    error::{BitTorrentError, Result},
+    message::BlockRequest,
+    metadata::{self, UT_METADATA_ID},
+    peer::{allowed_fast_set, ExtensionRegistry, Peer, ALLOWED_FAST_COUNT},
+    pex::{PexMessage, UT_PEX_ID},
+    piece::PieceManager,
+    rate_limit::TorrentLimits,
+    stats::TransferStats,
+    storage::Storage,
+    swarm::BanList,
+    utils::generate_peer_id,
+    MAX_PEERS,
+};
+use std::collections::HashMap;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, watch, Mutex, Semaphore};
+use tokio::time::{timeout, Duration};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Largest block we'll serve; anything bigger is a misbehaving peer
+const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
+
+/// Everything needed to serve one torrent's verified pieces.
+#[derive(Clone)]
+pub struct SeedContext {
+    pub info_hash: [u8; 20],
+    /// The truncated v2 info-hash of a hybrid torrent, so peers in its v2
+    /// swarm are served too.
+    pub hybrid_info_hash: Option<[u8; 20]>,
+    pub piece_manager: Arc<Mutex<PieceManager>>,
+    pub storage: Arc<Mutex<Storage>>,
+    pub have_tx: broadcast::Sender<usize>,
+    pub stats: Arc<TransferStats>,
+    /// Where to report inbound peers' listen addresses, so the download
+    /// can connect back to them.
+    pub peer_tx: Option<mpsc::Sender<SocketAddrV4>>,
+    /// Where to report what peers tell us over `ut_pex`.
+    pub pex_tx: Option<mpsc::Sender<PexMessage>>,
+    /// Decides which connections we upload on.
+    pub choker: Arc<Mutex<Choker>>,
+    pub limits: TorrentLimits,
+    /// BEP 10 extensions offered to inbound peers.
+    pub extensions: Arc<ExtensionRegistry>,
+    /// The info dictionary, served over `ut_metadata`.
+    pub metadata: Option<Arc<Vec<u8>>>,
+    /// Peers turned away before the handshake is answered.
+    pub banned: BanList,
+}
+
+/// Accepts inbound peer connections for every registered torrent and serves
+/// block requests from the pieces we have on disk.
+pub struct PeerListener {
+    port: u16,
+    torrents: Arc<Mutex<HashMap<[u8; 20], SeedContext>>>,
+}
+
+impl PeerListener {
+    /// Binds the first free port in `port_range` (inclusive) and starts
+    /// accepting connections in the background.
+    pub async fn bind(port_range: (u16, u16)) -> Result<Self> {
+        let (first, last) = port_range;
+        let mut last_error = None;
+        for port in first..=last {
+            match TcpListener::bind(("0.0.0.0", port)).await {
+                Ok(listener) => return Ok(Self::start(listener)),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(last_error.map(BitTorrentError::Io).unwrap_or_else(|| {
+            BitTorrentError::Client(format!("No port available in {}-{}", first, last))
+        }))
+    }
+
+    fn start(listener: TcpListener) -> Self {
+        let port = listener
+            .local_addr()
+            .map(|addr| addr.port())
+            .unwrap_or_default();
+        let torrents = Arc::new(Mutex::new(HashMap::new()));
+        println!("Listening for incoming peers on port {}", port);
+
+        tokio::spawn(accept_loop(listener, Arc::clone(&torrents)));
+
+        Self { port, torrents }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub async fn register(&self, context: SeedContext) {
+        let mut torrents = self.torrents.lock().await;
+        if let Some(info_hash) = context.hybrid_info_hash {
+            torrents.insert(info_hash, context.clone());
+        }
+        torrents.insert(context.info_hash, context);
+    }
+
+    /// Stops serving the torrent registered with `info_hash`, in every
+    /// swarm it is in.
+    pub async fn unregister(&self, info_hash: &[u8; 20]) {
+        self.torrents
+            .lock()
+            .await
+            .retain(|_, context| context.info_hash != *info_hash);
+    }
+}
+
+async fn accept_loop(listener: TcpListener, torrents: Arc<Mutex<HashMap<[u8; 20], SeedContext>>>) {
+    let peer_id = generate_peer_id();
+    let slots = Arc::new(Semaphore::new(MAX_PEERS));
+
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                println!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+
+        // Drop the connection outright once we're at capacity
+        let Ok(permit) = Arc::clone(&slots).try_acquire_owned() else {
+            continue;
+        };
+
+        let torrents = Arc::clone(&torrents);
+        tokio::spawn(async move {
+            if let Err(e) = handle_inbound(stream, addr, peer_id, torrents).await {
+                println!("Inbound peer {} disconnected: {}", addr, e);
+            }
+            drop(permit);
+        });
+    }
+}
+
+async fn handle_inbound(
+    stream: TcpStream,
+    addr: SocketAddr,
+    peer_id: [u8; 20],
+    torrents: Arc<Mutex<HashMap<[u8; 20], SeedContext>>>,
+) -> Result<()> {
+    let SocketAddr::V4(addr) = addr else {
+        return Err(BitTorrentError::Peer("IPv6 peers are not supported".into()));
+    };
+
+    // Torrents that banned the peer don't answer to their info-hash
+    let info_hashes: Vec<[u8; 20]> = torrents
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, context)| !context.banned.contains(addr.ip()))
+        .map(|(&info_hash, _)| info_hash)
+        .collect();
+    let (peer, info_hash) = timeout(
+        HANDSHAKE_TIMEOUT,
+        Peer::accept(stream, addr, peer_id, &info_hashes),
+    )
+    .await??;
+
+    let mut context = torrents
+        .lock()
+        .await
+        .get(&info_hash)
+        .cloned()
+        .ok_or_else(|| BitTorrentError::Peer("Torrent no longer available".into()))?;
+    // The allowed-fast set comes from the info-hash the peer knows us by
+    context.info_hash = info_hash;
+
+    serve_peer(peer, context).await
+}
+
+/// Serves a connected peer until it disconnects: sends our bitfield, keeps
+/// it informed of new pieces with `Have`, and answers its block requests
+/// whenever the choker unchokes it.
+pub async fn serve_peer(mut peer: Peer, context: SeedContext) -> Result<()> {
+    peer.set_rate_limits(context.limits.for_peer());
+    let (choker_id, choke_rx) = context.choker.lock().await.register(peer.addr());
+    let result = serve_connection(peer, &context, choker_id, choke_rx).await;
+    context.choker.lock().await.unregister(choker_id);
+    result
+}
+
+async fn serve_connection(
+    mut peer: Peer,
+    context: &SeedContext,
+    choker_id: usize,
+    mut choke_rx: watch::Receiver<bool>,
+) -> Result<()> {
+    let (bitfield, num_pieces) = {
+        let pm = context.piece_manager.lock().await;
+        (pm.bitfield(), pm.num_pieces())
+    };
+    peer.set_num_pieces(num_pieces)?;
+    peer.send_bitfield(bitfield, num_pieces).await?;
+    peer.set_extensions(Arc::clone(&context.extensions));
+    peer.send_extended_handshake().await?;
+    send_allowed_fast(&mut peer, context).await?;
+
+    let mut have_rx = context.have_tx.subscribe();
+    let mut download_running = true;
+    let mut reported = false;
+    let mut interested = false;
+
+    loop {
+        tokio::select! {
+            received = peer.recv() => {
+                if received?.is_none() {
+                    return Ok(());
+                }
+
+                // Its source port is ephemeral; only the handshake says
+                // where it can be reached
+                if !reported {
+                    if let (Some(port), Some(peer_tx)) = (peer.listen_port(), &context.peer_tx) {
+                        let _ = peer_tx.try_send(SocketAddrV4::new(*peer.addr().ip(), port));
+                        reported = true;
+                    }
+                }
+
+                if peer.peer_interested() != interested {
+                    interested = peer.peer_interested();
+                    context.choker.lock().await.set_interested(choker_id, interested);
+                }
+
+                handle_extended_messages(&mut peer, context).await?;
+                let sent = answer_requests(&mut peer, context).await?;
+                context.choker.lock().await.record_upload(choker_id, sent);
+            }
+            changed = choke_rx.changed() => {
+                if changed.is_err() {
+                    return Ok(());
+                }
+                let choking = *choke_rx.borrow_and_update();
+                peer.set_choking(choking).await?;
+            }
+            have = have_rx.recv(), if download_running => match have {
+                Ok(index) => peer.send_have(index).await?,
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => download_running = false,
+            },
+        }
+    }
+}
+
+/// Lets the peer request the pieces of its allowed-fast set we have, even
+/// while we choke it (BEP 6).
+pub(crate) async fn send_allowed_fast(peer: &mut Peer, context: &SeedContext) -> Result<()> {
+    let allowed_fast: Vec<u32> = {
+        let pm = context.piece_manager.lock().await;
+        allowed_fast_set(
+            *peer.addr().ip(),
+            &context.info_hash,
+            pm.num_pieces(),
+            ALLOWED_FAST_COUNT,
+        )
+        .into_iter()
+        .filter(|&index| pm.is_completed(index as usize))
+        .collect()
+    };
+    peer.send_allowed_fast(&allowed_fast).await
+}
+
+/// Answers the block and hash requests the peer sent since the last call.
+/// Returns the number of block bytes sent.
+pub(crate) async fn answer_requests(peer: &mut Peer, context: &SeedContext) -> Result<u64> {
+    let mut sent = 0;
+    for request in peer.take_requests() {
+        sent += serve_request(peer, context, request).await?;
+    }
+
+    // Hashes are small enough to send even while choking
+    for request in peer.take_hash_requests() {
+        let hashes = context.piece_manager.lock().await.hashes_for(&request);
+        match hashes {
+            Some(hashes) => peer.send_hashes(request, &hashes).await?,
+            None => peer.reject_hash_request(request).await?,
+        }
+    }
+    Ok(sent)
+}
+
+/// Answers the `ut_metadata` requests the peer sent since the last call,
+/// and passes on the peers it told us about over `ut_pex`.
+pub(crate) async fn handle_extended_messages(peer: &mut Peer, context: &SeedContext) -> Result<()> {
+    for (id, payload) in peer.take_extended_messages() {
+        match id {
+            UT_METADATA_ID => {
+                let info = context.metadata.as_deref().map(Vec::as_slice);
+                metadata::serve_metadata(peer, info, &payload).await?;
+            }
+            UT_PEX_ID => {
+                let message = PexMessage::decode(&payload)?;
+                if let Some(pex_tx) = &context.pex_tx {
+                    let _ = pex_tx.try_send(message);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+async fn serve_request(
+    peer: &mut Peer,
+    context: &SeedContext,
+    request: BlockRequest,
+) -> Result<u64> {
+    let index = request.index as usize;
+    let valid = {
+        let pm = context.piece_manager.lock().await;
+        pm.is_completed(index)
+            && request.length > 0
+            && request.length <= MAX_REQUEST_LENGTH
+            && pm.get_piece(index).is_some_and(|piece| {
+                request.begin as usize + request.length as usize <= piece.length()
+            })
+    };
+
+    if !valid {
+        println!(
+            "Rejecting invalid request from {}: {:?}",
+            peer.addr(),
+            request
+        );
+        peer.reject_request(request).await?;
+        return Ok(0);
+    }
+
+    let data = context
+        .storage
+        .lock()
+        .await
+        .read_block(index, request.begin as usize, request.length as usize)
+        .await?;
+    peer.send_block(request, &data).await?;
+    context.stats.add_uploaded(data.len() as u64);
+    Ok(data.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle;
+    use crate::message::{ExtendedHandshake, HashRequest, Message, MessageId};
+    use crate::metadata::UT_METADATA;
+    use crate::peer::PeerCodec;
+    use crate::pex::PexPeer;
+    use crate::piece::PieceInfo;
+    use crate::BLOCK_SIZE;
+    use futures_util::{SinkExt, StreamExt};
+    use sha1::{Digest, Sha1};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_util::codec::Framed;
+
+    // Serves `piece_manager` and `storage` as info-hash [9; 20] with
+    // everything optional left out
+    fn test_context(piece_manager: PieceManager, storage: Storage) -> SeedContext {
+        let (have_tx, _) = broadcast::channel(4);
+        SeedContext {
+            info_hash: [9; 20],
+            hybrid_info_hash: None,
+            piece_manager: Arc::new(Mutex::new(piece_manager)),
+            storage: Arc::new(Mutex::new(storage)),
+            have_tx,
+            stats: Arc::new(TransferStats::new(0)),
+            peer_tx: None,
+            pex_tx: None,
+            choker: Arc::new(Mutex::new(Choker::default())),
+            limits: TorrentLimits::default(),
+            extensions: Arc::default(),
+            metadata: None,
+            banned: BanList::new(),
+        }
+    }
+
+    fn handshake_with(reserved: [u8; 8], info_hash: [u8; 20]) -> Vec<u8> {
+        let mut handshake = vec![19u8];
+        handshake.extend_from_slice(b"BitTorrent protocol");
+        handshake.extend_from_slice(&reserved);
+        handshake.extend_from_slice(&info_hash);
+        handshake.extend_from_slice(&[1; 20]);
+        handshake
+    }
+
+    #[tokio::test]
+    async fn test_serves_requested_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("payload");
+        let mut storage = Storage::with_layout(path.clone(), vec![(path, 8)], 8)
+            .await
+            .unwrap();
+        storage.write_piece(0, b"seedme!!").await.unwrap();
+
+        let hash: [u8; 20] = Sha1::digest(b"seedme!!").into();
+        let mut piece_manager = PieceManager::new(8, vec![hash], 8);
+        piece_manager.mark_completed(0);
+
+        let info_hash = [9u8; 20];
+        let listener = PeerListener::start(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        listener
+            .register(test_context(piece_manager, storage))
+            .await;
+
+        let mut stream = TcpStream::connect(("127.0.0.1", listener.port()))
+            .await
+            .unwrap();
+        stream
+            .write_all(&handshake_with([0; 8], info_hash))
+            .await
+            .unwrap();
+
+        let mut reply = [0u8; 68];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[28..48], &info_hash);
+
+        // Bitfield with piece 0 set
+        let mut bitfield = [0u8; 6];
+        stream.read_exact(&mut bitfield).await.unwrap();
+        assert_eq!(bitfield, [0, 0, 0, 2, 5, 0x80]);
+
+        // Interested, then expect an unchoke for a free upload slot
+        stream.write_all(&[0, 0, 0, 1, 2]).await.unwrap();
+        let mut unchoke = [0u8; 5];
+        stream.read_exact(&mut unchoke).await.unwrap();
+        assert_eq!(unchoke, [0, 0, 0, 1, 1]);
+
+        let mut request = vec![0, 0, 0, 13, 6];
+        request.extend_from_slice(&BlockRequest::new(0, 2, 4).to_payload());
+        stream.write_all(&request).await.unwrap();
+
+        let mut piece = [0u8; 17];
+        stream.read_exact(&mut piece).await.unwrap();
+        assert_eq!(&piece[..5], &[0, 0, 0, 13, 7]);
+        assert_eq!(&piece[13..], b"edme");
+    }
+
+    #[tokio::test]
+    async fn test_answers_hash_requests_in_hybrid_v2_swarm() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("payload");
+        let storage = Storage::with_layout(path.clone(), vec![(path, BLOCK_SIZE * 3)], BLOCK_SIZE)
+            .await
+            .unwrap();
+
+        // Three one-block pieces of a single v2 file
+        let layer: Vec<[u8; 32]> = (0..3u8).map(|i| merkle::hash_block(&[i])).collect();
+        let root = merkle::root(&layer, 4, merkle::pad_hash(1));
+        let pieces = (0..3).map(|i| PieceInfo::v2(i, BLOCK_SIZE)).collect();
+        let mut piece_manager = PieceManager::from_pieces(BLOCK_SIZE, pieces);
+        assert!(piece_manager.add_merkle_file(root, 0, BLOCK_SIZE * 3, Some(layer.clone())));
+
+        let listener = PeerListener::start(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        listener
+            .register(SeedContext {
+                hybrid_info_hash: Some([8; 20]),
+                ..test_context(piece_manager, storage)
+            })
+            .await;
+
+        // Connect in the v2 swarm, with the v2 bit set
+        let mut stream = TcpStream::connect(("127.0.0.1", listener.port()))
+            .await
+            .unwrap();
+        stream
+            .write_all(&handshake_with([0, 0, 0, 0, 0, 0, 0, 0x10], [8; 20]))
+            .await
+            .unwrap();
+
+        let mut reply = [0u8; 68];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[28..48], &[8; 20]);
+        assert_eq!(reply[27] & 0x10, 0x10);
+
+        let mut framed = Framed::new(stream, PeerCodec::new());
+        let request = HashRequest::new(root, 0, 0, 4, 2);
+        let unknown = HashRequest::new([3; 32], 0, 0, 4, 2);
+        for request in [request, unknown] {
+            framed
+                .send(Message::new(MessageId::HashRequest, request.to_payload()))
+                .await
+                .unwrap();
+        }
+
+        let mut answers = Vec::new();
+        while answers.len() < 2 {
+            let message = framed.next().await.unwrap().unwrap();
+            if matches!(message.id, MessageId::Hashes | MessageId::HashReject) {
+                answers.push(message);
+            }
+        }
+        assert_eq!(answers[0].id, MessageId::Hashes);
+        let (header, hashes) = answers[0].payload.split_at(HashRequest::PAYLOAD_LEN);
+        assert_eq!(HashRequest::from_payload(header), Some(request));
+        let hashes: Vec<[u8; 32]> = hashes
+            .chunks_exact(32)
+            .map(|hash| hash.try_into().unwrap())
+            .collect();
+        assert_eq!(&hashes[..3], layer.as_slice());
+        assert!(merkle::verify(&root, 0, &hashes, &[]));
+        assert_eq!(answers[1].id, MessageId::HashReject);
+        assert_eq!(answers[1].payload, unknown.to_payload());
+    }
+
+    #[tokio::test]
+    async fn test_serves_metadata_and_forwards_pex() {
+        const REMOTE_UT_METADATA_ID: u8 = 3;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("payload");
+        let storage = Storage::with_layout(path.clone(), vec![(path, 8)], 8)
+            .await
+            .unwrap();
+        let info = b"d4:name4:teste".to_vec();
+
+        let listener = PeerListener::start(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let (pex_tx, mut pex_rx) = mpsc::channel(4);
+        let extensions = ExtensionRegistry::new().with_metadata_size(info.len());
+        listener
+            .register(SeedContext {
+                pex_tx: Some(pex_tx),
+                extensions: Arc::new(extensions),
+                metadata: Some(Arc::new(info.clone())),
+                ..test_context(PieceManager::new(8, vec![[0; 20]], 8), storage)
+            })
+            .await;
+
+        let mut stream = TcpStream::connect(("127.0.0.1", listener.port()))
+            .await
+            .unwrap();
+        stream
+            .write_all(&handshake_with([0, 0, 0, 0, 0, 0x10, 0, 0], [9; 20]))
+            .await
+            .unwrap();
+        let mut reply = [0u8; 68];
+        stream.read_exact(&mut reply).await.unwrap();
+
+        let mut framed = Framed::new(stream, PeerCodec::new());
+        let mut ours = ExtendedHandshake::default();
+        ours.m.insert(UT_METADATA.into(), REMOTE_UT_METADATA_ID);
+        let mut payload = vec![0];
+        payload.extend(serde_bencode::to_bytes(&ours).unwrap());
+        framed
+            .send(Message::new(MessageId::Extended, payload))
+            .await
+            .unwrap();
+
+        // Piece 0 exists, piece 1 doesn't
+        for request in [
+            &b"d8:msg_typei0e5:piecei0ee"[..],
+            b"d8:msg_typei0e5:piecei1ee",
+        ] {
+            let mut payload = vec![UT_METADATA_ID];
+            payload.extend_from_slice(request);
+            framed
+                .send(Message::new(MessageId::Extended, payload))
+                .await
+                .unwrap();
+        }
+        let added = PexMessage {
+            added: vec![PexPeer {
+                addr: "10.0.0.1:6881".parse().unwrap(),
+                flags: 0,
+            }],
+            dropped: Vec::new(),
+        };
+        let mut payload = vec![UT_PEX_ID];
+        payload.extend(added.encode().unwrap());
+        framed
+            .send(Message::new(MessageId::Extended, payload))
+            .await
+            .unwrap();
+
+        let mut replies = Vec::new();
+        while replies.len() < 2 {
+            let message = framed.next().await.unwrap().unwrap();
+            match message.payload.split_first() {
+                Some((&0, payload)) if message.id == MessageId::Extended => {
+                    let theirs: ExtendedHandshake = serde_bencode::from_bytes(payload).unwrap();
+                    assert_eq!(theirs.metadata_size, Some(info.len()));
+                }
+                Some((&REMOTE_UT_METADATA_ID, payload)) if message.id == MessageId::Extended => {
+                    replies.push(payload.to_vec())
+                }
+                _ => {}
+            }
+        }
+        let mut expected = b"d8:msg_typei1e5:piecei0e10:total_sizei14ee".to_vec();
+        expected.extend_from_slice(&info);
+        assert_eq!(replies[0], expected);
+        assert_eq!(replies[1], b"d8:msg_typei2e5:piecei1ee");
+        assert_eq!(pex_rx.recv().await.unwrap(), added);
+    }
+
+    #[tokio::test]
+    async fn test_banned_peer_gets_no_handshake() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("payload");
+        let storage = Storage::with_layout(path.clone(), vec![(path, 8)], 8)
+            .await
+            .unwrap();
+        let banned = BanList::new();
+        banned.insert(std::net::Ipv4Addr::LOCALHOST);
+
+        let listener = PeerListener::start(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        listener
+            .register(SeedContext {
+                banned,
+                ..test_context(PieceManager::new(8, vec![[0; 20]], 8), storage)
+            })
+            .await;
+
+        let mut stream = TcpStream::connect(("127.0.0.1", listener.port()))
+            .await
+            .unwrap();
+        stream
+            .write_all(&handshake_with([0; 8], [9; 20]))
+            .await
+            .unwrap();
+
+        let mut reply = Vec::new();
+        let _ = stream.read_to_end(&mut reply).await;
+        assert!(reply.is_empty());
+    }
+}
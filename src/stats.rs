@@ -0,0 +1,62 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Byte counters reported to trackers, plus the bytes thrown away because
+/// they failed verification. Shared between the download loop, the
+/// connections we seed to and the tracker task.
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    left: AtomicU64,
+    wasted: AtomicU64,
+}
+
+impl TransferStats {
+    pub fn new(left: u64) -> Self {
+        Self {
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            left: AtomicU64::new(left),
+            wasted: AtomicU64::new(0),
+        }
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Records a verified piece: counts it as downloaded and no longer left.
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+        let _ = self
+            .left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                Some(left.saturating_sub(bytes))
+            });
+    }
+
+    /// Records a downloaded piece that failed its hash check.
+    pub fn add_wasted(&self, bytes: u64) {
+        self.wasted.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn set_left(&self, left: u64) {
+        self.left.store(left, Ordering::Relaxed);
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn left(&self) -> u64 {
+        self.left.load(Ordering::Relaxed)
+    }
+
+    pub fn wasted(&self) -> u64 {
+        self.wasted.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,400 @@
+use crate::{
+    error::{BitTorrentError, Result},
+    resume::FileStamp,
+    torrent::{FileMode, Info, Torrent},
+};
+use std::io::SeekFrom;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+// Names Windows refuses to create regardless of extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+#[derive(Debug, Clone)]
+struct FileEntry {
+    path: PathBuf,
+    // Offset of the file's first byte within the torrent's byte stream
+    offset: u64,
+    length: u64,
+    // Pad files and the gaps between v2 files read as zeros and are never
+    // written to disk
+    padding: bool,
+}
+
+/// On-disk backing store for a torrent's payload.
+///
+/// Every file in the torrent is preallocated up front and verified pieces
+/// are written at their offset in the concatenated byte stream as soon as
+/// they arrive, so pieces spanning file boundaries are split across the
+/// files they cover and memory use stays bounded by in-flight pieces.
+#[derive(Debug)]
+pub struct Storage {
+    root: PathBuf,
+    files: Vec<FileEntry>,
+    piece_length: usize,
+    total_length: usize,
+    // Whether any payload data was already on disk when the files were opened
+    had_existing_data: bool,
+}
+
+impl Storage {
+    /// Lays out `torrent` under `download_dir`. Single-file torrents are
+    /// written to `<download_dir>/<name>`, multi-file torrents to the tree
+    /// described by each file's path under `<download_dir>/<name>/`.
+    pub async fn create(torrent: &Torrent, download_dir: impl AsRef<Path>) -> Result<Self> {
+        let root = download_dir
+            .as_ref()
+            .join(sanitize_component(&torrent.info.name)?);
+
+        let layout = match &torrent.info.files {
+            FileMode::SingleFile { length } => vec![(Some(root.clone()), *length)],
+            FileMode::MultiFile { files } => files
+                .iter()
+                .map(|file| {
+                    if file.is_padding() {
+                        return Ok((None, file.length));
+                    }
+                    let relative = sanitize_path(&file.path)?;
+                    Ok((Some(root.join(relative)), file.length))
+                })
+                .collect::<Result<Vec<_>>>()?,
+            FileMode::V2Only {} => Self::v2_layout(&root, &torrent.info)?,
+        };
+
+        Self::with_padded_layout(root, layout, torrent.info.piece_length).await
+    }
+
+    // Every v2 file starts on a piece boundary, as if padded with zeros.
+    // A tree holding a single file is a single-file torrent.
+    fn v2_layout(root: &Path, info: &Info) -> Result<Vec<(Option<PathBuf>, usize)>> {
+        let files = info.v2_files();
+        if let [file] = files.as_slice() {
+            if file.path.len() == 1 {
+                return Ok(vec![(Some(root.to_path_buf()), file.length)]);
+            }
+        }
+
+        let mut layout = Vec::with_capacity(files.len() * 2);
+        for (i, file) in files.iter().enumerate() {
+            layout.push((Some(root.join(sanitize_path(&file.path)?)), file.length));
+            let gap = file.length.next_multiple_of(info.piece_length) - file.length;
+            if gap > 0 && i + 1 < files.len() {
+                layout.push((None, gap));
+            }
+        }
+        Ok(layout)
+    }
+
+    #[cfg(test)]
+    pub(crate) async fn with_layout(
+        root: PathBuf,
+        layout: Vec<(PathBuf, usize)>,
+        piece_length: usize,
+    ) -> Result<Self> {
+        let layout = layout
+            .into_iter()
+            .map(|(path, length)| (Some(path), length))
+            .collect();
+        Self::with_padded_layout(root, layout, piece_length).await
+    }
+
+    // Entries without a path are padding
+    pub(crate) async fn with_padded_layout(
+        root: PathBuf,
+        layout: Vec<(Option<PathBuf>, usize)>,
+        piece_length: usize,
+    ) -> Result<Self> {
+        let mut files = Vec::with_capacity(layout.len());
+        let mut offset = 0u64;
+        let mut had_existing_data = false;
+
+        for (path, length) in layout {
+            let Some(path) = path else {
+                files.push(FileEntry {
+                    path: PathBuf::new(),
+                    offset,
+                    length: length as u64,
+                    padding: true,
+                });
+                offset += length as u64;
+                continue;
+            };
+
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .await?;
+
+            // Preallocate so pieces can land at any offset in any order
+            let existing_length = file.metadata().await?.len();
+            had_existing_data |= existing_length > 0;
+            if existing_length != length as u64 {
+                file.set_len(length as u64).await?;
+            }
+
+            files.push(FileEntry {
+                path,
+                offset,
+                length: length as u64,
+                padding: false,
+            });
+            offset += length as u64;
+        }
+
+        Ok(Self {
+            root,
+            files,
+            piece_length,
+            total_length: offset as usize,
+            had_existing_data,
+        })
+    }
+
+    pub async fn write_piece(&mut self, index: usize, data: &[u8]) -> Result<()> {
+        let mut written = 0;
+        for (entry, file_offset, range) in self.spans(self.piece_offset(index), data.len()) {
+            if entry.padding {
+                written += range.len();
+                continue;
+            }
+            let mut file = OpenOptions::new().write(true).open(&entry.path).await?;
+            file.seek(SeekFrom::Start(file_offset)).await?;
+            file.write_all(&data[range.clone()]).await?;
+            file.flush().await?;
+            written += range.len();
+        }
+
+        if written != data.len() {
+            return Err(BitTorrentError::Piece(format!(
+                "Piece {} extends past the end of the torrent",
+                index
+            )));
+        }
+        Ok(())
+    }
+
+    pub async fn read_piece(&mut self, index: usize, length: usize) -> Result<Vec<u8>> {
+        self.read_block(index, 0, length).await
+    }
+
+    pub async fn read_block(
+        &mut self,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; length];
+        let mut read = 0;
+        let offset = self.piece_offset(index) + begin as u64;
+        for (entry, file_offset, range) in self.spans(offset, length) {
+            // Already zeroed
+            if entry.padding {
+                read += range.len();
+                continue;
+            }
+            let mut file = OpenOptions::new().read(true).open(&entry.path).await?;
+            file.seek(SeekFrom::Start(file_offset)).await?;
+            file.read_exact(&mut data[range.clone()]).await?;
+            read += range.len();
+        }
+
+        if read != length {
+            return Err(BitTorrentError::Piece(format!(
+                "Piece {} extends past the end of the torrent",
+                index
+            )));
+        }
+        Ok(data)
+    }
+
+    pub async fn flush(&mut self) -> Result<()> {
+        for entry in self.files.iter().filter(|entry| !entry.padding) {
+            let file = OpenOptions::new().write(true).open(&entry.path).await?;
+            file.sync_data().await?;
+        }
+        Ok(())
+    }
+
+    /// Directory (multi-file) or file (single-file) the payload is written to.
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn total_length(&self) -> usize {
+        self.total_length
+    }
+
+    pub fn had_existing_data(&self) -> bool {
+        self.had_existing_data
+    }
+
+    /// Where the fast-resume state for this payload is kept: next to the
+    /// payload as `<name>.resume`.
+    pub fn resume_path(&self) -> PathBuf {
+        let mut name = self.root.file_name().unwrap_or_default().to_os_string();
+        name.push(".resume");
+        self.root.with_file_name(name)
+    }
+
+    pub async fn file_stamps(&self) -> Result<Vec<FileStamp>> {
+        let mut stamps = Vec::with_capacity(self.files.len());
+        for entry in self.files.iter().filter(|entry| !entry.padding) {
+            stamps.push(FileStamp::read(&entry.path).await?);
+        }
+        Ok(stamps)
+    }
+
+    fn piece_offset(&self, index: usize) -> u64 {
+        (index * self.piece_length) as u64
+    }
+
+    // Splits the byte range [offset, offset + length) into per-file chunks,
+    // returning each file, the position within it, and the matching range of
+    // the caller's buffer.
+    fn spans(&self, offset: u64, length: usize) -> Vec<(&FileEntry, u64, std::ops::Range<usize>)> {
+        let end = offset + length as u64;
+        self.files
+            .iter()
+            .filter(|entry| entry.length > 0)
+            .filter(|entry| entry.offset < end && entry.offset + entry.length > offset)
+            .map(|entry| {
+                let start = offset.max(entry.offset);
+                let stop = end.min(entry.offset + entry.length);
+                let buf_start = (start - offset) as usize;
+                let buf_end = (stop - offset) as usize;
+                (entry, start - entry.offset, buf_start..buf_end)
+            })
+            .collect()
+    }
+}
+
+/// Turns a torrent-supplied path into a relative path that cannot escape
+/// the download directory.
+pub fn sanitize_path(components: &[String]) -> Result<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in components {
+        path.push(sanitize_component(component)?);
+    }
+
+    if path.as_os_str().is_empty() {
+        return Err(BitTorrentError::InvalidData(
+            "Empty file path in torrent".into(),
+        ));
+    }
+    Ok(path)
+}
+
+/// Sanitizes a single path component from a torrent.
+///
+/// Separators, control characters and characters reserved on Windows are
+/// replaced, trailing dots and spaces are trimmed, and reserved device
+/// names are prefixed. Components that would refer to the current or
+/// parent directory are rejected outright.
+pub fn sanitize_component(component: &str) -> Result<String> {
+    let cleaned: String = component
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let cleaned = cleaned.trim_end_matches(['.', ' ']).to_string();
+
+    if cleaned.is_empty() || component == "." || component == ".." {
+        return Err(BitTorrentError::InvalidData(format!(
+            "Unsafe path component in torrent: {:?}",
+            component
+        )));
+    }
+
+    let stem = cleaned.split('.').next().unwrap_or_default();
+    let cleaned = if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    {
+        format!("_{}", cleaned)
+    } else {
+        cleaned
+    };
+
+    // Belt and braces: the result must be exactly one normal component
+    let mut parsed = Path::new(&cleaned).components();
+    match (parsed.next(), parsed.next()) {
+        (Some(Component::Normal(_)), None) => Ok(cleaned),
+        _ => Err(BitTorrentError::InvalidData(format!(
+            "Unsafe path component in torrent: {:?}",
+            component
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pieces_written_out_of_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("payload");
+        let layout = vec![(path.clone(), 10)];
+        let mut storage = Storage::with_layout(path.clone(), layout, 4).await.unwrap();
+
+        storage.write_piece(2, b"ij").await.unwrap();
+        storage.write_piece(0, b"abcd").await.unwrap();
+        storage.write_piece(1, b"efgh").await.unwrap();
+        storage.flush().await.unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"abcdefghij");
+        assert_eq!(storage.read_piece(1, 4).await.unwrap(), b"efgh");
+    }
+
+    #[tokio::test]
+    async fn test_pieces_span_file_boundaries() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("folder");
+        let layout = vec![
+            (root.join("a.bin"), 3),
+            (root.join("empty"), 0),
+            (root.join("sub").join("b.bin"), 5),
+            (root.join("c.bin"), 2),
+        ];
+        let mut storage = Storage::with_layout(root.clone(), layout, 4).await.unwrap();
+
+        storage.write_piece(0, b"abcd").await.unwrap();
+        storage.write_piece(1, b"efgh").await.unwrap();
+        storage.write_piece(2, b"ij").await.unwrap();
+
+        assert_eq!(std::fs::read(root.join("a.bin")).unwrap(), b"abc");
+        assert_eq!(std::fs::read(root.join("empty")).unwrap(), b"");
+        assert_eq!(std::fs::read(root.join("sub/b.bin")).unwrap(), b"defgh");
+        assert_eq!(std::fs::read(root.join("c.bin")).unwrap(), b"ij");
+        assert_eq!(storage.read_piece(1, 4).await.unwrap(), b"efgh");
+    }
+
+    #[test]
+    fn test_sanitize_hostile_paths() {
+        assert!(sanitize_component("..").is_err());
+        assert!(sanitize_component(".").is_err());
+        assert!(sanitize_component("").is_err());
+        assert_eq!(sanitize_component("/etc").unwrap(), "_etc");
+        assert_eq!(sanitize_component("..\\..\\x").unwrap(), ".._.._x");
+        assert_eq!(sanitize_component("C:").unwrap(), "C_");
+        assert_eq!(sanitize_component("con.txt").unwrap(), "_con.txt");
+        assert_eq!(sanitize_component("notes. ").unwrap(), "notes");
+
+        let path = sanitize_path(&["docs".into(), "readme.md".into()]).unwrap();
+        assert_eq!(path, PathBuf::from("docs").join("readme.md"));
+        assert!(sanitize_path(&["docs".into(), "..".into(), "x".into()]).is_err());
+    }
+}
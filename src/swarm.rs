@@ -0,0 +1,256 @@
+use crate::pex::{PexPeer, FLAG_REACHABLE};
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+// Wait after the first failed connect; doubles with every further failure
+const CONNECT_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(30 * 60);
+// Candidates are forgotten after this many failed connects in a row
+const MAX_CONNECT_FAILURES: u32 = 5;
+// A peer that disconnected is dialed again after this long
+const RECONNECT_DELAY: Duration = Duration::from_secs(2 * 60);
+
+/// Where a candidate address was learned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    Pex,
+    Inbound,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CandidateState {
+    Idle,
+    Connecting,
+    Connected,
+}
+
+#[derive(Debug, Clone)]
+struct Candidate {
+    source: PeerSource,
+    // PEX flags, if the peer was learned over PEX
+    flags: u8,
+    state: CandidateState,
+    failures: u32,
+    retry_at: Instant,
+    // Insertion order, so older candidates are dialed first
+    seq: u64,
+}
+
+/// IPs banned from one torrent's swarm. Clones share the set, so inbound
+/// connections can be checked against the bans the download makes.
+#[derive(Debug, Clone, Default)]
+pub struct BanList(Arc<Mutex<HashSet<Ipv4Addr>>>);
+
+impl BanList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, ip: Ipv4Addr) {
+        self.0.lock().expect("ban list lock poisoned").insert(ip);
+    }
+
+    pub fn contains(&self, ip: &Ipv4Addr) -> bool {
+        self.0.lock().expect("ban list lock poisoned").contains(ip)
+    }
+}
+
+/// Addresses we may connect to for one torrent, with their connection
+/// state. Failed connects are retried with exponential backoff; banned
+/// IPs are never dialed again.
+#[derive(Debug, Default)]
+pub struct PeerPool {
+    candidates: HashMap<SocketAddrV4, Candidate>,
+    banned: BanList,
+    next_seq: u64,
+}
+
+impl PeerPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `addr` as a candidate. Returns false if it was already known
+    /// or its IP is banned.
+    pub fn add(&mut self, addr: SocketAddrV4, source: PeerSource) -> bool {
+        self.insert(addr, source, 0)
+    }
+
+    /// Adds a peer learned over PEX, keeping its flags.
+    pub fn add_pex(&mut self, peer: PexPeer) -> bool {
+        self.insert(peer.addr, PeerSource::Pex, peer.flags)
+    }
+
+    fn insert(&mut self, addr: SocketAddrV4, source: PeerSource, flags: u8) -> bool {
+        if self.banned.contains(addr.ip()) || self.candidates.contains_key(&addr) {
+            return false;
+        }
+        self.candidates.insert(
+            addr,
+            Candidate {
+                source,
+                flags,
+                state: CandidateState::Idle,
+                failures: 0,
+                retry_at: Instant::now(),
+                seq: self.next_seq,
+            },
+        );
+        self.next_seq += 1;
+        true
+    }
+
+    /// Forgets a PEX candidate another peer reported as gone, unless we
+    /// are already talking to it.
+    pub fn remove_dropped(&mut self, addr: SocketAddrV4) {
+        if self.candidates.get(&addr).is_some_and(|candidate| {
+            candidate.source == PeerSource::Pex && candidate.state == CandidateState::Idle
+        }) {
+            self.candidates.remove(&addr);
+        }
+    }
+
+    /// Picks the next address to dial and marks it as connecting.
+    /// Candidates that never failed come first, then those known to be
+    /// reachable, then the oldest.
+    pub fn next_to_dial(&mut self) -> Option<SocketAddrV4> {
+        let now = Instant::now();
+        let (&addr, candidate) = self
+            .candidates
+            .iter_mut()
+            .filter(|(_, candidate)| {
+                candidate.state == CandidateState::Idle && candidate.retry_at <= now
+            })
+            .min_by_key(|(_, candidate)| {
+                (
+                    candidate.failures,
+                    candidate.flags & FLAG_REACHABLE == 0,
+                    candidate.seq,
+                )
+            })?;
+        candidate.state = CandidateState::Connecting;
+        Some(addr)
+    }
+
+    pub fn connect_succeeded(&mut self, addr: SocketAddrV4) {
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.state = CandidateState::Connected;
+            candidate.failures = 0;
+        }
+    }
+
+    pub fn connect_failed(&mut self, addr: SocketAddrV4) {
+        let Some(candidate) = self.candidates.get_mut(&addr) else {
+            return;
+        };
+        candidate.failures += 1;
+        if candidate.failures >= MAX_CONNECT_FAILURES {
+            self.candidates.remove(&addr);
+            return;
+        }
+        let backoff = CONNECT_BACKOFF
+            .saturating_mul(1 << (candidate.failures - 1))
+            .min(MAX_CONNECT_BACKOFF);
+        candidate.state = CandidateState::Idle;
+        candidate.retry_at = Instant::now() + backoff;
+    }
+
+    /// Returns a connected peer to the pool, to be redialed later.
+    pub fn disconnected(&mut self, addr: SocketAddrV4) {
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.state = CandidateState::Idle;
+            candidate.retry_at = Instant::now() + RECONNECT_DELAY;
+        }
+    }
+
+    /// Stops dialing every address on `addr`'s IP, for good.
+    pub fn ban(&mut self, addr: SocketAddrV4) {
+        self.banned.insert(*addr.ip());
+        self.candidates.retain(|known, _| known.ip() != addr.ip());
+    }
+
+    pub fn is_banned(&self, addr: SocketAddrV4) -> bool {
+        self.banned.contains(addr.ip())
+    }
+
+    /// Handle on this pool's bans, which stays up to date.
+    pub fn ban_list(&self) -> BanList {
+        self.banned.clone()
+    }
+
+    /// When the next idle candidate becomes ready to dial.
+    pub fn next_retry(&self) -> Option<Instant> {
+        self.candidates
+            .values()
+            .filter(|candidate| candidate.state == CandidateState::Idle)
+            .map(|candidate| candidate.retry_at)
+            .min()
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(last_octet: u8) -> SocketAddrV4 {
+        SocketAddrV4::new([10, 0, 0, last_octet].into(), 6881)
+    }
+
+    #[test]
+    fn test_dial_order_and_backoff() {
+        let mut pool = PeerPool::new();
+        assert!(pool.add(addr(1), PeerSource::Tracker));
+        assert!(pool.add_pex(PexPeer {
+            addr: addr(2),
+            flags: FLAG_REACHABLE,
+        }));
+        assert!(!pool.add(addr(1), PeerSource::Dht));
+
+        // Reachable PEX peer first, then the tracker peer
+        assert_eq!(pool.next_to_dial(), Some(addr(2)));
+        assert_eq!(pool.next_to_dial(), Some(addr(1)));
+        assert_eq!(pool.next_to_dial(), None);
+
+        pool.connect_failed(addr(1));
+        assert_eq!(pool.next_to_dial(), None);
+        let retry = pool.next_retry().unwrap();
+        assert!(retry >= Instant::now() + CONNECT_BACKOFF - Duration::from_secs(1));
+
+        pool.connect_succeeded(addr(2));
+        pool.disconnected(addr(2));
+        assert_eq!(pool.len(), 2);
+
+        for _ in 1..MAX_CONNECT_FAILURES {
+            pool.connect_failed(addr(1));
+        }
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn test_ban_and_dropped() {
+        let mut pool = PeerPool::new();
+        pool.add(addr(1), PeerSource::Tracker);
+        pool.add(SocketAddrV4::new(*addr(1).ip(), 7000), PeerSource::Inbound);
+        pool.add(addr(2), PeerSource::Pex);
+
+        pool.ban(addr(1));
+        assert!(pool.is_banned(SocketAddrV4::new(*addr(1).ip(), 1)));
+        assert!(!pool.add(addr(1), PeerSource::Dht));
+        assert_eq!(pool.len(), 1);
+
+        pool.remove_dropped(addr(2));
+        assert!(pool.is_empty());
+    }
+}
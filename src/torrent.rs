@@ -1,17 +1,109 @@
-use serde::{Deserialize, Serialize};
-use sha1::{Digest, Sha1};
-use std::path::Path;
+use crate::bencode::{self, Value};
 use crate::error::BitTorrentError;
 use serde::de::{self, Deserializer, Visitor};
 use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use serde_bytes::{ByteBuf, Bytes};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::collections::BTreeMap;
 use std::fmt;
+use std::path::Path;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Torrent {
+    // Optional when the torrent only lists trackers in `announce-list`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub announce: String,
+    // Tracker tiers (BEP 12); takes precedence over `announce` when present
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "lossy_string"
+    )]
+    pub comment: Option<String>,
+    #[serde(
+        rename = "created by",
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "lossy_string"
+    )]
+    pub created_by: Option<String>,
+    // Seconds since the Unix epoch
+    #[serde(
+        rename = "creation date",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "lossy_string"
+    )]
+    pub encoding: Option<String>,
+    // Web seeds (BEP 19)
+    #[serde(rename = "url-list", default, skip_serializing_if = "Option::is_none")]
+    pub url_list: Option<UrlList>,
+    // HTTP seeds (BEP 17)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub httpseeds: Option<Vec<String>>,
+    // DHT nodes to bootstrap from, as host and port pairs (BEP 5). Kept
+    // as read so a malformed entry doesn't cost the whole torrent; see
+    // `dht_nodes`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<Value>,
     pub info: Info,
+    // Concatenated piece hashes of each v2 file bigger than one piece,
+    // keyed by its pieces root (BEP 52)
+    #[serde(
+        rename = "piece layers",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub piece_layers: Option<BTreeMap<ByteBuf, ByteBuf>>,
+    // The info dictionary exactly as it was read, which the info-hashes
+    // are taken over
+    #[serde(skip)]
+    info_bytes: Option<Vec<u8>>,
     #[serde(skip)]
     info_hash: Option<[u8; 20]>,
+    #[serde(skip)]
+    info_hash_v2: Option<[u8; 32]>,
+}
+
+/// `url-list` holds either a single URL or a list of them.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum UrlList {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+// Fields outside the info dictionary aren't always valid UTF-8 in older
+// torrents, and aren't worth rejecting the torrent over
+fn lossy_string<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let bytes = ByteBuf::deserialize(deserializer)?;
+    Ok(Some(String::from_utf8_lossy(&bytes).into_owned()))
+}
+
+/// Which versions of the protocol a torrent can be shared with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetaVersion {
+    V1,
+    /// BitTorrent v2 only (BEP 52).
+    V2,
+    /// Both: v1 `pieces` and `files` alongside the v2 `file tree`.
+    Hybrid,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -19,38 +111,155 @@ pub struct Info {
     pub name: String,
     #[serde(rename = "piece length")]
     pub piece_length: usize,
+    // Absent from v2-only torrents
+    #[serde(default, skip_serializing_if = "PieceHashes::is_empty")]
     pub pieces: PieceHashes,
     #[serde(flatten)]
     pub files: FileMode,
+    // 2 for v2 and hybrid torrents
+    #[serde(
+        rename = "meta version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<u32>,
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<FileTree>,
+    // 1 keeps peers to those the trackers hand out (BEP 27)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+    // Hex MD5 of a single-file torrent's file
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "lossy_string"
+    )]
+    pub md5sum: Option<String>,
+    // Keys not modelled above, such as `source`, kept so the dictionary
+    // round-trips
+    #[serde(flatten, deserialize_with = "unknown_info_keys")]
+    pub extra: BTreeMap<ByteBuf, Value>,
+}
+
+// Every key `Info` has a field for, `files` and `length` included
+const INFO_KEYS: &[&str] = &[
+    "name",
+    "piece length",
+    "pieces",
+    "length",
+    "files",
+    "meta version",
+    "file tree",
+    "private",
+    "md5sum",
+];
+
+// The flattened map is offered every key, including those the other fields
+// took
+fn unknown_info_keys<'de, D>(deserializer: D) -> Result<BTreeMap<ByteBuf, Value>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mut keys = BTreeMap::<ByteBuf, Value>::deserialize(deserializer)?;
+    keys.retain(|key, _| {
+        !INFO_KEYS
+            .iter()
+            .any(|known| known.as_bytes() == key.as_slice())
+    });
+    Ok(keys)
+}
+
+impl Info {
+    /// Whether peers may only come from the trackers: no DHT, PEX or local
+    /// peer discovery (BEP 27).
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    pub fn version(&self) -> MetaVersion {
+        if self.meta_version != Some(2) || self.file_tree.is_none() {
+            MetaVersion::V1
+        } else if self.pieces.is_empty() {
+            MetaVersion::V2
+        } else {
+            MetaVersion::Hybrid
+        }
+    }
+
+    /// Number of pieces. In v2-only torrents every file starts a new
+    /// piece, so this counts each file's pieces separately.
+    pub fn num_pieces(&self) -> usize {
+        match self.version() {
+            MetaVersion::V2 => self
+                .v2_files()
+                .iter()
+                .map(|file| file.length.div_ceil(self.piece_length))
+                .sum(),
+            _ => self.pieces.0.len(),
+        }
+    }
+
+    /// Files in the v2 `file tree`, in order. Empty for v1 torrents.
+    pub fn v2_files(&self) -> Vec<V2File> {
+        let mut files = Vec::new();
+        if let Some(tree) = &self.file_tree {
+            tree.collect_files(&mut Vec::new(), &mut files);
+        }
+        files
+    }
 }
 
 impl fmt::Display for Info {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Torrent: {}\n", self.name)?;
-        write!(f, "Piece length: {} bytes\n", self.piece_length)?;
-        write!(f, "Number of pieces: {}\n", self.pieces.0.len())?;
-        
+        writeln!(f, "Torrent: {}", self.name)?;
+        writeln!(f, "Piece length: {} bytes", self.piece_length)?;
+        writeln!(f, "Number of pieces: {}", self.num_pieces())?;
+        if self.is_private() {
+            writeln!(f, "Private: yes")?;
+        }
+
         match &self.files {
             FileMode::SingleFile { length } => {
-                write!(f, "Mode: Single File\n")?;
-                write!(f, "Total size: {} bytes", length)
+                writeln!(f, "Mode: Single File")?;
+                write!(f, "Total size: {} bytes", length)?;
+                if let Some(md5sum) = &self.md5sum {
+                    write!(f, "\nMD5: {}", md5sum)?;
+                }
+                Ok(())
             }
             FileMode::MultiFile { files } => {
-                write!(f, "Mode: Multi File\n")?;
-                write!(f, "Number of files: {}\n", files.len())?;
-                write!(f, "Total size: {} bytes", files.iter().map(|f| f.length).sum::<usize>())?;
-                
+                writeln!(f, "Mode: Multi File")?;
+                writeln!(f, "Number of files: {}", files.len())?;
+                write!(
+                    f,
+                    "Total size: {} bytes",
+                    files.iter().map(|f| f.length).sum::<usize>()
+                )?;
+
                 // Optionally list the files if you want more detail
                 if f.alternate() {
                     for file in files {
-                        write!(f, "\n  - {} ({} bytes)", 
-                            file.path.join("/"), 
-                            file.length
-                        )?;
+                        write!(f, "\n  - {} ({} bytes)", file.path.join("/"), file.length)?;
+                        if let Some(attr) = &file.attr {
+                            write!(f, " [attr {}]", attr)?;
+                        }
+                        if let Some(md5sum) = &file.md5sum {
+                            write!(f, " md5 {}", md5sum)?;
+                        }
                     }
                 }
                 Ok(())
             }
+            FileMode::V2Only {} => {
+                let files = self.v2_files();
+                writeln!(f, "Mode: BitTorrent v2")?;
+                writeln!(f, "Number of files: {}", files.len())?;
+                write!(
+                    f,
+                    "Total size: {} bytes",
+                    files.iter().map(|f| f.length).sum::<usize>()
+                )
+            }
         }
     }
 }
@@ -60,17 +269,98 @@ impl fmt::Display for Info {
 pub enum FileMode {
     SingleFile { length: usize },
     MultiFile { files: Vec<FileInfo> },
+    // v2-only torrents list their files in `file tree` alone
+    V2Only {},
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct FileInfo {
     pub length: usize,
     pub path: Vec<String>,
+    // File attributes (BEP 47); `p` marks a pad file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "lossy_string"
+    )]
+    pub md5sum: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+impl FileInfo {
+    /// Whether this is a pad file, which only aligns the next file to a
+    /// piece boundary and is all zeros.
+    pub fn is_padding(&self) -> bool {
+        self.attr.as_ref().is_some_and(|attr| attr.contains('p'))
+    }
+}
+
+/// The v2 `file tree`: each directory maps names to its entries (BEP 52).
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct FileTree(pub BTreeMap<String, FileTreeNode>);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum FileTreeNode {
+    // Files are a dictionary with a single, empty, key
+    File {
+        #[serde(rename = "")]
+        file: TreeFile,
+    },
+    Directory(FileTree),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TreeFile {
+    pub length: usize,
+    // Absent for empty files
+    #[serde(
+        rename = "pieces root",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub pieces_root: Option<ByteBuf>,
+}
+
+impl FileTree {
+    fn collect_files(&self, path: &mut Vec<String>, files: &mut Vec<V2File>) {
+        for (name, node) in &self.0 {
+            path.push(name.clone());
+            match node {
+                FileTreeNode::File { file } => files.push(V2File {
+                    path: path.clone(),
+                    length: file.length,
+                    pieces_root: file
+                        .pieces_root
+                        .as_ref()
+                        .and_then(|root| root.as_slice().try_into().ok()),
+                }),
+                FileTreeNode::Directory(tree) => tree.collect_files(path, files),
+            }
+            path.pop();
+        }
+    }
+}
+
+/// A file of a v2 torrent, with its path from the top of the file tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct V2File {
+    pub path: Vec<String>,
+    pub length: usize,
+    /// Root of the merkle tree over the file's 16 KiB blocks.
+    pub pieces_root: Option<[u8; 32]>,
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct PieceHashes(pub Vec<[u8; 20]>);
 
+impl PieceHashes {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
 // Custom visitor for PieceHashes deserialization
 struct PieceHashesVisitor;
 
@@ -85,7 +375,7 @@ impl<'de> Visitor<'de> for PieceHashesVisitor {
     where
         E: de::Error,
     {
-        if v.len() % 20 != 0 {
+        if !v.len().is_multiple_of(20) {
             return Err(E::custom(format!(
                 "piece hashes length must be multiple of 20, got {}",
                 v.len()
@@ -127,40 +417,270 @@ impl Serialize for PieceHashes {
 
 impl Torrent {
     pub async fn from_file(path: impl AsRef<Path>) -> crate::error::Result<Self> {
-        let data = tokio::fs::read(path)
-            .await
-            .map_err(|e| BitTorrentError::Io(e))?;
-            
+        let data = tokio::fs::read(path).await.map_err(BitTorrentError::Io)?;
+
         let mut torrent: Self = serde_bencode::from_bytes(&data)
             .map_err(|e| BitTorrentError::InvalidData(e.to_string()))?;
-            
+        torrent.validate()?;
+
+        let info_bytes = bencode::dict_value(&data, b"info", bencode::DEFAULT_MAX_DEPTH)?
+            .ok_or_else(|| BitTorrentError::InvalidData("Missing info dictionary".into()))?;
+        torrent.info_bytes = Some(info_bytes.to_vec());
         torrent.info_hash = Some(torrent.calculate_info_hash());
+        if torrent.info.version() != MetaVersion::V1 {
+            torrent.info_hash_v2 = Some(torrent.calculate_info_hash_v2());
+        }
         Ok(torrent)
     }
 
+    /// Builds a torrent from an info dictionary fetched from peers. The
+    /// caller must already have checked `info_bytes` against the info-hash.
+    pub fn from_metadata(
+        info_bytes: &[u8],
+        trackers: Vec<Vec<String>>,
+    ) -> crate::error::Result<Self> {
+        let info: Info = serde_bencode::from_bytes(info_bytes)
+            .map_err(|e| BitTorrentError::InvalidData(e.to_string()))?;
+
+        let info_hash_v2 =
+            (info.version() != MetaVersion::V1).then(|| Sha256::digest(info_bytes).into());
+        // Not part of the info dictionary; v2 files bigger than a piece get
+        // their piece layers from peers
+        let torrent = Self {
+            info_bytes: Some(info_bytes.to_vec()),
+            info_hash: Some(Sha1::digest(info_bytes).into()),
+            info_hash_v2,
+            ..Self::new(info, trackers)
+        };
+        torrent.validate()?;
+        Ok(torrent)
+    }
+
+    /// A torrent for `info` announcing to the tracker tiers in `trackers`,
+    /// with no other metadata.
+    pub fn new(info: Info, trackers: Vec<Vec<String>>) -> Self {
+        Self {
+            announce: trackers
+                .first()
+                .and_then(|tier| tier.first())
+                .cloned()
+                .unwrap_or_default(),
+            announce_list: (!trackers.is_empty()).then_some(trackers),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            encoding: None,
+            url_list: None,
+            httpseeds: None,
+            nodes: None,
+            info,
+            piece_layers: None,
+            info_bytes: None,
+            info_hash: None,
+            info_hash_v2: None,
+        }
+    }
+
+    /// The bencoded torrent, as written to a `.torrent` file.
+    pub fn to_bytes(&self) -> crate::error::Result<Vec<u8>> {
+        serde_bencode::to_bytes(self).map_err(|e| BitTorrentError::InvalidData(e.to_string()))
+    }
+
+    pub async fn save(&self, path: impl AsRef<Path>) -> crate::error::Result<()> {
+        tokio::fs::write(path, self.to_bytes()?)
+            .await
+            .map_err(BitTorrentError::Io)
+    }
+
+    /// HTTP/FTP servers holding the torrent's files (BEP 19).
+    pub fn web_seeds(&self) -> Vec<String> {
+        let urls = match &self.url_list {
+            Some(UrlList::Single(url)) => vec![url.clone()],
+            Some(UrlList::Multiple(urls)) => urls.clone(),
+            None => Vec::new(),
+        };
+        urls.into_iter().filter(|url| !url.is_empty()).collect()
+    }
+
+    /// Servers speaking the BEP 17 HTTP seeding protocol.
+    pub fn http_seeds(&self) -> Vec<String> {
+        self.httpseeds
+            .iter()
+            .flatten()
+            .filter(|url| !url.is_empty())
+            .cloned()
+            .collect()
+    }
+
+    /// DHT nodes the torrent suggests bootstrapping from. Private torrents
+    /// don't use the DHT, so they have none. Entries that aren't a host
+    /// and port are skipped.
+    pub fn dht_nodes(&self) -> Vec<(String, u16)> {
+        if self.info.is_private() {
+            return Vec::new();
+        }
+        let Some(nodes) = self.nodes.as_ref().and_then(Value::as_list) else {
+            return Vec::new();
+        };
+        nodes
+            .iter()
+            .filter_map(|node| match node.as_list()? {
+                [host, port] => {
+                    let port = u16::try_from(port.as_int()?).ok()?;
+                    Some((host.as_str()?.to_string(), port))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    // v2 files with data must have a 32-byte pieces root
+    fn validate(&self) -> crate::error::Result<()> {
+        let files = self.info.v2_files();
+        match files
+            .iter()
+            .find(|file| file.length > 0 && file.pieces_root.is_none())
+        {
+            Some(file) => Err(BitTorrentError::InvalidData(format!(
+                "Missing or invalid pieces root for {}",
+                file.path.join("/")
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// The info-hash the torrent is known by in handshakes and announces:
+    /// the SHA-1 one, or for v2-only torrents the SHA-256 one truncated to
+    /// 20 bytes.
     pub fn info_hash(&self) -> [u8; 20] {
-        self.info_hash.unwrap_or_else(|| self.calculate_info_hash())
+        match (self.info.version(), self.info_hash_v2()) {
+            (MetaVersion::V2, Some(hash)) => truncate_info_hash(&hash),
+            _ => self.info_hash.unwrap_or_else(|| self.calculate_info_hash()),
+        }
+    }
+
+    /// SHA-256 info-hash of v2 and hybrid torrents.
+    pub fn info_hash_v2(&self) -> Option<[u8; 32]> {
+        if self.info.version() == MetaVersion::V1 {
+            return None;
+        }
+        Some(
+            self.info_hash_v2
+                .unwrap_or_else(|| self.calculate_info_hash_v2()),
+        )
+    }
+
+    /// Every swarm the torrent is shared in. Hybrid torrents are in both
+    /// the v1 swarm and the v2 one, which uses the truncated v2 info-hash.
+    pub fn info_hashes(&self) -> Vec<[u8; 20]> {
+        let mut hashes = vec![self.info_hash()];
+        if let (MetaVersion::Hybrid, Some(hash)) = (self.info.version(), self.info_hash_v2()) {
+            hashes.push(truncate_info_hash(&hash));
+        }
+        hashes
+    }
+
+    /// Piece hashes of the v2 file with `pieces_root`, if the torrent
+    /// carries them. Files no bigger than a piece have none.
+    pub fn piece_layer(&self, pieces_root: &[u8; 32]) -> Option<Vec<[u8; 32]>> {
+        let layer = self.piece_layers.as_ref()?.get(Bytes::new(pieces_root))?;
+        if !layer.len().is_multiple_of(32) {
+            return None;
+        }
+        Some(
+            layer
+                .chunks_exact(32)
+                .map(|hash| hash.try_into().expect("chunk of 32 bytes"))
+                .collect(),
+        )
+    }
+
+    /// The bencoded info dictionary: the bytes it was read from, or for a
+    /// torrent built here, `info` encoded.
+    pub fn info_bytes(&self) -> Vec<u8> {
+        self.info_bytes.clone().unwrap_or_else(|| {
+            serde_bencode::to_bytes(&self.info).expect("Info serialization should never fail")
+        })
     }
 
     fn calculate_info_hash(&self) -> [u8; 20] {
-        let info_bencoded = serde_bencode::to_bytes(&self.info)
-            .expect("Info serialization should never fail");
-            
         let mut hasher = Sha1::new();
-        hasher.update(&info_bencoded);
-        hasher.finalize()
-            .try_into()
-            .expect("SHA1 output is always 20 bytes")
+        hasher.update(self.info_bytes());
+        hasher.finalize().into()
+    }
+
+    fn calculate_info_hash_v2(&self) -> [u8; 32] {
+        Sha256::digest(self.info_bytes()).into()
+    }
+
+    /// Tracker tiers to announce to. Per BEP 12, `announce-list` replaces
+    /// `announce` when it has any trackers in it.
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        let tiers: Vec<Vec<String>> = self
+            .announce_list
+            .iter()
+            .flatten()
+            .map(|tier| tier.iter().filter(|url| !url.is_empty()).cloned().collect())
+            .filter(|tier: &Vec<String>| !tier.is_empty())
+            .collect();
+
+        if !tiers.is_empty() {
+            tiers
+        } else if !self.announce.is_empty() {
+            vec![vec![self.announce.clone()]]
+        } else {
+            Vec::new()
+        }
     }
 
     pub fn total_length(&self) -> usize {
         match &self.info.files {
             FileMode::SingleFile { length } => *length,
             FileMode::MultiFile { files } => files.iter().map(|f| f.length).sum(),
+            FileMode::V2Only {} => self.info.v2_files().iter().map(|f| f.length).sum(),
+        }
+    }
+}
+
+impl fmt::Display for Torrent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.info, f)?;
+        if let Some(comment) = &self.comment {
+            write!(f, "\nComment: {}", comment)?;
+        }
+        if let Some(created_by) = &self.created_by {
+            write!(f, "\nCreated by: {}", created_by)?;
+        }
+        if let Some(creation_date) = self.creation_date {
+            write!(f, "\nCreation date: {} (Unix time)", creation_date)?;
+        }
+        if let Some(encoding) = &self.encoding {
+            write!(f, "\nEncoding: {}", encoding)?;
+        }
+        for (i, tier) in self.trackers().iter().enumerate() {
+            write!(f, "\nTracker tier {}: {}", i + 1, tier.join(", "))?;
         }
+        for url in self.web_seeds() {
+            write!(f, "\nWeb seed: {}", url)?;
+        }
+        for url in self.http_seeds() {
+            write!(f, "\nHTTP seed: {}", url)?;
+        }
+        for (host, port) in self.dht_nodes() {
+            write!(f, "\nDHT node: {}:{}", host, port)?;
+        }
+        Ok(())
     }
 }
 
+/// The 20 bytes of a v2 info-hash used where v1 has room for no more, as
+/// in handshakes, trackers and the DHT.
+pub fn truncate_info_hash(info_hash_v2: &[u8; 32]) -> [u8; 20] {
+    info_hash_v2[..20]
+        .try_into()
+        .expect("a v2 info-hash is longer than 20 bytes")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -189,4 +709,207 @@ mod tests {
         let result: Result<PieceHashes, _> = serde_bencode::from_bytes(&invalid_data);
         assert!(result.is_err());
     }
+
+    #[tokio::test]
+    async fn test_announce_list_tiers() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/src/tests/data/test.torrent");
+        let torrent = Torrent::from_file(path).await.unwrap();
+
+        assert_eq!(
+            torrent.trackers(),
+            vec![
+                vec!["https://academictorrents.com/announce.php".to_string()],
+                vec!["https://ipv6.academictorrents.com/announce.php".to_string()],
+                vec!["udp://tracker.opentrackr.org:1337/announce".to_string()],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_announce_used_without_announce_list() {
+        let mut torrent: Torrent = serde_bencode::from_bytes(
+            b"d8:announce14:http://t/annce4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+        )
+        .unwrap();
+        assert_eq!(torrent.trackers(), vec![vec!["http://t/annce".to_string()]]);
+
+        // Empty tiers don't shadow `announce`
+        torrent.announce_list = Some(vec![vec![]]);
+        assert_eq!(torrent.trackers(), vec![vec!["http://t/annce".to_string()]]);
+    }
+
+    #[tokio::test]
+    async fn test_info_hash_over_original_bytes() {
+        // `source` isn't modelled, and `private` is out of order
+        let info = b"d6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaa6:source3:abc7:privatei1ee";
+        let mut data = b"d8:announce8:http://t4:info".to_vec();
+        data.extend_from_slice(info);
+        data.push(b'e');
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("info-bytes.torrent");
+        tokio::fs::write(&path, &data).await.unwrap();
+        let torrent = Torrent::from_file(&path).await.unwrap();
+
+        assert_eq!(torrent.info_bytes(), info);
+        assert_eq!(torrent.info_hash(), <[u8; 20]>::from(Sha1::digest(info)));
+        assert_eq!(torrent.info.private, Some(1));
+        assert_eq!(
+            torrent.info.extra.get(Bytes::new(b"source")),
+            Some(&Value::Bytes(b"abc".to_vec()))
+        );
+
+        // Unknown keys survive re-encoding, in canonical order
+        let encoded = serde_bencode::to_bytes(&torrent.info).unwrap();
+        assert_eq!(
+            encoded,
+            b"d6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1e6:source3:abce"
+        );
+    }
+
+    #[test]
+    fn test_metainfo_fields() {
+        let data = b"d7:comment5:hello10:created by4:mktr13:creation datei1700000000e8:encoding5:UTF-89:httpseedsl9:http://h/e4:infod5:filesld6:lengthi1e6:md5sum32:0123456789abcdef0123456789abcdef4:pathl1:aeee4:name1:d12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae5:nodesll7:1.2.3.4i6881eee8:url-list9:http://w/e";
+        let mut torrent: Torrent = serde_bencode::from_bytes(data).unwrap();
+        assert_eq!(torrent.comment.as_deref(), Some("hello"));
+        assert_eq!(torrent.created_by.as_deref(), Some("mktr"));
+        assert_eq!(torrent.creation_date, Some(1_700_000_000));
+        assert_eq!(torrent.encoding.as_deref(), Some("UTF-8"));
+        assert_eq!(torrent.web_seeds(), vec!["http://w/".to_string()]);
+        assert_eq!(torrent.http_seeds(), vec!["http://h/".to_string()]);
+        assert_eq!(torrent.dht_nodes(), vec![("1.2.3.4".to_string(), 6881)]);
+        let FileMode::MultiFile { files } = &torrent.info.files else {
+            panic!("expected a multi-file torrent");
+        };
+        assert_eq!(
+            files[0].md5sum.as_deref(),
+            Some("0123456789abcdef0123456789abcdef")
+        );
+        assert_eq!(serde_bencode::to_bytes(&torrent).unwrap(), data);
+
+        let shown = format!("{:#}", torrent);
+        assert!(shown.contains("Comment: hello"));
+        assert!(shown.contains("md5 0123456789abcdef0123456789abcdef"));
+        assert!(shown.contains("DHT node: 1.2.3.4:6881"));
+        assert!(!shown.contains("Private"));
+
+        // Private torrents stay off the DHT
+        torrent.info.private = Some(1);
+        assert!(torrent.info.is_private());
+        assert!(torrent.dht_nodes().is_empty());
+        assert!(format!("{}", torrent).contains("Private: yes"));
+    }
+
+    #[test]
+    fn test_malformed_optional_fields_tolerated() {
+        let data = b"d4:infod6:lengthi1e6:md5sum2:\xff\xfe4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaae5:nodesll7:1.2.3.4i6881eel3:badi70000eei5el1:heee";
+        let torrent: Torrent = serde_bencode::from_bytes(data).unwrap();
+        assert_eq!(torrent.info.md5sum.as_deref(), Some("\u{fffd}\u{fffd}"));
+        assert_eq!(torrent.dht_nodes(), vec![("1.2.3.4".to_string(), 6881)]);
+        assert_eq!(
+            serde_bencode::to_bytes(&torrent.nodes).unwrap(),
+            b"ll7:1.2.3.4i6881eel3:badi70000eei5el1:hee"
+        );
+        // Shown whether or not the file list is
+        assert!(format!("{}", torrent.info).contains("MD5: \u{fffd}\u{fffd}"));
+
+        let data = b"d4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaae5:nodes3:abce";
+        let torrent: Torrent = serde_bencode::from_bytes(data).unwrap();
+        assert!(torrent.dht_nodes().is_empty());
+    }
+
+    #[test]
+    fn test_v2_and_hybrid_metainfo() {
+        let file = |length, root: u8| FileTreeNode::File {
+            file: TreeFile {
+                length,
+                pieces_root: Some(ByteBuf::from(vec![root; 32])),
+            },
+        };
+        let dir = FileTree(BTreeMap::from([("a".to_string(), file(40000, 1))]));
+        let tree = FileTree(BTreeMap::from([
+            ("b".to_string(), file(100, 2)),
+            ("dir".to_string(), FileTreeNode::Directory(dir)),
+        ]));
+        let mut info = Info {
+            name: "v2".into(),
+            piece_length: 16384,
+            pieces: PieceHashes::default(),
+            files: FileMode::V2Only {},
+            meta_version: Some(2),
+            file_tree: Some(tree),
+            private: None,
+            md5sum: None,
+            extra: BTreeMap::new(),
+        };
+
+        let bytes = serde_bencode::to_bytes(&info).unwrap();
+        let torrent = Torrent::from_metadata(&bytes, Vec::new()).unwrap();
+        assert_eq!(torrent.info.version(), MetaVersion::V2);
+        let files = torrent.info.v2_files();
+        assert_eq!(files[0].path, vec!["b".to_string()]);
+        assert_eq!(files[1].path, vec!["dir".to_string(), "a".to_string()]);
+        assert_eq!(files[1].pieces_root, Some([1; 32]));
+        // Each file starts a new piece
+        assert_eq!(torrent.info.num_pieces(), 4);
+        assert_eq!(torrent.total_length(), 40100);
+
+        let info_hash_v2: [u8; 32] = Sha256::digest(&bytes).into();
+        assert_eq!(torrent.info_hash_v2(), Some(info_hash_v2));
+        assert_eq!(torrent.calculate_info_hash_v2(), info_hash_v2);
+        assert_eq!(
+            torrent.info_hashes(),
+            vec![truncate_info_hash(&info_hash_v2)]
+        );
+
+        // Piece layers sit next to the info dictionary
+        let mut data = b"d4:info".to_vec();
+        data.extend_from_slice(&bytes);
+        data.extend_from_slice(b"12:piece layersd32:");
+        data.extend_from_slice(&[1; 32]);
+        data.extend_from_slice(b"96:");
+        data.extend_from_slice(&[5; 96]);
+        data.extend_from_slice(b"ee");
+        let torrent: Torrent = serde_bencode::from_bytes(&data).unwrap();
+        assert_eq!(torrent.piece_layer(&[1; 32]), Some(vec![[5; 32]; 3]));
+        assert_eq!(torrent.piece_layer(&[2; 32]), None);
+
+        // Hybrid: the same files for v1, with a pad file after "b"
+        info.pieces = PieceHashes(vec![[0; 20]; 4]);
+        info.files = FileMode::MultiFile {
+            files: vec![
+                FileInfo {
+                    length: 100,
+                    path: vec!["b".into()],
+                    attr: None,
+                    md5sum: None,
+                },
+                FileInfo {
+                    length: 16284,
+                    path: vec![".pad".into(), "16284".into()],
+                    attr: Some("p".into()),
+                    md5sum: None,
+                },
+                FileInfo {
+                    length: 40000,
+                    path: vec!["dir".into(), "a".into()],
+                    attr: None,
+                    md5sum: None,
+                },
+            ],
+        };
+        let bytes = serde_bencode::to_bytes(&info).unwrap();
+        let torrent = Torrent::from_metadata(&bytes, Vec::new()).unwrap();
+        assert_eq!(torrent.info.version(), MetaVersion::Hybrid);
+        let info_hash: [u8; 20] = Sha1::digest(&bytes).into();
+        let info_hash_v2: [u8; 32] = Sha256::digest(&bytes).into();
+        assert_eq!(
+            torrent.info_hashes(),
+            vec![info_hash, truncate_info_hash(&info_hash_v2)]
+        );
+
+        // Files with data need a pieces root
+        let broken =
+            "d9:file treed1:ad0:d6:lengthi5eeee12:meta versioni2e4:name1:a12:piece lengthi16384ee";
+        assert!(Torrent::from_metadata(broken.as_bytes(), Vec::new()).is_err());
+    }
 }
@@ -1,13 +1,58 @@
 // src/tracker.rs
 use crate::{
     error::{BitTorrentError, Result},
+    stats::TransferStats,
     torrent::Torrent,
-    utils::generate_peer_id,
+    udp_tracker::{UdpAnnounce, UdpTracker},
 };
+use futures_util::future::join_all;
+use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::net::SocketAddrV4;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, timeout, Duration, Instant};
 use url::Url;
 
+// Used until a tracker tells us its interval
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(1800);
+// First retry delay after a failed announce; doubles on each failure
+const RETRY_BACKOFF: Duration = Duration::from_secs(60);
+const STOPPED_TIMEOUT: Duration = Duration::from_secs(5);
+// Longest one tracker may take to answer before the next in its tier is tried
+const TRACKER_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    None,
+    Completed,
+    Started,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AnnounceEvent::None => "",
+            AnnounceEvent::Completed => "completed",
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Stopped => "stopped",
+        }
+    }
+
+    // Event codes used by the UDP tracker protocol
+    fn udp_code(&self) -> u32 {
+        match self {
+            AnnounceEvent::None => 0,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 struct TrackerRequest<'a> {
     info_hash: &'a [u8; 20],
@@ -24,20 +69,22 @@ struct TrackerRequest<'a> {
 struct TrackerResponse {
     #[serde(default)]
     interval: u32,
-    #[serde(default)]
+    #[serde(default, rename = "min interval")]
     min_interval: Option<u32>,
     #[serde(default)]
     complete: Option<u32>,
     #[serde(default)]
     incomplete: Option<u32>,
-    #[serde(default)]
+    #[serde(default, rename = "warning message")]
     warning_message: Option<String>,
-    #[serde(default)]
+    #[serde(default, rename = "failure reason")]
     failure_reason: Option<String>,
+    // Absent when the announce failed
+    #[serde(default)]
     peers: Peers,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 struct Peers(Vec<SocketAddrV4>);
 
 impl<'de> Deserialize<'de> for Peers {
@@ -58,7 +105,7 @@ impl<'de> Deserialize<'de> for Peers {
             where
                 E: serde::de::Error,
             {
-                if v.len() % 6 != 0 {
+                if !v.len().is_multiple_of(6) {
                     return Err(E::custom(format!(
                         "peer string length must be multiple of 6, got {}",
                         v.len()
@@ -67,7 +114,7 @@ impl<'de> Deserialize<'de> for Peers {
 
                 let peers = v
                     .chunks_exact(6)
-                    .filter_map(|chunk| {
+                    .map(|chunk| {
                         let ip = std::net::Ipv4Addr::new(
                             chunk[0],
                             chunk[1],
@@ -75,7 +122,7 @@ impl<'de> Deserialize<'de> for Peers {
                             chunk[3],
                         );
                         let port = u16::from_be_bytes([chunk[4], chunk[5]]);
-                        Some(SocketAddrV4::new(ip, port))
+                        SocketAddrV4::new(ip, port)
                     })
                     .collect();
 
@@ -91,8 +138,6 @@ impl<'de> Deserialize<'de> for Peers {
                 struct PeerDict {
                     ip: String,
                     port: u16,
-                    #[serde(default)]
-                    peer_id: Option<String>,
                 }
 
                 let mut peers = Vec::new();
@@ -109,63 +154,155 @@ impl<'de> Deserialize<'de> for Peers {
     }
 }
 
+// One announce URL within a tier
+struct TrackerEndpoint {
+    url: String,
+    // Kept so a UDP tracker's connection id is reused between announces
+    udp: Option<UdpTracker>,
+}
 
-#[derive(Debug, Deserialize)]
-struct PeerDict {
-    peer_id: Option<String>,
-    ip: String,
-    port: u16,
+impl TrackerEndpoint {
+    fn new(url: String) -> Self {
+        Self { url, udp: None }
+    }
 }
 
-pub struct Tracker {
-    announce_url: String,
+// Snapshot of what we tell every tracker in one announce round
+struct AnnounceParams {
     info_hash: [u8; 20],
     peer_id: [u8; 20],
     port: u16,
     uploaded: u64,
     downloaded: u64,
     left: u64,
+    key: u32,
+    event: AnnounceEvent,
+}
+
+/// What a tracker told us in reply to an announce.
+#[derive(Debug, Clone, Default)]
+pub struct AnnounceResponse {
+    pub interval: u32,
+    pub min_interval: Option<u32>,
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+    pub peers: Vec<SocketAddrV4>,
+    /// Non-fatal message the tracker asked us to show.
+    pub warning: Option<String>,
+}
+
+/// Control messages for a running tracker task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackerCommand {
+    Completed,
+    Stop,
+}
+
+/// Handle to a tracker task started with `Tracker::spawn`.
+pub struct TrackerHandle {
+    commands: mpsc::UnboundedSender<TrackerCommand>,
+    task: JoinHandle<()>,
+}
+
+impl TrackerHandle {
+    /// Sends the `completed` event once the last piece has verified.
+    pub fn completed(&self) {
+        let _ = self.commands.send(TrackerCommand::Completed);
+    }
+
+    /// Sends the `stopped` event and waits for the task to finish.
+    pub async fn stop(self) {
+        let _ = self.commands.send(TrackerCommand::Stop);
+        let _ = self.task.await;
+    }
+}
+
+/// Announces to a torrent's trackers, organised in BEP 12 tiers.
+///
+/// Trackers are shuffled within each tier once, at construction. Every
+/// tier is announced to at once; within a tier trackers are tried in order
+/// until one responds, and that tracker is moved to the front of its tier
+/// so it is tried first next time. Peers from all responding trackers are
+/// merged.
+pub struct Tracker {
+    tiers: Vec<Vec<TrackerEndpoint>>,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    port: u16,
+    stats: Arc<TransferStats>,
+    // Sent with UDP announces so the tracker can recognise us across IPs
+    key: u32,
+    // Whether a `started` announce has gone through yet
+    started: bool,
+    interval: Duration,
+    min_interval: Option<Duration>,
+    // How long each tracker gets before it counts as failed
+    timeout: Duration,
 }
 
 impl Tracker {
-    pub fn new(torrent: &Torrent) -> Result<Self> {
-        println!("Announce URL: {}", torrent.announce);
+    pub fn new(
+        torrent: &Torrent,
+        port: u16,
+        peer_id: [u8; 20],
+        stats: Arc<TransferStats>,
+    ) -> Result<Self> {
+        Self::with_tiers(torrent.trackers(), torrent.info_hash(), port, peer_id, stats)
+    }
+
+    /// Like `new`, for when there is no `Torrent` yet (e.g. a magnet link).
+    pub fn with_tiers(
+        tiers: Vec<Vec<String>>,
+        info_hash: [u8; 20],
+        port: u16,
+        peer_id: [u8; 20],
+        stats: Arc<TransferStats>,
+    ) -> Result<Self> {
+        let mut rng = rand::thread_rng();
+        let tiers: Vec<Vec<TrackerEndpoint>> = tiers
+            .into_iter()
+            .map(|tier| {
+                let mut tier: Vec<TrackerEndpoint> =
+                    tier.into_iter().map(TrackerEndpoint::new).collect();
+                tier.shuffle(&mut rng);
+                tier
+            })
+            .collect();
+
+        if tiers.is_empty() {
+            return Err(BitTorrentError::Tracker("Torrent has no trackers".into()));
+        }
+        for (i, tier) in tiers.iter().enumerate() {
+            let urls: Vec<&str> = tier.iter().map(|t| t.url.as_str()).collect();
+            println!("Tracker tier {}: {}", i, urls.join(", "));
+        }
+
         Ok(Self {
-            announce_url: torrent.announce.clone(),
-            info_hash: torrent.info_hash(),
-            peer_id: generate_peer_id(),
-            port: 6881,
-            uploaded: 0,
-            downloaded: 0,
-            left: torrent.total_length() as u64,
+            tiers,
+            info_hash,
+            peer_id,
+            port,
+            stats,
+            key: rand::random(),
+            started: false,
+            interval: DEFAULT_INTERVAL,
+            min_interval: None,
+            timeout: TRACKER_TIMEOUT,
         })
     }
 
-    pub async fn get_peers(&self) -> Result<Vec<SocketAddrV4>> {
-        let request = TrackerRequest {
-            info_hash: &self.info_hash,
-            peer_id: &self.peer_id,
-            port: self.port,
-            uploaded: self.uploaded,
-            downloaded: self.downloaded,
-            left: self.left,
-            compact: 1,
-            event: "started",
-        };
-
-        let url = self.build_tracker_url(&request)?;
-        println!("Requesting peers from tracker: {}", url);
+    /// Gives each tracker `timeout` to answer an announce before moving on
+    /// to the next one in its tier.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
 
-        let response = reqwest::get(url).await?;
-        println!("Response status: {}", response.status());
-        
-        let bytes = response.bytes().await?;
-        let response: TrackerResponse = serde_bencode::from_bytes(&bytes)
-            .map_err(|e| BitTorrentError::Tracker(format!("Failed to decode response: {}", e)))?;
+    /// Sends the initial `started` announce and returns the peers found.
+    pub async fn get_peers(&mut self) -> Result<Vec<SocketAddrV4>> {
+        let peers = self.announce(AnnounceEvent::Started).await?.peers;
 
-        let peers = response.peers.0;
         println!("Successfully decoded {} peers", peers.len());
-        
         if peers.is_empty() {
             return Err(BitTorrentError::Tracker("No peers available".into()));
         }
@@ -173,36 +310,458 @@ impl Tracker {
         Ok(peers)
     }
 
-    fn build_tracker_url(&self, request: &TrackerRequest) -> Result<Url> {
-        // Start with the base URL
-        let base_url = Url::parse(&self.announce_url)
-            .map_err(|e| BitTorrentError::Tracker(e.to_string()))?;
-            
-        let mut query = String::new();
-        
-        query.push_str("?info_hash=");
-        for &byte in request.info_hash {
-            query.push_str(&format!("%{:02x}", byte));
+    /// Announces `event` to every tier with the current transfer counters.
+    pub async fn announce(&mut self, event: AnnounceEvent) -> Result<AnnounceResponse> {
+        let params = AnnounceParams {
+            info_hash: self.info_hash,
+            peer_id: self.peer_id,
+            port: self.port,
+            uploaded: self.stats.uploaded(),
+            downloaded: self.stats.downloaded(),
+            left: self.stats.left(),
+            key: self.key,
+            event,
+        };
+
+        let tiers = self
+            .tiers
+            .iter_mut()
+            .map(|tier| announce_tier(tier, &params, self.timeout));
+        let results = join_all(tiers).await;
+
+        let mut merged: Option<AnnounceResponse> = None;
+        let mut seen = HashSet::new();
+        let mut last_error = None;
+
+        for result in results {
+            let response = match result {
+                Ok(response) => response,
+                Err(e) => {
+                    last_error = Some(e);
+                    continue;
+                }
+            };
+            let merged = merged.get_or_insert_with(|| AnnounceResponse {
+                interval: u32::MAX,
+                ..Default::default()
+            });
+            // Re-announce as often as the most eager tracker asks, but no
+            // more often than any tracker allows
+            if response.interval > 0 {
+                merged.interval = merged.interval.min(response.interval);
+            }
+            merged.min_interval = merged.min_interval.max(response.min_interval);
+            merged.seeders = merged.seeders.max(response.seeders);
+            merged.leechers = merged.leechers.max(response.leechers);
+            merged.warning = merged.warning.take().or(response.warning);
+            merged
+                .peers
+                .extend(response.peers.into_iter().filter(|addr| seen.insert(*addr)));
         }
-        
-        query.push_str("&peer_id=");
-        for &byte in request.peer_id {
-            query.push_str(&format!("%{:02x}", byte));
+
+        let Some(response) = merged else {
+            return Err(last_error
+                .unwrap_or_else(|| BitTorrentError::Tracker("No trackers responded".into())));
+        };
+
+        if event == AnnounceEvent::Started {
+            self.started = true;
         }
-        
-        query.push_str(&format!("&port={}", request.port));
-        query.push_str(&format!("&uploaded={}", request.uploaded));
-        query.push_str(&format!("&downloaded={}", request.downloaded));
-        query.push_str(&format!("&left={}", request.left));
-        query.push_str(&format!("&compact={}", request.compact));
-        query.push_str(&format!("&event={}", request.event));
-        
-        Ok(Url::parse(&format!("{}{}", base_url, query))
-            .map_err(|e| BitTorrentError::Tracker(e.to_string()))?)
+        if response.interval != u32::MAX {
+            self.interval = Duration::from_secs(response.interval as u64);
+        }
+        self.min_interval = response
+            .min_interval
+            .map(|secs| Duration::from_secs(secs as u64));
+
+        Ok(response)
+    }
+
+    /// Time to wait before the next regular announce.
+    pub fn next_announce_in(&self) -> Duration {
+        self.interval.max(self.min_interval.unwrap_or_default())
+    }
+
+    /// Announce URLs in the order they will be tried, tier by tier.
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        self.tiers
+            .iter()
+            .map(|tier| tier.iter().map(|t| t.url.clone()).collect())
+            .collect()
+    }
+
+    /// Re-announces in the background on the tracker's interval, sending
+    /// every batch of peers it returns to `peer_tx`. If `get_peers` hasn't
+    /// been called yet the task starts with a `started` announce.
+    pub fn spawn(mut self, peer_tx: mpsc::Sender<Vec<SocketAddrV4>>) -> TrackerHandle {
+        let (commands, mut command_rx) = mpsc::unbounded_channel();
+
+        let task = tokio::spawn(async move {
+            let mut next_announce = if self.started {
+                Instant::now() + self.next_announce_in()
+            } else {
+                Instant::now()
+            };
+            let mut failures = 0u32;
+
+            loop {
+                let event = tokio::select! {
+                    _ = sleep_until(next_announce) => {
+                        if self.started {
+                            AnnounceEvent::None
+                        } else {
+                            AnnounceEvent::Started
+                        }
+                    }
+                    command = command_rx.recv() => match command {
+                        Some(TrackerCommand::Completed) => AnnounceEvent::Completed,
+                        Some(TrackerCommand::Stop) | None => break,
+                    },
+                };
+
+                match self.announce(event).await {
+                    Ok(response) => {
+                        failures = 0;
+                        next_announce = Instant::now() + self.next_announce_in();
+                        // Once the download finishes nobody is listening for
+                        // peers, but we keep announcing while seeding
+                        if !response.peers.is_empty() {
+                            let _ = peer_tx.send(response.peers).await;
+                        }
+                    }
+                    Err(e) => {
+                        // Back off, but never wait longer than the interval
+                        let backoff = RETRY_BACKOFF * 2u32.pow(failures.min(5));
+                        failures += 1;
+                        println!("Announce failed, retrying in {:?}: {}", backoff, e);
+                        next_announce = Instant::now() + backoff.min(self.interval);
+                    }
+                }
+            }
+
+            // Best effort: don't hold up shutdown on an unresponsive tracker
+            if self.started {
+                let _ = timeout(STOPPED_TIMEOUT, self.announce(AnnounceEvent::Stopped)).await;
+            }
+        });
+
+        TrackerHandle { commands, task }
     }
 }
 
-fn urlencode(bytes: &[u8]) -> String {
-    bytes.iter().map(|&byte| format!("%{:02x}", byte)).collect()
+// Tries the trackers of one tier in order until one answers within
+// `timeout_per_tracker`, and moves that one to the front of the tier.
+async fn announce_tier(
+    tier: &mut Vec<TrackerEndpoint>,
+    params: &AnnounceParams,
+    timeout_per_tracker: Duration,
+) -> Result<AnnounceResponse> {
+    let mut last_error = None;
+    for i in 0..tier.len() {
+        let result = match timeout(timeout_per_tracker, announce(&mut tier[i], params)).await {
+            Ok(result) => result,
+            Err(_) => Err(BitTorrentError::Tracker("Tracker timed out".into())),
+        };
+        match result {
+            Ok(response) => {
+                println!(
+                    "Tracker {} returned {} peers",
+                    tier[i].url,
+                    response.peers.len()
+                );
+                if let Some(warning) = &response.warning {
+                    println!("Tracker {} warning: {}", tier[i].url, warning);
+                }
+                let working = tier.remove(i);
+                tier.insert(0, working);
+                return Ok(response);
+            }
+            Err(e) => {
+                println!("Tracker {} failed: {}", tier[i].url, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| BitTorrentError::Tracker("Empty tracker tier".into())))
 }
 
+async fn announce(
+    endpoint: &mut TrackerEndpoint,
+    params: &AnnounceParams,
+) -> Result<AnnounceResponse> {
+    if endpoint.url.starts_with("udp://") {
+        announce_udp(endpoint, params).await
+    } else {
+        announce_http(&endpoint.url, params).await
+    }
+}
+
+async fn announce_udp(
+    endpoint: &mut TrackerEndpoint,
+    params: &AnnounceParams,
+) -> Result<AnnounceResponse> {
+    let request = UdpAnnounce {
+        info_hash: params.info_hash,
+        peer_id: params.peer_id,
+        downloaded: params.downloaded,
+        left: params.left,
+        uploaded: params.uploaded,
+        event: params.event.udp_code(),
+        key: params.key,
+        num_want: -1,
+        port: params.port,
+    };
+
+    if endpoint.udp.is_none() {
+        endpoint.udp = Some(UdpTracker::new(&endpoint.url).await?);
+    }
+    let udp = endpoint.udp.as_mut().expect("UDP tracker was just created");
+
+    println!("Requesting peers from UDP tracker: {}", endpoint.url);
+    let response = udp.announce(&request).await?;
+    Ok(AnnounceResponse {
+        interval: response.interval,
+        min_interval: None,
+        seeders: Some(response.seeders),
+        leechers: Some(response.leechers),
+        peers: response.peers,
+        warning: None,
+    })
+}
+
+async fn announce_http(announce_url: &str, params: &AnnounceParams) -> Result<AnnounceResponse> {
+    let request = TrackerRequest {
+        info_hash: &params.info_hash,
+        peer_id: &params.peer_id,
+        port: params.port,
+        uploaded: params.uploaded,
+        downloaded: params.downloaded,
+        left: params.left,
+        compact: 1,
+        event: params.event.as_str(),
+    };
+
+    let url = build_tracker_url(announce_url, &request)?;
+    println!("Requesting peers from tracker: {}", url);
+
+    let response = reqwest::get(url).await?;
+    println!("Response status: {}", response.status());
+
+    let bytes = response.bytes().await?;
+    let response: TrackerResponse = serde_bencode::from_bytes(&bytes)
+        .map_err(|e| BitTorrentError::Tracker(format!("Failed to decode response: {}", e)))?;
+
+    // A failure reason means nothing else in the response is meaningful
+    if let Some(reason) = response.failure_reason {
+        return Err(BitTorrentError::TrackerFailure(reason));
+    }
+
+    Ok(AnnounceResponse {
+        interval: response.interval,
+        min_interval: response.min_interval,
+        seeders: response.complete,
+        leechers: response.incomplete,
+        peers: response.peers.0,
+        warning: response.warning_message,
+    })
+}
+
+fn build_tracker_url(announce_url: &str, request: &TrackerRequest) -> Result<Url> {
+    // Start with the base URL
+    let base_url = Url::parse(announce_url).map_err(|e| BitTorrentError::Tracker(e.to_string()))?;
+
+    // Some announce URLs already carry a query (e.g. a passkey)
+    let mut query = String::from(if base_url.query().is_some() { "&" } else { "?" });
+
+    query.push_str("info_hash=");
+    for &byte in request.info_hash {
+        query.push_str(&format!("%{:02x}", byte));
+    }
+
+    query.push_str("&peer_id=");
+    for &byte in request.peer_id {
+        query.push_str(&format!("%{:02x}", byte));
+    }
+
+    query.push_str(&format!("&port={}", request.port));
+    query.push_str(&format!("&uploaded={}", request.uploaded));
+    query.push_str(&format!("&downloaded={}", request.downloaded));
+    query.push_str(&format!("&left={}", request.left));
+    query.push_str(&format!("&compact={}", request.compact));
+    if !request.event.is_empty() {
+        query.push_str(&format!("&event={}", request.event));
+    }
+
+    Url::parse(&format!("{}{}", base_url, query))
+        .map_err(|e| BitTorrentError::Tracker(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // Serves `body` to every HTTP request and returns the announce URL
+    async fn mock_http_tracker(body: Vec<u8>) -> String {
+        mock_http_tracker_with_log(body).await.0
+    }
+
+    // Like `mock_http_tracker`, also reporting each request line
+    async fn mock_http_tracker_with_log(
+        body: Vec<u8>,
+    ) -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let (log_tx, log_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request);
+                let _ = log_tx.send(request.lines().next().unwrap_or_default().to_string());
+                let header = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(header.as_bytes()).await.unwrap();
+                stream.write_all(&body).await.unwrap();
+            }
+        });
+        (url, log_rx)
+    }
+
+    fn compact_peers(peers: &[[u8; 6]]) -> Vec<u8> {
+        let mut body = format!("d8:intervali1800e5:peers{}:", peers.len() * 6).into_bytes();
+        for peer in peers {
+            body.extend_from_slice(peer);
+        }
+        body.extend_from_slice(b"e");
+        body
+    }
+
+    fn tracker_with_tiers(tiers: Vec<Vec<String>>) -> Tracker {
+        Tracker {
+            tiers: tiers
+                .into_iter()
+                .map(|tier| tier.into_iter().map(TrackerEndpoint::new).collect())
+                .collect(),
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+            port: 6881,
+            stats: Arc::new(TransferStats::new(100)),
+            key: 0,
+            started: false,
+            interval: DEFAULT_INTERVAL,
+            min_interval: None,
+            timeout: TRACKER_TIMEOUT,
+        }
+    }
+
+    // Accepts connections and never answers them
+    async fn hanging_tracker() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut open = Vec::new();
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                open.push(stream);
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_tier_failover_promotes_and_merges() {
+        // Nothing listens on port 1, so the first tracker fails immediately
+        let dead = "http://127.0.0.1:1/announce".to_string();
+        let first =
+            mock_http_tracker(compact_peers(&[[10, 0, 0, 1, 0, 80], [10, 0, 0, 2, 0, 80]])).await;
+        let second =
+            mock_http_tracker(compact_peers(&[[10, 0, 0, 2, 0, 80], [10, 0, 0, 3, 0, 80]])).await;
+
+        let mut tracker = tracker_with_tiers(vec![vec![dead.clone(), first.clone()], vec![second]]);
+        let peers = tracker.get_peers().await.unwrap();
+
+        let expected: Vec<SocketAddrV4> = vec![
+            "10.0.0.1:80".parse().unwrap(),
+            "10.0.0.2:80".parse().unwrap(),
+            "10.0.0.3:80".parse().unwrap(),
+        ];
+        assert_eq!(peers, expected);
+        assert_eq!(tracker.tiers()[0], vec![first, dead]);
+    }
+
+    #[tokio::test]
+    async fn test_tiers_announced_concurrently_with_timeout() {
+        let hanging = hanging_tracker().await;
+        let first = mock_http_tracker(compact_peers(&[[10, 0, 0, 1, 0, 80]])).await;
+        let second = mock_http_tracker(compact_peers(&[[10, 0, 0, 2, 0, 80]])).await;
+
+        // Each tier waits out one hanging tracker; one after the other
+        // would take two timeouts
+        let tiers = vec![
+            vec![hanging.clone(), first.clone()],
+            vec![hanging.clone(), second],
+        ];
+        let mut tracker = tracker_with_tiers(tiers).with_timeout(Duration::from_secs(1));
+
+        let started = Instant::now();
+        let peers = tracker.get_peers().await.unwrap();
+        assert!(started.elapsed() < Duration::from_millis(1900));
+        assert_eq!(peers.len(), 2);
+        assert_eq!(tracker.tiers()[0], vec![first, hanging]);
+    }
+
+    #[tokio::test]
+    async fn test_reannounces_with_events() {
+        let body = b"d8:intervali1e5:peers6:\x0a\x00\x00\x01\x00\x50e".to_vec();
+        let (url, mut requests) = mock_http_tracker_with_log(body).await;
+        let tracker = tracker_with_tiers(vec![vec![url]]);
+        let stats = Arc::clone(&tracker.stats);
+
+        let (peer_tx, mut peer_rx) = mpsc::channel(4);
+        let handle = tracker.spawn(peer_tx);
+
+        assert!(requests.recv().await.unwrap().contains("&event=started"));
+        let peers = peer_rx.recv().await.unwrap();
+        assert_eq!(peers, vec!["10.0.0.1:80".parse::<SocketAddrV4>().unwrap()]);
+
+        // The next regular announce carries the updated counters and no event
+        stats.add_downloaded(40);
+        let request = requests.recv().await.unwrap();
+        assert!(request.contains("&downloaded=40&left=60"));
+        assert!(!request.contains("&event="));
+
+        handle.completed();
+        while !requests.recv().await.unwrap().contains("&event=completed") {}
+
+        handle.stop().await;
+        while !requests.recv().await.unwrap().contains("&event=stopped") {}
+    }
+
+    #[tokio::test]
+    async fn test_failure_reason_and_warning() {
+        let failing = mock_http_tracker(b"d14:failure reason11:not allowede".to_vec()).await;
+        let mut tracker = tracker_with_tiers(vec![vec![failing]]);
+        match tracker.announce(AnnounceEvent::Started).await {
+            Err(BitTorrentError::TrackerFailure(reason)) => assert_eq!(reason, "not allowed"),
+            other => panic!("expected tracker failure, got {:?}", other),
+        }
+
+        let body = b"d8:intervali1800e5:peers0:15:warning message10:slow down!e".to_vec();
+        let warning = mock_http_tracker(body).await;
+        let mut tracker = tracker_with_tiers(vec![vec![warning]]);
+        let response = tracker.announce(AnnounceEvent::Started).await.unwrap();
+        assert_eq!(response.warning.as_deref(), Some("slow down!"));
+    }
+}
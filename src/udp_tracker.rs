@@ -0,0 +1,484 @@
+use crate::error::{BitTorrentError, Result};
+use bytes::{Buf, BufMut};
+use rand::random;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::time::{timeout, Duration, Instant};
+use url::Url;
+
+// Magic constant identifying the connect request (BEP 15)
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+// A connection id may be reused for one minute after it was received
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+
+// The spec waits 15 * 2^n seconds for up to n = 8, which is hours for a
+// dead tracker; we stop after n = 2 and bound each request as a whole
+const DEFAULT_BASE_TIMEOUT: Duration = Duration::from_secs(15);
+const DEFAULT_MAX_RETRANSMITS: u32 = 2;
+const DEFAULT_DEADLINE: Duration = Duration::from_secs(60);
+
+const MAX_PACKET_SIZE: usize = 65_507;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpAnnounce {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub downloaded: u64,
+    pub left: u64,
+    pub uploaded: u64,
+    // 0 = none, 1 = completed, 2 = started, 3 = stopped
+    pub event: u32,
+    pub key: u32,
+    pub num_want: i32,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpAnnounceResponse {
+    pub interval: u32,
+    pub leechers: u32,
+    pub seeders: u32,
+    pub peers: Vec<SocketAddrV4>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeStats {
+    pub seeders: u32,
+    pub completed: u32,
+    pub leechers: u32,
+}
+
+/// Client for a single `udp://` tracker.
+#[derive(Debug)]
+pub struct UdpTracker {
+    socket: UdpSocket,
+    connection: Option<(u64, Instant)>,
+    base_timeout: Duration,
+    max_retransmits: u32,
+    // Longest a request may take, connecting included
+    deadline: Duration,
+}
+
+impl UdpTracker {
+    pub async fn new(announce_url: &str) -> Result<Self> {
+        Self::with_timeouts(announce_url, DEFAULT_BASE_TIMEOUT, DEFAULT_MAX_RETRANSMITS).await
+    }
+
+    /// Like `new`, but with the retransmission schedule overridden. Request
+    /// `n` (counting from 0) waits `base_timeout * 2^n` for a response, up
+    /// to the deadline set by `with_deadline`.
+    pub async fn with_timeouts(
+        announce_url: &str,
+        base_timeout: Duration,
+        max_retransmits: u32,
+    ) -> Result<Self> {
+        let url = Url::parse(announce_url).map_err(|e| BitTorrentError::Tracker(e.to_string()))?;
+        if url.scheme() != "udp" {
+            return Err(BitTorrentError::Tracker(format!(
+                "Not a UDP tracker: {}",
+                announce_url
+            )));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| BitTorrentError::Tracker("UDP tracker URL has no host".into()))?;
+        let port = url
+            .port()
+            .ok_or_else(|| BitTorrentError::Tracker("UDP tracker URL has no port".into()))?;
+
+        let addr = lookup_host((host, port))
+            .await?
+            .find(SocketAddr::is_ipv4)
+            .ok_or_else(|| BitTorrentError::Tracker(format!("Could not resolve {}", host)))?;
+
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.connect(addr).await?;
+
+        Ok(Self {
+            socket,
+            connection: None,
+            base_timeout,
+            max_retransmits,
+            deadline: DEFAULT_DEADLINE,
+        })
+    }
+
+    /// Gives up on any announce or scrape that takes longer than `deadline`
+    /// in total, however many retransmits are left.
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    pub async fn announce(&mut self, request: &UdpAnnounce) -> Result<UdpAnnounceResponse> {
+        let mut body = Vec::with_capacity(82);
+        body.extend_from_slice(&request.info_hash);
+        body.extend_from_slice(&request.peer_id);
+        body.put_u64(request.downloaded);
+        body.put_u64(request.left);
+        body.put_u64(request.uploaded);
+        body.put_u32(request.event);
+        body.put_u32(0); // Let the tracker use the packet's source address
+        body.put_u32(request.key);
+        body.put_i32(request.num_want);
+        body.put_u16(request.port);
+
+        let response = self.request(ACTION_ANNOUNCE, &body).await?;
+        if response.len() < 12 {
+            return Err(BitTorrentError::Tracker(
+                "Truncated announce response".into(),
+            ));
+        }
+
+        let mut buf = &response[..12];
+        let interval = buf.get_u32();
+        let leechers = buf.get_u32();
+        let seeders = buf.get_u32();
+        let peers = response[12..]
+            .chunks_exact(6)
+            .map(|chunk| {
+                let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
+                SocketAddrV4::new(ip, u16::from_be_bytes([chunk[4], chunk[5]]))
+            })
+            .collect();
+
+        Ok(UdpAnnounceResponse {
+            interval,
+            leechers,
+            seeders,
+            peers,
+        })
+    }
+
+    pub async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>> {
+        let body: Vec<u8> = info_hashes.iter().flatten().copied().collect();
+        let response = self.request(ACTION_SCRAPE, &body).await?;
+        if response.len() < info_hashes.len() * 12 {
+            return Err(BitTorrentError::Tracker("Truncated scrape response".into()));
+        }
+
+        Ok(response
+            .chunks_exact(12)
+            .take(info_hashes.len())
+            .map(|mut chunk| ScrapeStats {
+                seeders: chunk.get_u32(),
+                completed: chunk.get_u32(),
+                leechers: chunk.get_u32(),
+            })
+            .collect())
+    }
+
+    // Sends `action` with `body`, retransmitting on the spec's schedule
+    // until the deadline, and returns the response payload after the action
+    // and transaction id.
+    async fn request(&mut self, action: u32, body: &[u8]) -> Result<Vec<u8>> {
+        match timeout(self.deadline, self.retransmit(action, body)).await {
+            Ok(response) => response,
+            Err(_) => Err(BitTorrentError::Tracker("UDP tracker timed out".into())),
+        }
+    }
+
+    async fn retransmit(&mut self, action: u32, body: &[u8]) -> Result<Vec<u8>> {
+        for attempt in 0..=self.max_retransmits {
+            // The id can expire while we are retrying, so check every time
+            let connection_id = self.connection_id().await?;
+            let transaction_id: u32 = random();
+
+            let mut packet = Vec::with_capacity(16 + body.len());
+            packet.put_u64(connection_id);
+            packet.put_u32(action);
+            packet.put_u32(transaction_id);
+            packet.extend_from_slice(body);
+
+            if let Some(response) = self.send_and_wait(&packet, transaction_id, attempt).await? {
+                return Self::parse_response(action, response);
+            }
+        }
+
+        Err(BitTorrentError::Tracker("UDP tracker timed out".into()))
+    }
+
+    async fn connection_id(&mut self) -> Result<u64> {
+        if let Some((id, received)) = self.connection {
+            if received.elapsed() < CONNECTION_ID_TTL {
+                return Ok(id);
+            }
+        }
+
+        for attempt in 0..=self.max_retransmits {
+            let transaction_id: u32 = random();
+            let mut packet = Vec::with_capacity(16);
+            packet.put_u64(PROTOCOL_ID);
+            packet.put_u32(ACTION_CONNECT);
+            packet.put_u32(transaction_id);
+
+            if let Some(response) = self.send_and_wait(&packet, transaction_id, attempt).await? {
+                let response = Self::parse_response(ACTION_CONNECT, response)?;
+                if response.len() < 8 {
+                    return Err(BitTorrentError::Tracker(
+                        "Truncated connect response".into(),
+                    ));
+                }
+                let id = (&response[..8]).get_u64();
+                self.connection = Some((id, Instant::now()));
+                return Ok(id);
+            }
+        }
+
+        Err(BitTorrentError::Tracker("UDP tracker timed out".into()))
+    }
+
+    // Returns `None` if no matching response arrived within this attempt's
+    // timeout. Packets with a different transaction id are ignored.
+    async fn send_and_wait(
+        &self,
+        packet: &[u8],
+        transaction_id: u32,
+        attempt: u32,
+    ) -> Result<Option<Vec<u8>>> {
+        self.socket.send(packet).await?;
+        let wait = self.base_timeout * 2u32.pow(attempt);
+
+        let received = timeout(wait, async {
+            let mut buf = vec![0u8; MAX_PACKET_SIZE];
+            loop {
+                let len = self.socket.recv(&mut buf).await?;
+                if len >= 8 && (&buf[4..8]).get_u32() == transaction_id {
+                    buf.truncate(len);
+                    return Ok::<_, BitTorrentError>(buf);
+                }
+            }
+        })
+        .await;
+
+        match received {
+            Ok(response) => response.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn parse_response(expected_action: u32, response: Vec<u8>) -> Result<Vec<u8>> {
+        let action = (&response[..4]).get_u32();
+        if action == ACTION_ERROR {
+            let message = String::from_utf8_lossy(&response[8..]);
+            return Err(BitTorrentError::TrackerFailure(message.into_owned()));
+        }
+        if action != expected_action {
+            return Err(BitTorrentError::Tracker(format!(
+                "Unexpected UDP tracker action {} (expected {})",
+                action, expected_action
+            )));
+        }
+        Ok(response[8..].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Clone, Copy)]
+    enum Mode {
+        Normal,
+        Error,
+        Silent,
+    }
+
+    // Minimal BEP 15 tracker on localhost. Returns its URL and a counter of
+    // connect requests it has answered.
+    async fn mock_tracker(mode: Mode) -> (String, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        let connects = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&connects);
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                if let Mode::Silent = mode {
+                    continue;
+                }
+                let mut packet = &buf[..len];
+                let connection_id = packet.get_u64();
+                let action = packet.get_u32();
+                let transaction_id = packet.get_u32();
+
+                let mut reply = Vec::new();
+                match (mode, action) {
+                    (_, ACTION_CONNECT) => {
+                        assert_eq!(connection_id, PROTOCOL_ID);
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        reply.put_u32(ACTION_CONNECT);
+                        reply.put_u32(transaction_id);
+                        reply.put_u64(0xDEADBEEF);
+                    }
+                    (Mode::Error, _) => {
+                        reply.put_u32(ACTION_ERROR);
+                        reply.put_u32(transaction_id);
+                        reply.extend_from_slice(b"torrent not registered");
+                    }
+                    (_, ACTION_ANNOUNCE) => {
+                        assert_eq!(connection_id, 0xDEADBEEF);
+                        assert_eq!(&packet[..20], &[7u8; 20]);
+                        reply.put_u32(ACTION_ANNOUNCE);
+                        reply.put_u32(transaction_id);
+                        reply.put_u32(1800);
+                        reply.put_u32(3);
+                        reply.put_u32(5);
+                        reply.extend_from_slice(&[10, 0, 0, 1, 0x1A, 0xE1]);
+                        reply.extend_from_slice(&[10, 0, 0, 2, 0x1A, 0xE2]);
+                    }
+                    (_, ACTION_SCRAPE) => {
+                        reply.put_u32(ACTION_SCRAPE);
+                        reply.put_u32(transaction_id);
+                        for _ in 0..packet.len() / 20 {
+                            reply.put_u32(5);
+                            reply.put_u32(42);
+                            reply.put_u32(3);
+                        }
+                    }
+                    _ => continue,
+                }
+                socket.send_to(&reply, from).await.unwrap();
+            }
+        });
+
+        (url, connects)
+    }
+
+    fn announce_request() -> UdpAnnounce {
+        UdpAnnounce {
+            info_hash: [7; 20],
+            peer_id: [1; 20],
+            downloaded: 0,
+            left: 1000,
+            uploaded: 0,
+            event: 2,
+            key: 1,
+            num_want: -1,
+            port: 6881,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_announce_and_scrape() {
+        let (url, connects) = mock_tracker(Mode::Normal).await;
+        let mut tracker = UdpTracker::new(&url).await.unwrap();
+
+        let response = tracker.announce(&announce_request()).await.unwrap();
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.seeders, 5);
+        assert_eq!(response.leechers, 3);
+        assert_eq!(
+            response.peers,
+            vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:6882".parse().unwrap()
+            ]
+        );
+
+        let stats = tracker.scrape(&[[7; 20]]).await.unwrap();
+        assert_eq!(
+            stats,
+            vec![ScrapeStats {
+                seeders: 5,
+                completed: 42,
+                leechers: 3
+            }]
+        );
+
+        // The connection id from the first exchange is reused
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_error_action() {
+        let (url, _) = mock_tracker(Mode::Error).await;
+        let mut tracker = UdpTracker::new(&url).await.unwrap();
+
+        match tracker.announce(&announce_request()).await {
+            Err(BitTorrentError::TrackerFailure(message)) => {
+                assert_eq!(message, "torrent not registered")
+            }
+            other => panic!("expected tracker error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timeout_after_retransmits() {
+        let (url, _) = mock_tracker(Mode::Silent).await;
+        let mut tracker = UdpTracker::with_timeouts(&url, Duration::from_millis(10), 2)
+            .await
+            .unwrap();
+
+        let started = Instant::now();
+        let result = tracker.announce(&announce_request()).await;
+        assert!(matches!(result, Err(BitTorrentError::Tracker(_))));
+        // 10 + 20 + 40 ms of waiting before giving up
+        assert!(started.elapsed() >= Duration::from_millis(70));
+    }
+
+    // Waits for a tracker on the default schedule to give up on a socket
+    // that never answers. Returns how long that took and the number of
+    // packets sent.
+    async fn silent_announce(deadline: Option<Duration>) -> (Duration, usize) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        let mut tracker = UdpTracker::new(&url).await.unwrap();
+        if let Some(deadline) = deadline {
+            tracker = tracker.with_deadline(deadline);
+        }
+
+        let started = Instant::now();
+        let result = tracker.announce(&announce_request()).await;
+        assert!(matches!(result, Err(BitTorrentError::Tracker(_))));
+        let elapsed = started.elapsed();
+
+        let mut buf = [0u8; 64];
+        let mut sent = 0;
+        while let Ok(received) = timeout(Duration::from_secs(1), socket.recv_from(&mut buf)).await {
+            received.unwrap();
+            sent += 1;
+        }
+        (elapsed, sent)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_silent_tracker_given_up_on_within_deadline() {
+        // Connects at 0, 15 and 45 s; the deadline ends the last wait early
+        let (elapsed, sent) = silent_announce(None).await;
+        assert_eq!(sent, DEFAULT_MAX_RETRANSMITS as usize + 1);
+        assert!(elapsed >= Duration::from_secs(45));
+        assert!(elapsed <= DEFAULT_DEADLINE + Duration::from_millis(10));
+
+        // Without the deadline, retransmits still stop at the cap
+        let (elapsed, sent) = silent_announce(Some(Duration::from_secs(3600))).await;
+        assert_eq!(sent, DEFAULT_MAX_RETRANSMITS as usize + 1);
+        assert!(elapsed >= Duration::from_secs(105));
+        assert!(elapsed <= Duration::from_secs(106));
+    }
+
+    #[tokio::test]
+    async fn test_deadline_cuts_retransmits_short() {
+        let (url, _) = mock_tracker(Mode::Silent).await;
+        // Would wait 10 * (2^9 - 1) ms, about five seconds, without a deadline
+        let mut tracker = UdpTracker::with_timeouts(&url, Duration::from_millis(10), 8)
+            .await
+            .unwrap()
+            .with_deadline(Duration::from_millis(100));
+
+        let started = Instant::now();
+        let result = tracker.announce(&announce_request()).await;
+        assert!(matches!(result, Err(BitTorrentError::Tracker(_))));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
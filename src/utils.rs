@@ -13,10 +13,7 @@ pub fn generate_peer_id() -> [u8; 20] {
 pub fn calculate_piece_hash(data: &[u8]) -> [u8; 20] {
     let mut hasher = Sha1::new();
     hasher.update(data);
-    hasher
-        .finalize()
-        .try_into()
-        .expect("SHA1 output is always 20 bytes")
+    hasher.finalize().into()
 }
 
 pub fn bit_set(bitfield: &[u8], index: usize) -> bool {